@@ -1,5 +1,27 @@
+use std::{env, fs, path::Path};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/store.proto")?;
+    tonic_build::configure()
+        // Upload headers are much larger than the chunks sharing their enum
+        .boxed(".storage.UploadFileRequest.data.header")
+        .compile(&["proto/store.proto"], &["proto"])?;
+
+    // The methods metrics are labelled with, as `<Service>/<Method>`
+    let proto = fs::read_to_string("proto/store.proto")?;
+    let mut service = "";
+    let mut methods = Vec::new();
+    for line in proto.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("service ") {
+            service = rest.trim_end_matches('{').trim();
+        } else if let Some(rest) = line.strip_prefix("rpc ") {
+            let method = rest.split('(').next().unwrap_or_default().trim();
+            methods.push(format!("{:?}", format!("{}/{}", service, method)));
+        }
+    }
+    fs::write(
+        Path::new(&env::var("OUT_DIR")?).join("methods.rs"),
+        format!("const METHODS: &[&str] = &[{}];\n", methods.join(", ")),
+    )?;
 
     Ok(())
 }
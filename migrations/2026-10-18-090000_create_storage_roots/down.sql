@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE store DROP COLUMN root_id;
+
+DROP TABLE storage_roots;
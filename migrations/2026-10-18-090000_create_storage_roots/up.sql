@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE storage_roots (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL UNIQUE,
+    path VARCHAR NOT NULL
+);
+
+ALTER TABLE store ADD COLUMN root_id INTEGER REFERENCES storage_roots(id);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE storage_roots
+    DROP COLUMN weight,
+    DROP COLUMN min_free_bytes,
+    DROP COLUMN state;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE storage_roots
+    ADD COLUMN weight INTEGER NOT NULL DEFAULT 1,
+    ADD COLUMN min_free_bytes BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN state VARCHAR NOT NULL DEFAULT 'online';
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE store
+    DROP COLUMN file_size,
+    DROP COLUMN content_type,
+    DROP COLUMN metadata;
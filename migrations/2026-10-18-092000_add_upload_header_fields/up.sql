@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE store
+    ADD COLUMN file_size BIGINT,
+    ADD COLUMN content_type VARCHAR,
+    ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE multipart_parts;
+
+DROP TABLE multipart_uploads;
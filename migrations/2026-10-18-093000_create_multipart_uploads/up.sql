@@ -0,0 +1,22 @@
+-- Your SQL goes here
+CREATE TABLE multipart_uploads (
+    id SERIAL PRIMARY KEY,
+    upload_id VARCHAR NOT NULL UNIQUE,
+    file_name VARCHAR NOT NULL,
+    file_size BIGINT,
+    content_type VARCHAR,
+    metadata JSONB NOT NULL DEFAULT '{}',
+    expected_hash VARCHAR,
+    root_id INTEGER NOT NULL REFERENCES storage_roots(id),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE multipart_parts (
+    id SERIAL PRIMARY KEY,
+    multipart_id INTEGER NOT NULL REFERENCES multipart_uploads(id) ON DELETE CASCADE,
+    part_number INTEGER NOT NULL,
+    part_hash VARCHAR NOT NULL,
+    part_size BIGINT NOT NULL,
+    file_path VARCHAR NOT NULL,
+    UNIQUE (multipart_id, part_number)
+);
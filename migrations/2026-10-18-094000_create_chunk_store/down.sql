@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE store DROP COLUMN chunked;
+
+DROP TABLE file_chunks;
+DROP TABLE chunks;
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+CREATE TABLE chunks (
+    id SERIAL PRIMARY KEY,
+    chunk_hash VARCHAR NOT NULL UNIQUE,
+    chunk_size BIGINT NOT NULL,
+    root_id INTEGER NOT NULL REFERENCES storage_roots(id),
+    file_path VARCHAR NOT NULL,
+    last_used_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE file_chunks (
+    store_id INTEGER NOT NULL REFERENCES store(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    chunk_id INTEGER NOT NULL REFERENCES chunks(id),
+    PRIMARY KEY (store_id, position)
+);
+
+CREATE INDEX file_chunks_chunk_id_idx ON file_chunks (chunk_id);
+
+ALTER TABLE store ADD COLUMN chunked BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE store DROP COLUMN outboard_path;
+ALTER TABLE store DROP COLUMN blake3_hash;
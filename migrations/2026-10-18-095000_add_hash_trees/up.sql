@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE store ADD COLUMN blake3_hash VARCHAR;
+ALTER TABLE store ADD COLUMN outboard_path VARCHAR;
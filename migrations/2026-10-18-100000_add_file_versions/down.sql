@@ -0,0 +1,11 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE multipart_uploads DROP COLUMN keep_versions;
+ALTER TABLE multipart_uploads DROP COLUMN file_key;
+ALTER TABLE multipart_uploads DROP COLUMN namespace;
+
+DROP INDEX store_key_version;
+ALTER TABLE store DROP COLUMN created_at;
+ALTER TABLE store DROP COLUMN version;
+ALTER TABLE store DROP COLUMN key_id;
+
+DROP TABLE file_keys;
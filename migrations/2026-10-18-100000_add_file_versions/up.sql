@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE file_keys (
+    id SERIAL PRIMARY KEY,
+    namespace VARCHAR NOT NULL,
+    file_key VARCHAR NOT NULL,
+    latest_version INTEGER NOT NULL DEFAULT 0,
+    keep_versions INTEGER,
+    UNIQUE (namespace, file_key)
+);
+
+ALTER TABLE store ADD COLUMN key_id INTEGER REFERENCES file_keys(id);
+ALTER TABLE store ADD COLUMN version INTEGER;
+ALTER TABLE store ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT NOW();
+CREATE UNIQUE INDEX store_key_version ON store (key_id, version);
+
+ALTER TABLE multipart_uploads ADD COLUMN namespace VARCHAR;
+ALTER TABLE multipart_uploads ADD COLUMN file_key VARCHAR;
+ALTER TABLE multipart_uploads ADD COLUMN keep_versions INTEGER;
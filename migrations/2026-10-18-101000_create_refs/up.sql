@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE refs (
+    name VARCHAR PRIMARY KEY,
+    file_hash VARCHAR NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX refs_file_hash ON refs (file_hash);
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX store_tags;
+DROP INDEX store_metadata;
+
+ALTER TABLE multipart_uploads DROP COLUMN tags;
+ALTER TABLE store DROP COLUMN generation;
+ALTER TABLE store DROP COLUMN tags;
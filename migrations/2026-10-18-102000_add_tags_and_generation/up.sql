@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE store ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE store ADD COLUMN generation BIGINT NOT NULL DEFAULT 1;
+ALTER TABLE multipart_uploads ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+-- jsonb_ops (not jsonb_path_ops) to serve key lookups as well as containment
+CREATE INDEX store_metadata ON store USING GIN (metadata);
+CREATE INDEX store_tags ON store USING GIN (tags);
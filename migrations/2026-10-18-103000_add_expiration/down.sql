@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX store_expires_at;
+
+ALTER TABLE multipart_uploads DROP COLUMN expires_at;
+ALTER TABLE store DROP COLUMN expires_at;
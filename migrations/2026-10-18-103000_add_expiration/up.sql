@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE store ADD COLUMN expires_at TIMESTAMP;
+ALTER TABLE multipart_uploads ADD COLUMN expires_at TIMESTAMP;
+
+CREATE INDEX store_expires_at ON store (expires_at) WHERE expires_at IS NOT NULL;
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE governance_audit;
+DROP TABLE namespace_retention;
+
+ALTER TABLE multipart_uploads DROP COLUMN retain_until;
+ALTER TABLE store DROP COLUMN legal_hold;
+ALTER TABLE store DROP COLUMN retain_until;
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+ALTER TABLE store ADD COLUMN retain_until TIMESTAMP;
+ALTER TABLE store ADD COLUMN legal_hold BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE multipart_uploads ADD COLUMN retain_until TIMESTAMP;
+
+CREATE TABLE namespace_retention (
+    namespace VARCHAR PRIMARY KEY,
+    retention_secs BIGINT NOT NULL CHECK (retention_secs > 0),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE governance_audit (
+    id SERIAL PRIMARY KEY,
+    action VARCHAR NOT NULL,
+    target VARCHAR NOT NULL,
+    reason VARCHAR NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
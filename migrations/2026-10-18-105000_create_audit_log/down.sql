@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE audit_log;
+DROP FUNCTION audit_log_append_only;
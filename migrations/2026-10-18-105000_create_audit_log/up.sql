@@ -0,0 +1,28 @@
+-- Your SQL goes here
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    operation VARCHAR NOT NULL,
+    caller VARCHAR NOT NULL,
+    peer VARCHAR NOT NULL,
+    file_hash VARCHAR,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    outcome VARCHAR NOT NULL,
+    message VARCHAR NOT NULL DEFAULT ''
+);
+
+CREATE INDEX audit_log_created_at ON audit_log (created_at);
+CREATE INDEX audit_log_caller ON audit_log (caller, id);
+CREATE INDEX audit_log_file_hash ON audit_log (file_hash, id) WHERE file_hash IS NOT NULL;
+
+-- Records can be added, never changed or removed
+CREATE FUNCTION audit_log_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
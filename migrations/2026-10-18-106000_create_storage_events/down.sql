@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE storage_events;
+DROP FUNCTION storage_events_notify;
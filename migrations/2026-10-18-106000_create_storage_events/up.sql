@@ -0,0 +1,25 @@
+-- Your SQL goes here
+CREATE TABLE storage_events (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    kind VARCHAR NOT NULL,
+    file_hash VARCHAR NOT NULL,
+    file_name VARCHAR NOT NULL,
+    file_size BIGINT NOT NULL DEFAULT 0,
+    namespace VARCHAR,
+    file_key VARCHAR,
+    version INT
+);
+
+CREATE INDEX storage_events_created_at ON storage_events (created_at);
+
+-- Wakes up the watchers of every server once the event is committed
+CREATE FUNCTION storage_events_notify() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('storage_events', NEW.id::TEXT);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER storage_events_notify AFTER INSERT ON storage_events
+    FOR EACH ROW EXECUTE FUNCTION storage_events_notify();
@@ -0,0 +1,7 @@
+-- Transaction that added the event. Events are passed on in the order of
+-- their transactions, and only once every transaction that could still add
+-- an earlier one has ended, so an event committed late isn't skipped
+ALTER TABLE storage_events
+    ADD COLUMN txid BIGINT NOT NULL DEFAULT pg_current_xact_id()::TEXT::BIGINT;
+
+CREATE INDEX storage_events_position ON storage_events (txid, id);
@@ -0,0 +1,286 @@
+use chrono::{SecondsFormat, Utc};
+use dotenvy::dotenv;
+use hyper::{Request, Response};
+use serde_json::json;
+use std::{
+    env,
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    task::{Context, Poll},
+    thread,
+    time::Instant,
+};
+use tonic::{body::BoxBody, codegen::Service, transport::server::TcpConnectInfo, Code};
+use tower_layer::Layer;
+use tracing::{error, info};
+
+use crate::{
+    audit::CLIENT_ID_HEADER,
+    call_body::{observe_call, take_ready, CallFuture, CallObserver},
+    telemetry::REQUEST_ID_HEADER,
+};
+
+/// Methods left out of the access log unless `ACCESS_LOG_EXCLUDE` says
+/// otherwise: health checks and status probes.
+const DEFAULT_EXCLUDE: &str =
+    "grpc.health.v1.Health/Check,grpc.health.v1.Health/Watch,storage.Admin/GetStatus";
+
+/// Where access log lines go and which calls get one.
+struct AccessLogConfig {
+    destination: String,
+    sample_rate: f64,
+    exclude: Vec<String>,
+}
+
+impl AccessLogConfig {
+    /// Reads `ACCESS_LOG` (`stdout`, `stderr` or a file to append to, unset
+    /// disables the log), `ACCESS_LOG_SAMPLE_RATE` and `ACCESS_LOG_EXCLUDE`.
+    fn from_env() -> Option<Self> {
+        dotenv().ok();
+
+        let destination = env::var("ACCESS_LOG")
+            .ok()
+            .filter(|destination| !destination.is_empty())?;
+        let sample_rate: f64 = env::var("ACCESS_LOG_SAMPLE_RATE")
+            .unwrap_or("1".to_owned())
+            .parse()
+            .ok()
+            .filter(|rate| (0.0..=1.0).contains(rate))
+            .unwrap_or_else(|| {
+                error!("'ACCESS_LOG_SAMPLE_RATE' - should be a number in range: [0;1]");
+                panic!()
+            });
+        let exclude = env::var("ACCESS_LOG_EXCLUDE")
+            .unwrap_or(DEFAULT_EXCLUDE.to_owned())
+            .split(',')
+            .map(|method| method.trim().trim_start_matches('/').to_owned())
+            .filter(|method| !method.is_empty())
+            .collect();
+
+        Some(Self {
+            destination,
+            sample_rate,
+            exclude,
+        })
+    }
+}
+
+/// The file a call was about, filled in by its handler.
+#[derive(Default)]
+struct FileFields {
+    file_hash: String,
+    file_name: String,
+}
+
+/// Lets a handler name the file of its call in the access log. Detached (and
+/// ignored) when the log is disabled.
+#[derive(Clone, Default)]
+pub struct AccessNote(Option<Arc<Mutex<FileFields>>>);
+
+impl AccessNote {
+    pub fn of<T>(request: &tonic::Request<T>) -> Self {
+        request
+            .extensions()
+            .get::<AccessNote>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Names the file of the call, the name may be unknown (empty).
+    pub fn file(&self, file_hash: &str, file_name: &str) {
+        if let Some(fields) = &self.0 {
+            let mut fields = fields.lock().unwrap();
+            fields.file_hash = file_hash.to_owned();
+            fields.file_name = file_name.to_owned();
+        }
+    }
+}
+
+/// Writes one JSON line per gRPC call, apart from the application log.
+struct AccessLog {
+    lines: mpsc::Sender<String>,
+    sample_rate: f64,
+    exclude: Vec<String>,
+    // Successful calls seen, to sample them evenly
+    calls: AtomicU64,
+}
+
+impl AccessLog {
+    fn start(config: AccessLogConfig) -> Self {
+        let mut out: Box<dyn Write + Send> = match config.destination.as_str() {
+            "stdout" => Box::new(io::stdout()),
+            "stderr" => Box::new(io::stderr()),
+            path => match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Box::new(BufWriter::new(file)),
+                Err(e) => {
+                    error!("Could not open access log \"{}\"! Error: {}", path, e);
+                    panic!()
+                }
+            },
+        };
+        info!("Writing access log to {}", config.destination);
+
+        let (lines, rx) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            while let Ok(line) = rx.recv() {
+                let mut written = writeln!(out, "{}", line);
+                // Flushed once the pending lines are written
+                for line in rx.try_iter() {
+                    written = written.and_then(|_| writeln!(out, "{}", line));
+                }
+                if let Err(e) = written.and_then(|_| out.flush()) {
+                    error!("Could not write the access log! Error: {}", e);
+                }
+            }
+        });
+
+        Self {
+            lines,
+            sample_rate: config.sample_rate,
+            exclude: config.exclude,
+            calls: AtomicU64::new(0),
+        }
+    }
+
+    /// Failed calls are always logged, successful ones as sampled.
+    fn sampled(&self, code: Code) -> bool {
+        if code != Code::Ok || self.sample_rate >= 1.0 {
+            return true;
+        }
+        let n = self.calls.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.sample_rate).floor() > (n * self.sample_rate).floor()
+    }
+}
+
+/// An access log line in the making, written once its call is finished.
+struct Entry {
+    log: Arc<AccessLog>,
+    method: String,
+    peer: String,
+    caller: String,
+    request_id: String,
+    file: Arc<Mutex<FileFields>>,
+    started: Instant,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl CallObserver for Entry {
+    fn received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Writes the line of the call.
+    fn finished(&self, code: Code) {
+        if !self.log.sampled(code) {
+            return;
+        }
+
+        let file = self.file.lock().unwrap();
+        let line = json!({
+            "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "method": self.method,
+            "peer": self.peer,
+            "caller": self.caller,
+            "requestId": self.request_id,
+            "fileHash": file.file_hash,
+            "fileName": file.file_name,
+            "bytesIn": self.received.load(Ordering::Relaxed),
+            "bytesOut": self.sent.load(Ordering::Relaxed),
+            "durationMs": self.started.elapsed().as_micros() as f64 / 1000.0,
+            "status": format!("{:?}", code),
+        });
+        // The writer is gone only if it panicked
+        let _ = self.log.lines.send(line.to_string());
+    }
+}
+
+/// Logs the calls of the wrapped gRPC services, if `ACCESS_LOG` is set.
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    log: Option<Arc<AccessLog>>,
+}
+
+impl AccessLogLayer {
+    pub fn from_env() -> Self {
+        Self {
+            log: AccessLogConfig::from_env().map(|config| Arc::new(AccessLog::start(config))),
+        }
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLogService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLogService {
+            inner,
+            log: self.log.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLogService<S> {
+    inner: S,
+    log: Option<Arc<AccessLog>>,
+}
+
+impl<S> Service<Request<BoxBody>> for AccessLogService<S>
+where
+    S: Service<Request<BoxBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = CallFuture<S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<BoxBody>) -> Self::Future {
+        let method = request.uri().path().trim_start_matches('/').to_owned();
+        let log = match &self.log {
+            Some(log) if !log.exclude.contains(&method) => log.clone(),
+            _ => return Box::pin(take_ready(&mut self.inner).call(request)),
+        };
+
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|val| val.to_str().ok())
+                .unwrap_or_default()
+                .to_owned()
+        };
+        let file = Arc::new(Mutex::new(FileFields::default()));
+        let entry = Entry {
+            log,
+            method,
+            peer: request
+                .extensions()
+                .get::<TcpConnectInfo>()
+                .and_then(|info| info.remote_addr())
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            caller: header(CLIENT_ID_HEADER),
+            request_id: header(REQUEST_ID_HEADER),
+            file: file.clone(),
+            started: Instant::now(),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+        };
+
+        request.extensions_mut().insert(AccessNote(Some(file)));
+        observe_call(&mut self.inner, request, Arc::new(entry))
+    }
+}
@@ -0,0 +1,291 @@
+// Admin calls fail with the `Status` sent back to the client
+#![allow(clippy::result_large_err)]
+
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{service::Interceptor, Request, Response, Status};
+
+use crate::{
+    audit::Caller,
+    grpc::FileStorage,
+    storage::{
+        admin_server::Admin, AuditExportChunk, DeleteWebhookRequest, DeleteWebhookResponse,
+        FileInfo, GetStatusRequest, ListDeliveriesRequest, ListDeliveriesResponse,
+        ListWebhooksRequest, ListWebhooksResponse, NamespaceRetention, OverrideRetentionRequest,
+        QueryAuditLogRequest, QueryAuditLogResponse, RegisterWebhookRequest, RetryDeliveryRequest,
+        ServerStatus, ServiceMode, SetLegalHoldRequest, SetModeRequest,
+        SetNamespaceRetentionRequest, SetVolumeStateRequest, VolumeState as ProtoVolumeState,
+        Webhook, WebhookDelivery,
+    },
+    volumes::VolumeState,
+};
+
+pub struct StorageAdmin {
+    storage: Arc<FileStorage>,
+}
+
+impl StorageAdmin {
+    pub fn new(storage: Arc<FileStorage>) -> Self {
+        Self { storage }
+    }
+}
+
+/// Marks admin calls that carried the configured token.
+#[derive(Clone, Copy)]
+struct Authenticated;
+
+/// Requires `authorization: Bearer <ADMIN_TOKEN>` on admin calls when a token
+/// is configured. Calls changing state are refused without one, and so are
+/// the audit log and the webhook deliveries (which carry event payloads), see
+/// `require_token`. Only the status and the registered webhooks, without their
+/// secrets, are served to anyone reaching the admin port.
+#[derive(Clone)]
+pub struct AdminAuth {
+    token: Option<String>,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token: token.filter(|t| !t.is_empty()),
+        }
+    }
+}
+
+impl Interceptor for AdminAuth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(token) = &self.token else {
+            return Ok(request);
+        };
+
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.strip_prefix("Bearer "));
+
+        match provided {
+            Some(val) if bool::from(val.as_bytes().ct_eq(token.as_bytes())) => {
+                request.extensions_mut().insert(Authenticated);
+                Ok(request)
+            }
+            _ => Err(Status::unauthenticated("Invalid admin token!")),
+        }
+    }
+}
+
+/// Refuses calls changing state or reading the audit log or the deliveries
+/// unless they were authenticated, which needs `ADMIN_TOKEN` to be set.
+fn require_token<T>(request: &Request<T>) -> Result<(), Status> {
+    match request.extensions().get::<Authenticated>() {
+        Some(_) => Ok(()),
+        None => Err(Status::permission_denied(
+            "This admin call requires ADMIN_TOKEN to be set!",
+        )),
+    }
+}
+
+pub fn volume_state_to_proto(state: VolumeState) -> ProtoVolumeState {
+    match state {
+        VolumeState::Online => ProtoVolumeState::Online,
+        VolumeState::ReadOnly => ProtoVolumeState::ReadOnly,
+        VolumeState::Offline => ProtoVolumeState::Offline,
+    }
+}
+
+pub fn volume_state_from_proto(state: ProtoVolumeState) -> VolumeState {
+    match state {
+        ProtoVolumeState::Online => VolumeState::Online,
+        ProtoVolumeState::ReadOnly => VolumeState::ReadOnly,
+        ProtoVolumeState::Offline => VolumeState::Offline,
+    }
+}
+
+/// The caller of an admin call, `admin` unless it names itself.
+fn admin_caller<T>(request: &Request<T>) -> Caller {
+    let mut caller = Caller::of(request);
+    if caller.identity.is_empty() {
+        caller.identity = "admin".to_owned();
+    }
+    caller
+}
+
+#[tonic::async_trait]
+impl Admin for StorageAdmin {
+    type ExportAuditLogStream = ReceiverStream<Result<AuditExportChunk, Status>>;
+
+    async fn get_status(
+        &self,
+        _request: Request<GetStatusRequest>,
+    ) -> Result<Response<ServerStatus>, Status> {
+        Ok(Response::new(self.storage.status()))
+    }
+
+    async fn set_mode(
+        &self,
+        request: Request<SetModeRequest>,
+    ) -> Result<Response<ServerStatus>, Status> {
+        require_token(&request)?;
+        let caller = admin_caller(&request);
+        let request = request.into_inner();
+        let mode = ServiceMode::try_from(request.mode)
+            .map_err(|_| Status::invalid_argument("Unknown service mode!"));
+        self.storage
+            .audit()
+            .record("SetMode", &caller, None, 0, &mode);
+
+        self.storage.set_mode(mode?, request.reason);
+        Ok(Response::new(self.storage.status()))
+    }
+
+    async fn set_volume_state(
+        &self,
+        request: Request<SetVolumeStateRequest>,
+    ) -> Result<Response<ServerStatus>, Status> {
+        require_token(&request)?;
+        let caller = admin_caller(&request);
+        let request = request.into_inner();
+
+        let result = ProtoVolumeState::try_from(request.state)
+            .map_err(|_| Status::invalid_argument("Unknown volume state!"))
+            .and_then(|state| {
+                self.storage
+                    .set_volume_state(&request.volume_name, volume_state_from_proto(state))
+            });
+        self.storage
+            .audit()
+            .record("SetVolumeState", &caller, None, 0, &result);
+
+        result?;
+        Ok(Response::new(self.storage.status()))
+    }
+
+    async fn set_legal_hold(
+        &self,
+        request: Request<SetLegalHoldRequest>,
+    ) -> Result<Response<FileInfo>, Status> {
+        require_token(&request)?;
+        let caller = admin_caller(&request);
+        let request = request.into_inner();
+        let file_hash = request.file_hash.clone();
+
+        let result = self.storage.set_legal_hold(request);
+        self.storage
+            .audit()
+            .record("SetLegalHold", &caller, Some(&file_hash), 0, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn override_retention(
+        &self,
+        request: Request<OverrideRetentionRequest>,
+    ) -> Result<Response<FileInfo>, Status> {
+        require_token(&request)?;
+        let caller = admin_caller(&request);
+        let request = request.into_inner();
+        let file_hash = request.file_hash.clone();
+
+        let result = self.storage.override_retention(request);
+        self.storage
+            .audit()
+            .record("OverrideRetention", &caller, Some(&file_hash), 0, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn set_namespace_retention(
+        &self,
+        request: Request<SetNamespaceRetentionRequest>,
+    ) -> Result<Response<NamespaceRetention>, Status> {
+        require_token(&request)?;
+        let caller = admin_caller(&request);
+
+        let result = self.storage.set_namespace_retention(request.into_inner());
+        self.storage
+            .audit()
+            .record("SetNamespaceRetention", &caller, None, 0, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn query_audit_log(
+        &self,
+        request: Request<QueryAuditLogRequest>,
+    ) -> Result<Response<QueryAuditLogResponse>, Status> {
+        require_token(&request)?;
+        Ok(Response::new(
+            self.storage.audit().query(request.into_inner())?,
+        ))
+    }
+
+    async fn export_audit_log(
+        &self,
+        request: Request<QueryAuditLogRequest>,
+    ) -> Result<Response<Self::ExportAuditLogStream>, Status> {
+        require_token(&request)?;
+        Ok(Response::new(
+            self.storage.audit().export(request.into_inner())?,
+        ))
+    }
+
+    async fn register_webhook(
+        &self,
+        request: Request<RegisterWebhookRequest>,
+    ) -> Result<Response<Webhook>, Status> {
+        require_token(&request)?;
+        let caller = admin_caller(&request);
+
+        let result = self.storage.webhooks().register(request.into_inner());
+        self.storage
+            .audit()
+            .record("RegisterWebhook", &caller, None, 0, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn list_webhooks(
+        &self,
+        _request: Request<ListWebhooksRequest>,
+    ) -> Result<Response<ListWebhooksResponse>, Status> {
+        Ok(Response::new(self.storage.webhooks().list()?))
+    }
+
+    async fn delete_webhook(
+        &self,
+        request: Request<DeleteWebhookRequest>,
+    ) -> Result<Response<DeleteWebhookResponse>, Status> {
+        require_token(&request)?;
+        let caller = admin_caller(&request);
+
+        let result = self.storage.webhooks().delete(request.into_inner().id);
+        self.storage
+            .audit()
+            .record("DeleteWebhook", &caller, None, 0, &result);
+        result?;
+        Ok(Response::new(DeleteWebhookResponse {}))
+    }
+
+    async fn list_deliveries(
+        &self,
+        request: Request<ListDeliveriesRequest>,
+    ) -> Result<Response<ListDeliveriesResponse>, Status> {
+        require_token(&request)?;
+        Ok(Response::new(
+            self.storage
+                .webhooks()
+                .list_deliveries(request.into_inner())?,
+        ))
+    }
+
+    async fn retry_delivery(
+        &self,
+        request: Request<RetryDeliveryRequest>,
+    ) -> Result<Response<WebhookDelivery>, Status> {
+        require_token(&request)?;
+        let caller = admin_caller(&request);
+
+        let result = self.storage.webhooks().retry(request.into_inner().id);
+        self.storage
+            .audit()
+            .record("RetryDelivery", &caller, None, 0, &result);
+        Ok(Response::new(result?))
+    }
+}
@@ -0,0 +1,264 @@
+// Audit queries fail with the `Status` sent back to the client
+#![allow(clippy::result_large_err)]
+
+use chrono::{DateTime, SecondsFormat};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Status};
+use tracing::{error, Instrument};
+
+use crate::{
+    db::{AuditFilter, DbState},
+    models::{AuditRecord, NewAuditRecord},
+    storage::{
+        AuditExportChunk, AuditRecord as ProtoAuditRecord, QueryAuditLogRequest,
+        QueryAuditLogResponse,
+    },
+};
+
+/// Metadata key clients name themselves with in the audit log.
+pub const CLIENT_ID_HEADER: &str = "x-client-id";
+
+/// Records returned by one `QueryAuditLog` call, or loaded at once by an
+/// export, at most.
+const MAX_AUDIT_RECORDS: u32 = 1000;
+
+/// Size of the chunks of an audit export, in bytes.
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Who made a call.
+#[derive(Clone, Debug, Default)]
+pub struct Caller {
+    pub identity: String,
+    pub peer: String,
+}
+
+impl Caller {
+    /// Takes the identity from the `x-client-id` metadata of the request,
+    /// which clients set themselves.
+    pub fn of<T>(request: &Request<T>) -> Self {
+        Self {
+            identity: request
+                .metadata()
+                .get(CLIENT_ID_HEADER)
+                .and_then(|val| val.to_str().ok())
+                .unwrap_or_default()
+                .to_owned(),
+            peer: request
+                .remote_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The service itself, e.g. deleting expired files.
+    pub fn system() -> Self {
+        Self {
+            identity: "system".to_owned(),
+            peer: String::new(),
+        }
+    }
+}
+
+/// Append-only log of the storage operations and admin actions.
+#[derive(Clone)]
+pub struct AuditLog {
+    db: DbState,
+}
+
+impl AuditLog {
+    pub fn new(db: DbState) -> Self {
+        Self { db }
+    }
+
+    /// Adds a record of an operation with its outcome. A record that can't be
+    /// written is logged, the operation doesn't fail because of it.
+    pub fn record<T>(
+        &self,
+        operation: &str,
+        caller: &Caller,
+        file_hash: Option<&str>,
+        bytes: u64,
+        outcome: &Result<T, Status>,
+    ) {
+        let (outcome, message) = match outcome {
+            Ok(_) => (tonic::Code::Ok, String::new()),
+            Err(status) => (status.code(), status.message().to_owned()),
+        };
+
+        let record = NewAuditRecord {
+            operation: operation.to_owned(),
+            caller: caller.identity.clone(),
+            peer: caller.peer.clone(),
+            // Hashes are kept lowercase, keys and refs named instead as they are
+            file_hash: file_hash.filter(|hash| !hash.is_empty()).map(|hash| {
+                if hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    hash.to_ascii_lowercase()
+                } else {
+                    hash.to_owned()
+                }
+            }),
+            bytes: bytes as i64,
+            outcome: format!("{:?}", outcome),
+            message,
+        };
+
+        if let Err(e) = self.db.add_audit_record(&record) {
+            error!(
+                "Could not add {} of \"{}\" to the audit log! Error: {}",
+                record.operation, record.caller, e
+            );
+        }
+    }
+
+    pub fn query(
+        &self,
+        mut request: QueryAuditLogRequest,
+    ) -> Result<QueryAuditLogResponse, Status> {
+        request.file_hash.make_ascii_lowercase();
+        let limit = match request.limit {
+            0 => MAX_AUDIT_RECORDS,
+            limit => limit.min(MAX_AUDIT_RECORDS),
+        };
+        let after_id = match request.page_token.as_str() {
+            "" => 0,
+            token => token
+                .parse()
+                .map_err(|_| Status::invalid_argument("Invalid page token!"))?,
+        };
+
+        let records = self
+            .db
+            .query_audit_log(&audit_filter(&request)?, after_id, limit as i64)
+            .map_err(|e| {
+                error!("Error during loading the audit log from DB! Error: {}", &e);
+                Status::internal("Internal service error!")
+            })?;
+
+        let next_page_token = match records.last() {
+            Some(last) if records.len() == limit as usize => last.id.to_string(),
+            _ => String::new(),
+        };
+
+        Ok(QueryAuditLogResponse {
+            records: records.into_iter().map(record_message).collect(),
+            next_page_token,
+        })
+    }
+
+    /// Streams every matching record as a JSON line, loading them a page at
+    /// a time.
+    pub fn export(
+        &self,
+        mut request: QueryAuditLogRequest,
+    ) -> Result<ReceiverStream<Result<AuditExportChunk, Status>>, Status> {
+        request.file_hash.make_ascii_lowercase();
+        audit_filter(&request)?;
+
+        let db = self.db.clone();
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(
+            async move {
+                // Checked above already
+                let Ok(filter) = audit_filter(&request) else {
+                    return;
+                };
+                let mut after_id = 0;
+                let mut data = Vec::with_capacity(EXPORT_CHUNK_SIZE);
+
+                loop {
+                    let records =
+                        match db.query_audit_log(&filter, after_id, MAX_AUDIT_RECORDS as i64) {
+                            Ok(records) => records,
+                            Err(e) => {
+                                error!("Error during exporting the audit log! Error: {}", &e);
+                                let _ = tx
+                                    .send(Err(Status::internal("Internal service error!")))
+                                    .await;
+                                return;
+                            }
+                        };
+                    let Some(last) = records.last() else {
+                        break;
+                    };
+                    after_id = last.id;
+                    let done = records.len() < MAX_AUDIT_RECORDS as usize;
+
+                    for record in records {
+                        data.extend_from_slice(json_line(&record).as_bytes());
+                        if data.len() >= EXPORT_CHUNK_SIZE {
+                            let chunk = AuditExportChunk {
+                                data: std::mem::take(&mut data),
+                            };
+                            if tx.send(Ok(chunk)).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+
+                    if done {
+                        break;
+                    }
+                }
+
+                if !data.is_empty() {
+                    let _ = tx.send(Ok(AuditExportChunk { data })).await;
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(ReceiverStream::new(rx))
+    }
+}
+
+fn audit_filter(request: &QueryAuditLogRequest) -> Result<AuditFilter<'_>, Status> {
+    let time = |secs: i64| match secs {
+        0 => Ok(None),
+        secs => DateTime::from_timestamp(secs, 0)
+            .map(|at| Some(at.naive_utc()))
+            .ok_or_else(|| Status::invalid_argument("Invalid time range!")),
+    };
+
+    Ok(AuditFilter {
+        from: time(request.from)?,
+        to: time(request.to)?,
+        caller: Some(request.caller.as_str()).filter(|caller| !caller.is_empty()),
+        file_hash: Some(request.file_hash.as_str()).filter(|hash| !hash.is_empty()),
+        operation: Some(request.operation.as_str()).filter(|op| !op.is_empty()),
+    })
+}
+
+fn record_message(record: AuditRecord) -> ProtoAuditRecord {
+    ProtoAuditRecord {
+        id: record.id as u64,
+        created_at: record.created_at.and_utc().timestamp(),
+        operation: record.operation,
+        caller: record.caller,
+        peer: record.peer,
+        file_hash: record.file_hash.unwrap_or_default(),
+        bytes: record.bytes as u64,
+        outcome: record.outcome,
+        message: record.message,
+    }
+}
+
+fn json_line(record: &AuditRecord) -> String {
+    let line = serde_json::json!({
+        "id": record.id,
+        "time": record
+            .created_at
+            .and_utc()
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        "operation": record.operation,
+        "caller": record.caller,
+        "peer": record.peer,
+        "fileHash": record.file_hash,
+        "bytes": record.bytes,
+        "outcome": record.outcome,
+        "message": record.message,
+    });
+
+    format!("{}\n", line)
+}
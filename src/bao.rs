@@ -0,0 +1,338 @@
+//! BLAKE3 hash trees in the style of Bao.
+//!
+//! The tree of a blob is the regular BLAKE3 tree, cut off at groups of
+//! [`GROUP_SIZE`] bytes. Its parent nodes are kept in an "outboard" file next
+//! to the blob: 64 bytes per node (left and right child hash) in post-order, so
+//! it can be written while the data streams in. Any aligned slice of a
+//! power-of-two number of groups is a subtree, which a reader verifies against
+//! the root hash with the sibling hashes on its path to the root.
+
+use blake3::{
+    hazmat::{
+        left_subtree_len, merge_subtrees_non_root, merge_subtrees_root, ChainingValue, HasherExt,
+        Mode,
+    },
+    Hash, Hasher,
+};
+
+/// Bytes covered by a leaf of the stored tree.
+pub const GROUP_SIZE: u64 = 16 * 1024;
+
+/// Size of a parent node in the outboard.
+pub const NODE_SIZE: u64 = 64;
+
+/// Builds the hash tree of a byte stream.
+pub struct TreeBuilder {
+    group: Hasher,
+    group_len: u64,
+    written: u64,
+    stack: Vec<(ChainingValue, u64)>,
+}
+
+impl Default for TreeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TreeBuilder {
+    pub fn new() -> Self {
+        Self {
+            group: Hasher::new(),
+            group_len: 0,
+            written: 0,
+            stack: Vec::new(),
+        }
+    }
+
+    /// Hashes `data`, appending the parent nodes it completes to `outboard`.
+    pub fn update(&mut self, mut data: &[u8], outboard: &mut Vec<u8>) {
+        while !data.is_empty() {
+            // A full group is only closed once more data arrives, the last
+            // group is hashed differently when it's the only one
+            if self.group_len == GROUP_SIZE {
+                let cv = self.group.finalize_non_root();
+                self.push(cv, outboard);
+
+                self.group = Hasher::new();
+                self.group.set_input_offset(self.written);
+                self.group_len = 0;
+            }
+
+            let take = data.len().min((GROUP_SIZE - self.group_len) as usize);
+            self.group.update(&data[..take]);
+            self.group_len += take as u64;
+            self.written += take as u64;
+            data = &data[take..];
+        }
+    }
+
+    /// Appends the remaining parent nodes and returns the root hash, which is
+    /// the BLAKE3 hash of the whole stream.
+    pub fn finalize(mut self, outboard: &mut Vec<u8>) -> Hash {
+        if self.stack.is_empty() {
+            return self.group.finalize();
+        }
+
+        let mut right = self.group.finalize_non_root();
+        loop {
+            let (left, _) = self.stack.pop().unwrap();
+            outboard.extend_from_slice(&left);
+            outboard.extend_from_slice(&right);
+
+            if self.stack.is_empty() {
+                return merge_subtrees_root(&left, &right, Mode::Hash);
+            }
+            right = merge_subtrees_non_root(&left, &right, Mode::Hash);
+        }
+    }
+
+    fn push(&mut self, cv: ChainingValue, outboard: &mut Vec<u8>) {
+        let mut node = (cv, GROUP_SIZE);
+        while let Some(&(left, len)) = self.stack.last() {
+            if len != node.1 {
+                break;
+            }
+            self.stack.pop();
+            outboard.extend_from_slice(&left);
+            outboard.extend_from_slice(&node.0);
+            node = (merge_subtrees_non_root(&left, &node.0, Mode::Hash), len * 2);
+        }
+        self.stack.push(node);
+    }
+}
+
+/// A sibling hash on the path from a slice to the root.
+#[derive(Clone, Debug)]
+pub struct ProofStep {
+    /// Index of the parent node in the outboard.
+    pub node: u64,
+    /// Whether the sibling is the left child of that node.
+    pub sibling_is_left: bool,
+}
+
+/// Size of the slices a blob is verified in: the largest power-of-two number
+/// of groups not above `max_len`.
+pub fn slice_len(max_len: u64) -> u64 {
+    let groups = (max_len / GROUP_SIZE).max(1);
+    GROUP_SIZE << groups.ilog2()
+}
+
+/// Lists the parent nodes holding the proof of the slice at `offset`, from the
+/// slice up to the root. `offset` has to be a multiple of `slice_len`.
+pub fn proof_steps(size: u64, offset: u64, slice_len: u64) -> Vec<ProofStep> {
+    let target_len = slice_len.min(size - offset);
+
+    let mut steps = Vec::new();
+    let (mut start, mut len, mut base) = (0, size, 0);
+    while start != offset || len != target_len {
+        let node = base + groups(len) - 2;
+        let left = left_subtree_len(len);
+
+        if offset < start + left {
+            steps.push(ProofStep {
+                node,
+                sibling_is_left: false,
+            });
+            len = left;
+        } else {
+            steps.push(ProofStep {
+                node,
+                sibling_is_left: true,
+            });
+            base += groups(left) - 1;
+            start += left;
+            len -= left;
+        }
+    }
+
+    steps.reverse();
+    steps
+}
+
+/// Computes the root hash implied by a slice and its proof, given bottom-up
+/// as sibling hashes with a flag telling whether each one is the left child.
+/// The slice is intact if the result equals the root hash of the blob.
+pub fn slice_root(size: u64, offset: u64, data: &[u8], proof: &[(ChainingValue, bool)]) -> Hash {
+    if offset == 0 && data.len() as u64 == size {
+        return blake3::hash(data);
+    }
+
+    let mut cv = Hasher::new()
+        .set_input_offset(offset)
+        .update(data)
+        .finalize_non_root();
+
+    for (i, (sibling, is_left)) in proof.iter().enumerate() {
+        let (left, right) = if *is_left {
+            (sibling, &cv)
+        } else {
+            (&cv, sibling)
+        };
+
+        if i + 1 == proof.len() {
+            return merge_subtrees_root(left, right, Mode::Hash);
+        }
+        cv = merge_subtrees_non_root(left, right, Mode::Hash);
+    }
+
+    // A partial slice without proof can't match any root hash
+    Hash::from(cv)
+}
+
+fn groups(len: u64) -> u64 {
+    len.div_ceil(GROUP_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Chaining value of the subtree at `start`, computed from the data.
+    fn subtree_cv(data: &[u8], start: u64, len: u64) -> ChainingValue {
+        if len <= GROUP_SIZE {
+            let group = &data[start as usize..(start + len) as usize];
+            return Hasher::new()
+                .set_input_offset(start)
+                .update(group)
+                .finalize_non_root();
+        }
+        let left = left_subtree_len(len);
+        merge_subtrees_non_root(
+            &subtree_cv(data, start, left),
+            &subtree_cv(data, start + left, len - left),
+            Mode::Hash,
+        )
+    }
+
+    /// Proof of the slice at `offset`, bottom-up, from a walk down the tree.
+    fn reference_proof(data: &[u8], offset: u64, slice_len: u64) -> Vec<(ChainingValue, bool)> {
+        let size = data.len() as u64;
+        let target_len = slice_len.min(size - offset);
+
+        let mut proof = Vec::new();
+        let (mut start, mut len) = (0, size);
+        while start != offset || len != target_len {
+            let left = left_subtree_len(len);
+            if offset < start + left {
+                proof.push((subtree_cv(data, start + left, len - left), false));
+                len = left;
+            } else {
+                proof.push((subtree_cv(data, start, left), true));
+                start += left;
+                len -= left;
+            }
+        }
+        proof.reverse();
+        proof
+    }
+
+    fn outboard_proof(outboard: &[u8], steps: &[ProofStep]) -> Vec<(ChainingValue, bool)> {
+        steps
+            .iter()
+            .map(|step| {
+                let node = &outboard[(step.node * NODE_SIZE) as usize..][..NODE_SIZE as usize];
+                let half = if step.sibling_is_left {
+                    &node[..32]
+                } else {
+                    &node[32..]
+                };
+                (half.try_into().unwrap(), step.sibling_is_left)
+            })
+            .collect()
+    }
+
+    fn test_data(size: u64) -> Vec<u8> {
+        (0..size).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    const SIZES: [u64; 9] = [
+        0,
+        1,
+        GROUP_SIZE - 1,
+        GROUP_SIZE,
+        GROUP_SIZE + 1,
+        3 * GROUP_SIZE,
+        5 * GROUP_SIZE - 7,
+        8 * GROUP_SIZE,
+        13 * GROUP_SIZE + 100,
+    ];
+
+    #[test]
+    fn tree_root_is_blake3_hash() {
+        for size in SIZES {
+            let data = test_data(size);
+            // Fed in pieces not aligned to the groups
+            let mut tree = TreeBuilder::new();
+            let mut outboard = Vec::new();
+            for piece in data.chunks(5000) {
+                tree.update(piece, &mut outboard);
+            }
+
+            assert_eq!(
+                tree.finalize(&mut outboard),
+                blake3::hash(&data),
+                "size {size}"
+            );
+            let nodes = groups(size).max(1) - 1;
+            assert_eq!(outboard.len() as u64, nodes * NODE_SIZE, "size {size}");
+        }
+    }
+
+    #[test]
+    fn slices_verify_against_root() {
+        for size in SIZES {
+            let data = test_data(size);
+            let mut outboard = Vec::new();
+            let mut tree = TreeBuilder::new();
+            tree.update(&data, &mut outboard);
+            let root = tree.finalize(&mut outboard);
+
+            for slice_len in [GROUP_SIZE, 2 * GROUP_SIZE, 4 * GROUP_SIZE] {
+                let mut offset = 0;
+                loop {
+                    let end = (offset + slice_len).min(size);
+                    let slice = &data[offset as usize..end as usize];
+                    let steps = proof_steps(size, offset, slice_len);
+                    let proof = outboard_proof(&outboard, &steps);
+                    assert_eq!(
+                        proof,
+                        reference_proof(&data, offset, slice_len),
+                        "size {size}, slice {slice_len} at {offset}"
+                    );
+                    assert_eq!(slice_root(size, offset, slice, &proof), root);
+
+                    let mut corrupted = slice.to_vec();
+                    if let Some(byte) = corrupted.last_mut() {
+                        *byte ^= 1;
+                        assert_ne!(slice_root(size, offset, &corrupted, &proof), root);
+                    }
+
+                    offset = end;
+                    if offset >= size {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_blob_has_no_proof() {
+        let mut outboard = Vec::new();
+        let root = TreeBuilder::new().finalize(&mut outboard);
+
+        assert_eq!(root, blake3::hash(b""));
+        assert!(outboard.is_empty());
+        assert!(proof_steps(0, 0, GROUP_SIZE).is_empty());
+        assert_eq!(slice_root(0, 0, b"", &[]), root);
+    }
+
+    #[test]
+    fn slice_len_is_power_of_two_groups() {
+        assert_eq!(slice_len(0), GROUP_SIZE);
+        assert_eq!(slice_len(GROUP_SIZE), GROUP_SIZE);
+        assert_eq!(slice_len(3 * GROUP_SIZE), 2 * GROUP_SIZE);
+        assert_eq!(slice_len(8 * GROUP_SIZE + 1), 8 * GROUP_SIZE);
+    }
+}
@@ -0,0 +1,162 @@
+use dotenvy::dotenv;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, IsTerminal},
+    path::PathBuf,
+};
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+use grpc_storage::{
+    db::DbState,
+    volumes::{VolumeSet, VolumeState},
+};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv().ok();
+
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "info");
+    }
+
+    tracing_subscriber::fmt()
+        .without_time()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init()
+        .map_err(|e| e.to_string())?;
+
+    let command = env::args().nth(1).unwrap_or_default();
+    let args: Vec<String> = env::args().skip(2).collect();
+
+    match command.as_str() {
+        "relativize-paths" => relativize_paths(&args),
+        "set-volume-state" => set_volume_state(&args),
+        "gc-chunks" => gc_chunks(&args),
+        "-h" | "--help" => {
+            print_help();
+            Ok(())
+        }
+        _ => {
+            println!("Unknown command.");
+            print_help();
+            Ok(())
+        }
+    }
+}
+
+fn print_help() {
+    println!("Usage:");
+    println!("  relativize-paths [--root <name>] [--from <old_folder>] [--dry-run]");
+    println!("      Rewrite absolute file paths under <old_folder> (defaults to the root's");
+    println!("      folder) to paths relative to the given storage root.");
+    println!("  set-volume-state <name> <online|read-only|offline>");
+    println!("      Change the state of a storage volume (applied on next server start).");
+    println!("  gc-chunks [--grace <seconds>] [--dry-run]");
+    println!("      Remove chunks no stored file references and that weren't used for the");
+    println!("      grace period (defaults to 3600 seconds).");
+}
+
+fn option_value<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a String>, String> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(pos) => args
+            .get(pos + 1)
+            .map(Some)
+            .ok_or(format!("'{}' requires a value", flag)),
+        None => Ok(None),
+    }
+}
+
+fn relativize_paths(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+
+    let db = DbState::new();
+    let volumes = VolumeSet::from_env(&db);
+
+    let root = match option_value(args, "--root")? {
+        Some(name) => db.get_storage_root_by_name(name),
+        None => volumes
+            .volumes()
+            .first()
+            .and_then(|vol| db.get_storage_root(vol.id)),
+    }
+    .ok_or("Unknown storage root")?;
+
+    let prefix = match option_value(args, "--from")? {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(&root.path),
+    };
+
+    let rewritten = db.relativize_paths(&root, &prefix, dry_run)?;
+
+    if dry_run {
+        println!("{} record(s) would be rewritten", rewritten);
+    } else {
+        println!("{} record(s) rewritten", rewritten);
+    }
+
+    Ok(())
+}
+
+fn set_volume_state(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let (Some(name), Some(state)) = (args.first(), args.get(1)) else {
+        return Err("Usage: set-volume-state <name> <online|read-only|offline>".into());
+    };
+    let state: VolumeState = state.parse()?;
+
+    let db = DbState::new();
+    let root = db
+        .get_storage_root_by_name(name)
+        .ok_or("Unknown storage volume")?;
+    db.set_storage_root_state(root.id, state.as_str())?;
+
+    println!("Volume '{}' is now {}", root.name, state);
+
+    Ok(())
+}
+
+fn gc_chunks(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let grace: i64 = match option_value(args, "--grace")? {
+        Some(secs) => secs.parse()?,
+        None => 3600,
+    };
+
+    let db = DbState::new();
+    let chunks = db.remove_unreferenced_chunks(grace, dry_run)?;
+    let bytes: i64 = chunks.iter().map(|chunk| chunk.chunk_size).sum();
+
+    if dry_run {
+        println!(
+            "{} chunk(s), {} bytes would be removed",
+            chunks.len(),
+            bytes
+        );
+        return Ok(());
+    }
+
+    let mut roots = HashMap::new();
+    for chunk in &chunks {
+        let root = roots
+            .entry(chunk.root_id)
+            .or_insert_with(|| db.get_storage_root(chunk.root_id));
+        let Some(root) = root else {
+            warn!(
+                "Unknown storage root {} of chunk {}",
+                chunk.root_id, chunk.chunk_hash
+            );
+            continue;
+        };
+
+        let path = PathBuf::from(&root.path).join(&chunk.file_path);
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Couldn't remove chunk \"{}\": {}", path.display(), e);
+        }
+    }
+
+    println!("{} chunk(s), {} bytes removed", chunks.len(), bytes);
+
+    Ok(())
+}
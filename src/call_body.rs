@@ -0,0 +1,182 @@
+//! Request and response bodies following the traffic and the outcome of the
+//! gRPC calls, for the layers of the metrics and the access log.
+
+use bytes::Bytes;
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    HeaderMap, Request, Response,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tonic::{
+    body::{boxed, BoxBody},
+    codegen::Service,
+    Code, Status,
+};
+
+/// Told about the traffic and the outcome of a gRPC call.
+pub trait CallObserver: Send + Sync + 'static {
+    /// Message bytes received from the client.
+    fn received(&self, bytes: u64);
+
+    /// Message bytes sent to the client.
+    fn sent(&self, bytes: u64);
+
+    /// The call ended with `code`, `Cancelled` if the client went away before
+    /// its status.
+    fn finished(&self, code: Code);
+}
+
+pub type CallFuture<E> = Pin<Box<dyn Future<Output = Result<Response<BoxBody>, E>> + Send>>;
+
+/// Takes the ready service to handle a call, leaving a clone in its place.
+pub fn take_ready<S: Clone>(service: &mut S) -> S {
+    let clone = service.clone();
+    std::mem::replace(service, clone)
+}
+
+/// Hands a call to the ready `service`, telling `observer` about it.
+pub fn observe_call<S, O>(
+    service: &mut S,
+    request: Request<BoxBody>,
+    observer: Arc<O>,
+) -> CallFuture<S::Error>
+where
+    S: Service<Request<BoxBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    O: CallObserver,
+{
+    let mut inner = take_ready(service);
+
+    let request = request.map(|inner| {
+        boxed(ReceivedBody {
+            inner,
+            observer: observer.clone(),
+        })
+    });
+    let mut call = Call {
+        observer,
+        code: None,
+    };
+
+    Box::pin(async move {
+        let response = inner
+            .call(request)
+            .await
+            .inspect_err(|_| call.code = Some(Code::Internal))?;
+        // Errors before any message come without a body
+        call.code = grpc_status(response.headers());
+
+        Ok(response.map(|inner| {
+            boxed(CallBody {
+                inner,
+                call: Some(call),
+            })
+        }))
+    })
+}
+
+pub fn grpc_status(headers: &HeaderMap) -> Option<Code> {
+    headers
+        .get("grpc-status")
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse::<i32>().ok())
+        .map(Code::from)
+}
+
+/// A call in progress, finished once its response ends or is dropped.
+struct Call<O: CallObserver> {
+    observer: Arc<O>,
+    code: Option<Code>,
+}
+
+impl<O: CallObserver> Drop for Call<O> {
+    fn drop(&mut self) {
+        // Without a status the client went away first
+        self.observer.finished(self.code.unwrap_or(Code::Cancelled));
+    }
+}
+
+/// A request body counting the received bytes.
+struct ReceivedBody<O> {
+    inner: BoxBody,
+    observer: Arc<O>,
+}
+
+impl<O: CallObserver> Body for ReceivedBody<O> {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Status>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &polled {
+            if let Some(data) = frame.data_ref() {
+                self.observer.received(data.len() as u64);
+            }
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A response body counting the sent bytes, which finishes its call with the
+/// status of the trailers once it ends.
+struct CallBody<O: CallObserver> {
+    inner: BoxBody,
+    // Taken once the call is finished
+    call: Option<Call<O>>,
+}
+
+impl<O: CallObserver> Body for CallBody<O> {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Status>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        let Some(call) = &mut self.call else {
+            return polled;
+        };
+
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    call.observer.sent(data.len() as u64);
+                } else if let Some(code) = frame.trailers_ref().and_then(grpc_status) {
+                    call.code = Some(code);
+                }
+            }
+            Poll::Ready(Some(Err(status))) => {
+                call.code = Some(status.code());
+                self.call = None;
+            }
+            Poll::Ready(None) => self.call = None,
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
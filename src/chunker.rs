@@ -0,0 +1,219 @@
+use dotenvy::dotenv;
+use fastcdc::v2020::{
+    FastCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
+use std::env;
+use tracing::error;
+
+/// Chunk size bounds of the content-defined chunking.
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkerConfig {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl ChunkerConfig {
+    /// Reads `CHUNKED_STORAGE` and the `CDC_*_SIZE` bounds. Returns `None`
+    /// unless chunked storage is enabled.
+    pub fn from_env() -> Option<Self> {
+        dotenv().ok();
+
+        let enabled: bool = env::var("CHUNKED_STORAGE")
+            .unwrap_or("false".to_owned())
+            .parse()
+            .unwrap_or_else(|_| {
+                error!("'CHUNKED_STORAGE' - should be 'true' or 'false'");
+                panic!()
+            });
+
+        if !enabled {
+            return None;
+        }
+
+        let config = Self {
+            min_size: parse_size("CDC_MIN_SIZE", 64 * 1024, MINIMUM_MIN, MINIMUM_MAX),
+            avg_size: parse_size("CDC_AVG_SIZE", 256 * 1024, AVERAGE_MIN, AVERAGE_MAX),
+            max_size: parse_size("CDC_MAX_SIZE", 1024 * 1024, MAXIMUM_MIN, MAXIMUM_MAX),
+        };
+
+        if config.min_size > config.avg_size || config.avg_size > config.max_size {
+            error!("'CDC_MIN_SIZE' <= 'CDC_AVG_SIZE' <= 'CDC_MAX_SIZE' is required");
+            panic!();
+        }
+
+        Some(config)
+    }
+}
+
+/// Splits a byte stream into content-defined chunks with FastCDC.
+///
+/// A cut is only made once `max_size` bytes are buffered (or at the end of the
+/// stream), so the chunks are the same no matter how the incoming data was
+/// split into messages.
+pub struct Chunker {
+    config: ChunkerConfig,
+    buffer: Vec<u8>,
+}
+
+impl Chunker {
+    pub fn new(config: ChunkerConfig) -> Self {
+        Self {
+            config,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Buffers `data` and returns the chunks that are complete.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+
+        let max_size = self.config.max_size as usize;
+        let mut chunks = Vec::new();
+        let mut start = 0;
+
+        if self.buffer.len() >= max_size {
+            let cdc = self.cdc();
+            while self.buffer.len() - start >= max_size {
+                let (_, end) = cdc.cut(start, self.buffer.len() - start);
+                chunks.push(self.buffer[start..end].to_vec());
+                start = end;
+            }
+        }
+
+        self.buffer.drain(..start);
+        chunks
+    }
+
+    /// Returns the remaining chunks at the end of the stream.
+    pub fn finish(&mut self) -> Vec<Vec<u8>> {
+        let chunks = self
+            .cdc()
+            .map(|chunk| self.buffer[chunk.offset..chunk.offset + chunk.length].to_vec())
+            .collect();
+
+        self.buffer.clear();
+        chunks
+    }
+
+    fn cdc(&self) -> FastCDC<'_> {
+        FastCDC::new(
+            &self.buffer,
+            self.config.min_size,
+            self.config.avg_size,
+            self.config.max_size,
+        )
+    }
+}
+
+fn parse_size(name: &str, default: u32, min: u32, max: u32) -> u32 {
+    let size: u32 = match env::var(name) {
+        Ok(val) => val.parse().unwrap_or_else(|_| {
+            error!(
+                "'{}' - should be an integer value in range: [{};{}]",
+                name, min, max
+            );
+            panic!()
+        }),
+        Err(_) => default,
+    };
+
+    if size < min || size > max {
+        error!(
+            "'{}' - should be an integer value in range: [{};{}]",
+            name, min, max
+        );
+        panic!();
+    }
+
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: ChunkerConfig = ChunkerConfig {
+        min_size: 1024,
+        avg_size: 4096,
+        max_size: 16 * 1024,
+    };
+
+    /// Pseudo-random bytes, so the cut points vary.
+    fn test_data(size: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunk(data: &[u8], message_size: usize) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(CONFIG);
+        let mut chunks = Vec::new();
+        for message in data.chunks(message_size) {
+            chunks.extend(chunker.push(message));
+        }
+        chunks.extend(chunker.finish());
+        chunks
+    }
+
+    #[test]
+    fn cuts_do_not_depend_on_messages() {
+        let data = test_data(300 * 1024);
+        let whole: Vec<Vec<u8>> =
+            FastCDC::new(&data, CONFIG.min_size, CONFIG.avg_size, CONFIG.max_size)
+                .map(|cut| data[cut.offset..cut.offset + cut.length].to_vec())
+                .collect();
+
+        for message_size in [1000, 4096, 16 * 1024, 50_000, data.len()] {
+            assert_eq!(
+                chunk(&data, message_size),
+                whole,
+                "messages of {message_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn chunks_stay_within_limits() {
+        let data = test_data(300 * 1024);
+        let chunks = chunk(&data, 7000);
+
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), data);
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert!(
+                chunk.len() >= CONFIG.min_size as usize,
+                "{} bytes",
+                chunk.len()
+            );
+            assert!(
+                chunk.len() <= CONFIG.max_size as usize,
+                "{} bytes",
+                chunk.len()
+            );
+        }
+        assert!(!last.is_empty() && last.len() <= CONFIG.max_size as usize);
+    }
+
+    #[test]
+    fn uniform_data_is_cut_at_max_size() {
+        let data = vec![0; 5 * CONFIG.max_size as usize + 10];
+        let chunks = chunk(&data, 3000);
+
+        let sizes: Vec<usize> = chunks.iter().map(Vec::len).collect();
+        let max = CONFIG.max_size as usize;
+        assert_eq!(sizes, [max, max, max, max, max, 10]);
+    }
+
+    #[test]
+    fn empty_stream_has_no_chunks() {
+        assert!(chunk(&[], 1024).is_empty());
+    }
+}
@@ -1,27 +1,199 @@
+use chrono::{NaiveDateTime, SecondsFormat, Utc};
 use diesel::{
+    dsl::{exists, not, now, And, Eq, Gt, IntervalDsl, IsNull, LtEq, Or},
     pg::PgConnection,
     prelude::*,
     r2d2::{ConnectionManager, Pool},
     SelectableHelper,
 };
 use dotenvy::dotenv;
-use log::error;
-use std::{env, time::Duration};
+use serde_json::json;
+use std::{collections::HashMap, env, path::Path, time::Duration};
+use tracing::{error, info};
 
 use crate::{
-    models::{NewStoreItem, StoreItem},
+    events::EventKind,
+    models::{
+        AuditRecord, Chunk, FileRef, MultipartPart, MultipartUpload, NamespaceRetention,
+        NewAuditRecord, NewChunk, NewFileChunk, NewFileRef, NewGovernanceAudit, NewMultipartPart,
+        NewMultipartUpload, NewNamespaceRetention, NewOutboxEvent, NewStorageRoot, NewStoreItem,
+        NewStoredEvent, NewVersionedKey, NewWebhook, NewWebhookDelivery, OutboxEvent, StorageRoot,
+        StoreItem, StoredEvent, VersionedKey, Webhook, WebhookDelivery,
+    },
     schema::{
+        audit_log, chunks, file_chunks, file_keys, governance_audit, multipart_parts,
+        multipart_uploads, namespace_retention, outbox, refs, storage_events, storage_roots,
         store::dsl::*,
         store::{self, file_hash},
+        webhook_deliveries, webhooks,
     },
+    telemetry,
 };
 
 pub type DbPool = Pool<ConnectionManager<PgConnection>>;
 
+/// Matches the records that haven't expired. Expiration times are UTC.
+fn not_expired() -> Or<IsNull<expires_at>, Gt<expires_at, NaiveDateTime>> {
+    expires_at
+        .is_null()
+        .or(expires_at.gt(Utc::now().naive_utc()))
+}
+
+/// Records past their retention, or never retained.
+type RetentionOver = Or<IsNull<retain_until>, LtEq<retain_until, NaiveDateTime>>;
+
+/// Matches the records that may be deleted: not on legal hold and past their
+/// retention, if any.
+fn unlocked() -> And<Eq<legal_hold, bool>, RetentionOver> {
+    legal_hold.eq(false).and(
+        retain_until
+            .is_null()
+            .or(retain_until.le(Utc::now().naive_utc())),
+    )
+}
+
+/// Adds a governance audit record, with the value changed by the action as
+/// `previous` in its details.
+fn record_governance(
+    conn: &mut PgConnection,
+    mut audit: NewGovernanceAudit,
+    previous: serde_json::Value,
+) -> QueryResult<()> {
+    if let Some(details) = audit.details.as_object_mut() {
+        details.insert("previous".to_owned(), previous);
+    }
+
+    diesel::insert_into(governance_audit::table)
+        .values(&audit)
+        .execute(conn)
+        .map(|_| ())
+}
+
+/// States of a webhook delivery.
+pub const DELIVERY_PENDING: &str = "pending";
+pub const DELIVERY_DELIVERED: &str = "delivered";
+pub const DELIVERY_DEAD: &str = "dead";
+
+/// Records an event of the given kind for each of the records, in the
+/// transaction of the change: in `storage_events` for the watchers and, but
+/// for corrupted files, in the outbox to be sent to the webhooks.
+fn add_events(conn: &mut PgConnection, kind: EventKind, items: &[StoreItem]) -> QueryResult<()> {
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let key_ids: Vec<i32> = items.iter().filter_map(|item| item.key_id).collect();
+    let keys: HashMap<i32, (String, String)> = if key_ids.is_empty() {
+        HashMap::new()
+    } else {
+        file_keys::table
+            .filter(file_keys::id.eq_any(&key_ids))
+            .select((file_keys::id, file_keys::namespace, file_keys::file_key))
+            .load::<(i32, String, String)>(conn)?
+            .into_iter()
+            .map(|(key, ns, name)| (key, (ns, name)))
+            .collect()
+    };
+
+    let stored: Vec<NewStoredEvent> = items
+        .iter()
+        .map(|item| {
+            let key = item.key_id.and_then(|key| keys.get(&key));
+            NewStoredEvent {
+                kind: kind.as_str().to_owned(),
+                file_hash: item.file_hash.clone(),
+                file_name: item.file_name.clone(),
+                file_size: item.file_size.unwrap_or(0),
+                namespace: key.map(|(ns, _)| ns.clone()),
+                file_key: key.map(|(_, name)| name.clone()),
+                version: item.version,
+            }
+        })
+        .collect();
+
+    for batch in stored.chunks(1000) {
+        diesel::insert_into(storage_events::table)
+            .values(batch)
+            .execute(conn)?;
+    }
+
+    if kind == EventKind::FileCorrupted {
+        return Ok(());
+    }
+
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let events: Vec<NewOutboxEvent> = items
+        .iter()
+        .map(|item| {
+            let key = item.key_id.and_then(|key| keys.get(&key));
+            NewOutboxEvent {
+                kind: kind.as_str().to_owned(),
+                payload: json!({
+                    "event": kind.as_str(),
+                    "time": time,
+                    "fileHash": item.file_hash,
+                    "fileName": item.file_name,
+                    "size": item.file_size.unwrap_or(0),
+                    "contentType": item.content_type,
+                    "namespace": key.map(|(ns, _)| ns),
+                    "key": key.map(|(_, name)| name),
+                    "version": item.version,
+                }),
+            }
+        })
+        .collect();
+
+    for batch in events.chunks(1000) {
+        diesel::insert_into(outbox::table)
+            .values(batch)
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Events in a later transaction, or in the same one with a higher id.
+type EventAfter = Or<
+    Gt<storage_events::txid, i64>,
+    And<Eq<storage_events::txid, i64>, Gt<storage_events::id, i64>>,
+>;
+
+/// Matches the storage events after a position, the `(txid, id)` of an
+/// event. Events are passed on in the order of their positions.
+fn event_after(position: (i64, i64)) -> EventAfter {
+    let (txid, event) = position;
+    storage_events::txid.gt(txid).or(storage_events::txid
+        .eq(txid)
+        .and(storage_events::id.gt(event)))
+}
+
+/// The oldest transaction still running. Events of earlier transactions are
+/// all committed, later ones may still be added.
+fn event_horizon() -> diesel::expression::SqlLiteral<diesel::sql_types::BigInt> {
+    diesel::dsl::sql("pg_snapshot_xmin(pg_current_snapshot())::TEXT::BIGINT")
+}
+
+/// Which audit records to load, unset fields match everything.
+#[derive(Debug, Default)]
+pub struct AuditFilter<'a> {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub caller: Option<&'a str>,
+    pub file_hash: Option<&'a str>,
+    pub operation: Option<&'a str>,
+}
+
+#[derive(Clone)]
 pub struct DbState {
     pub db_pool: DbPool,
 }
 
+impl Default for DbState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DbState {
     pub fn new() -> Self {
         dotenv().ok();
@@ -54,6 +226,10 @@ impl DbState {
             Err(_) => 600,
         };
 
+        if let Err(e) = diesel::connection::set_default_instrumentation(telemetry::query_spans) {
+            error!("Couldn't trace DB queries! Error: {}", e);
+        }
+
         let connection_manager = ConnectionManager::<PgConnection>::new(database_url);
 
         match Pool::builder()
@@ -62,7 +238,7 @@ impl DbState {
             .idle_timeout(Some(Duration::new(database_idle_timeout, 0)))
             .build(connection_manager)
         {
-            Ok(pool) => return Self { db_pool: pool },
+            Ok(pool) => Self { db_pool: pool },
             Err(e) => {
                 error!("Couldn't create connection pool! Err: {}", e);
                 panic!()
@@ -75,31 +251,225 @@ impl DbState {
 
         match store
             .filter(file_hash.eq(hash))
+            .filter(not_expired())
+            .order(id.asc())
             .select(StoreItem::as_select())
             .load(&mut connection)
         {
             Ok(mut list) => {
-                if list.len() > 0 {
-                    return Some(list.remove(0));
+                if !list.is_empty() {
+                    Some(list.remove(0))
                 } else {
-                    return None;
+                    None
                 }
             }
             Err(_) => None,
         }
     }
 
-    pub fn add_new_item(&self, item: &NewStoreItem) -> Result<StoreItem, diesel::result::Error> {
+    /// Returns a stored file with the given hash that isn't flagged as broken.
+    pub fn get_healthy_file_by_hash(&self, hash: &str) -> Option<StoreItem> {
         let mut connection = self.db_pool.get().unwrap();
 
-        match diesel::insert_into(store::table)
-            .values(item)
+        store
+            .filter(file_hash.eq(hash))
+            .filter(file_is_error.eq(false))
+            .filter(not_expired())
+            .select(StoreItem::as_select())
+            .first(&mut connection)
+            .ok()
+    }
+
+    /// Returns which of the given hashes are stored in healthy files.
+    pub fn get_healthy_hashes(
+        &self,
+        hashes: &[String],
+    ) -> Result<Vec<String>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        store
+            .filter(file_hash.eq_any(hashes))
+            .filter(file_is_error.eq(false))
+            .filter(not_expired())
+            .select(file_hash)
+            .distinct()
+            .load(&mut connection)
+    }
+
+    /// Loads the stored files with any of the given hashes, oldest first.
+    pub fn get_files_by_hashes(
+        &self,
+        hashes: &[String],
+    ) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        store
+            .filter(file_hash.eq_any(hashes))
+            .filter(not_expired())
+            .order(id.asc())
+            .select(StoreItem::as_select())
+            .load(&mut connection)
+    }
+
+    /// Sets when the unexpired records with the given hash expire, `None`
+    /// keeps them. Returns the updated records, oldest first.
+    pub fn set_expiration(
+        &self,
+        hash: &str,
+        expiration: Option<NaiveDateTime>,
+    ) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let mut updated = diesel::update(store)
+            .filter(file_hash.eq(hash))
+            .filter(not_expired())
+            .set(expires_at.eq(expiration))
             .returning(StoreItem::as_returning())
-            .get_result(&mut connection)
-        {
-            Ok(res) => Ok(res),
-            Err(e) => Err(e),
+            .get_results(&mut connection)?;
+        updated.sort_by_key(|item: &StoreItem| item.id);
+
+        Ok(updated)
+    }
+
+    /// Loads up to `limit` expired records after `after_id` in id order,
+    /// leaving out those a ref points at and locked ones.
+    pub fn get_expired(
+        &self,
+        after_id: i32,
+        limit: i64,
+    ) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        store
+            .filter(id.gt(after_id))
+            .filter(expires_at.le(Utc::now().naive_utc()))
+            .filter(unlocked())
+            .filter(not(exists(
+                refs::table.filter(refs::file_hash.eq(store::file_hash)),
+            )))
+            .order(id.asc())
+            .limit(limit)
+            .select(StoreItem::as_select())
+            .load(&mut connection)
+    }
+
+    /// Removes the given records unless they were extended, referenced or
+    /// locked meanwhile. Returns the removed ones.
+    pub fn remove_expired(&self, ids: &[i32]) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let removed = diesel::delete(store.filter(id.eq_any(ids)))
+                .filter(expires_at.le(Utc::now().naive_utc()))
+                .filter(unlocked())
+                .filter(not(exists(
+                    refs::table.filter(refs::file_hash.eq(store::file_hash)),
+                )))
+                .returning(StoreItem::as_returning())
+                .get_results(conn)?;
+
+            add_events(conn, EventKind::FileExpired, &removed)?;
+            Ok(removed)
+        })
+    }
+
+    /// Replaces the metadata and tags of a record if it's still at the given
+    /// generation, and bumps the generation. Returns `None` if the record
+    /// changed meanwhile and `NotFound` if it's gone.
+    pub fn update_metadata(
+        &self,
+        rec_id: i32,
+        expected_generation: i64,
+        new_metadata: serde_json::Value,
+        new_tags: &[String],
+    ) -> Result<Option<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let updated = diesel::update(store.find(rec_id))
+                .filter(generation.eq(expected_generation))
+                .set((
+                    metadata.eq(new_metadata),
+                    tags.eq(new_tags),
+                    generation.eq(generation + 1),
+                ))
+                .returning(StoreItem::as_returning())
+                .get_result(conn)
+                .optional()?;
+
+            match updated {
+                Some(item) => Ok(Some(item)),
+                None => store
+                    .find(rec_id)
+                    .select(id)
+                    .first::<i32>(conn)
+                    .map(|_| None),
+            }
+        })
+    }
+
+    /// Lists records with all of `with_tags` and the metadata key (with the
+    /// value, if given), in id order after `after_id`.
+    pub fn list_files(
+        &self,
+        with_tags: &[String],
+        metadata_key: Option<&str>,
+        metadata_value: Option<&str>,
+        after_id: i32,
+        limit: i64,
+    ) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let mut query = store
+            .filter(id.gt(after_id))
+            .filter(not_expired())
+            .into_boxed();
+        if !with_tags.is_empty() {
+            query = query.filter(tags.contains(with_tags));
+        }
+        match (metadata_key, metadata_value) {
+            (Some(key), Some(value)) => {
+                query = query.filter(metadata.contains(serde_json::json!({ key: value })));
+            }
+            (Some(key), None) => query = query.filter(metadata.has_key(key)),
+            _ => {}
         }
+
+        query
+            .order(id.asc())
+            .limit(limit)
+            .select(StoreItem::as_select())
+            .load(&mut connection)
+    }
+
+    /// Records a stored file. Unless it is to become a version of a key,
+    /// which announces it then, its upload is recorded as an event as well.
+    pub fn add_new_item(
+        &self,
+        item: &NewStoreItem,
+        announce: bool,
+    ) -> Result<StoreItem, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let res = diesel::insert_into(store::table)
+                .values(item)
+                .returning(StoreItem::as_returning())
+                .get_result(conn)?;
+
+            if announce {
+                add_events(conn, EventKind::FileUploaded, std::slice::from_ref(&res))?;
+            }
+            Ok(res)
+        })
+    }
+
+    /// Records the uploads of files added without announcing them, in one
+    /// transaction.
+    pub fn announce_uploads(&self, items: &[StoreItem]) -> Result<(), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| add_events(conn, EventKind::FileUploaded, items))
     }
 
     pub fn update_last_read_state(
@@ -114,22 +484,1140 @@ impl DbState {
             .set(file_is_error.eq(state))
             .get_result::<StoreItem>(&mut connection)
         {
-            Ok(item) => {
-                return Ok(item);
-            }
+            Ok(item) => Ok(item),
             Err(e) => Err(e),
         }
     }
 
-    pub fn remove_item_by_hash(&self, hash: String) -> Result<StoreItem, diesel::result::Error> {
+    /// Removes the record of a file unless it is locked or referenced.
+    /// Returns `None` if it was kept.
+    pub fn remove_item_by_hash(
+        &self,
+        hash: String,
+    ) -> Result<Option<StoreItem>, diesel::result::Error> {
         let mut connection = self.db_pool.get().unwrap();
 
         match self.get_file_by_hash(hash) {
-            Some(rec) => {
-                diesel::delete(store.filter(id.eq(rec.id))).execute(&mut connection)?;
-                Ok(rec)
-            }
+            Some(rec) => connection.transaction(|conn| {
+                // Locked first, so refs set meanwhile are seen by the delete
+                store
+                    .filter(id.eq(rec.id))
+                    .select(id)
+                    .for_update()
+                    .first::<i32>(conn)?;
+
+                match diesel::delete(store.filter(id.eq(rec.id)))
+                    .filter(unlocked())
+                    .filter(not(exists(
+                        refs::table.filter(refs::file_hash.eq(store::file_hash)),
+                    )))
+                    .execute(conn)?
+                {
+                    0 => Ok(None),
+                    _ => {
+                        add_events(conn, EventKind::FileDeleted, std::slice::from_ref(&rec))?;
+                        Ok(Some(rec))
+                    }
+                }
+            }),
             None => Err(diesel::result::Error::NotFound),
         }
     }
+
+    /// Removes the given records in one transaction. Returns the removed ones,
+    /// records removed meanwhile are skipped. With `announce` their deletion
+    /// is recorded as an event.
+    pub fn remove_items(
+        &self,
+        ids: &[i32],
+        announce: bool,
+    ) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let removed = diesel::delete(store.filter(id.eq_any(ids)))
+                .returning(StoreItem::as_returning())
+                .get_results(conn)?;
+
+            if announce {
+                add_events(conn, EventKind::FileDeleted, &removed)?;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Removes the given records unless they are locked or referenced.
+    /// Returns the removed ones.
+    pub fn remove_unlocked(&self, ids: &[i32]) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            // Locked first, so refs set meanwhile are seen by the delete
+            store
+                .filter(id.eq_any(ids))
+                .select(id)
+                .order(id.asc())
+                .for_update()
+                .load::<i32>(conn)?;
+
+            let removed = diesel::delete(store.filter(id.eq_any(ids)))
+                .filter(unlocked())
+                .filter(not(exists(
+                    refs::table.filter(refs::file_hash.eq(store::file_hash)),
+                )))
+                .returning(StoreItem::as_returning())
+                .get_results(conn)?;
+
+            add_events(conn, EventKind::FileDeleted, &removed)?;
+            Ok(removed)
+        })
+    }
+
+    /// Returns the default retention of a namespace, in seconds.
+    pub fn get_namespace_retention(&self, ns: &str) -> Option<i64> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        namespace_retention::table
+            .find(ns)
+            .select(namespace_retention::retention_secs)
+            .first(&mut connection)
+            .ok()
+    }
+
+    /// Sets (or with `None` removes) the default retention of a namespace and
+    /// records the change with the previous retention. Returns the previous
+    /// retention.
+    pub fn set_namespace_retention(
+        &self,
+        ns: &str,
+        retention: Option<i64>,
+        audit: NewGovernanceAudit,
+    ) -> Result<Option<i64>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let previous = diesel::delete(namespace_retention::table.find(ns))
+                .returning(NamespaceRetention::as_returning())
+                .get_result(conn)
+                .optional()?
+                .map(|stored| stored.retention_secs);
+
+            if let Some(retention_secs) = retention {
+                diesel::insert_into(namespace_retention::table)
+                    .values(&NewNamespaceRetention {
+                        namespace: ns.to_owned(),
+                        retention_secs,
+                    })
+                    .execute(conn)?;
+            }
+
+            record_governance(conn, audit, serde_json::json!(previous))?;
+            Ok(previous)
+        })
+    }
+
+    /// Locks the records with the given hash until `until`. Returns `None`
+    /// without changing anything if a record is already locked for longer,
+    /// and `NotFound` if no record has the hash.
+    pub fn extend_retention(
+        &self,
+        hash: &str,
+        until: NaiveDateTime,
+    ) -> Result<Option<Vec<StoreItem>>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let current: Vec<Option<NaiveDateTime>> = store
+                .filter(file_hash.eq(hash))
+                .select(retain_until)
+                .for_update()
+                .load(conn)?;
+            if current.is_empty() {
+                return Err(diesel::result::Error::NotFound);
+            }
+            if current.iter().flatten().any(|locked| *locked > until) {
+                return Ok(None);
+            }
+
+            let mut updated = diesel::update(store.filter(file_hash.eq(hash)))
+                .set(retain_until.eq(until))
+                .returning(StoreItem::as_returning())
+                .get_results(conn)?;
+            updated.sort_by_key(|item: &StoreItem| item.id);
+
+            Ok(Some(updated))
+        })
+    }
+
+    /// Sets the retention of the records with the given hash, bypassing the
+    /// current one, and records the change with the previous retentions (unix
+    /// seconds). Returns the updated records, oldest first.
+    pub fn override_retention(
+        &self,
+        hash: &str,
+        until: Option<NaiveDateTime>,
+        audit: NewGovernanceAudit,
+    ) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let previous: Vec<Option<NaiveDateTime>> = store
+                .filter(file_hash.eq(hash))
+                .order(id.asc())
+                .select(retain_until)
+                .for_update()
+                .load(conn)?;
+            if previous.is_empty() {
+                return Err(diesel::result::Error::NotFound);
+            }
+
+            let mut updated = diesel::update(store.filter(file_hash.eq(hash)))
+                .set(retain_until.eq(until))
+                .returning(StoreItem::as_returning())
+                .get_results(conn)?;
+            updated.sort_by_key(|item: &StoreItem| item.id);
+
+            let previous: Vec<Option<i64>> = previous
+                .iter()
+                .map(|locked| locked.map(|at| at.and_utc().timestamp()))
+                .collect();
+            record_governance(conn, audit, serde_json::json!(previous))?;
+
+            Ok(updated)
+        })
+    }
+
+    /// Places or releases a legal hold on the records with the given hash and
+    /// records the change with the previous holds. Returns the updated
+    /// records, oldest first.
+    pub fn set_legal_hold(
+        &self,
+        hash: &str,
+        hold: bool,
+        audit: NewGovernanceAudit,
+    ) -> Result<Vec<StoreItem>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let previous: Vec<bool> = store
+                .filter(file_hash.eq(hash))
+                .order(id.asc())
+                .select(legal_hold)
+                .for_update()
+                .load(conn)?;
+            if previous.is_empty() {
+                return Err(diesel::result::Error::NotFound);
+            }
+
+            let mut updated = diesel::update(store.filter(file_hash.eq(hash)))
+                .set(legal_hold.eq(hold))
+                .returning(StoreItem::as_returning())
+                .get_results(conn)?;
+            updated.sort_by_key(|item: &StoreItem| item.id);
+
+            record_governance(conn, audit, serde_json::json!(previous))?;
+            Ok(updated)
+        })
+    }
+
+    /// Makes a stored item the next version of a key, creating the key on its
+    /// first upload, and sets the key's retention if `keep` is given. Versions
+    /// beyond the retention (`default_keep` if the key has none, 0 keeps all)
+    /// are removed in the same transaction. Returns the updated item and the
+    /// removed versions.
+    pub fn add_version(
+        &self,
+        item: i32,
+        key: &NewVersionedKey,
+        default_keep: i32,
+    ) -> Result<(StoreItem, Vec<StoreItem>), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let mut stored: VersionedKey = diesel::insert_into(file_keys::table)
+                .values(key)
+                .on_conflict((file_keys::namespace, file_keys::file_key))
+                .do_update()
+                .set(file_keys::latest_version.eq(file_keys::latest_version + 1))
+                .returning(VersionedKey::as_returning())
+                .get_result(conn)?;
+
+            if key.keep_versions.is_some() && stored.keep_versions != key.keep_versions {
+                stored = diesel::update(file_keys::table.find(stored.id))
+                    .set(file_keys::keep_versions.eq(key.keep_versions))
+                    .returning(VersionedKey::as_returning())
+                    .get_result(conn)?;
+            }
+
+            let res = diesel::update(store.find(item))
+                .set((key_id.eq(stored.id), version.eq(stored.latest_version)))
+                .returning(StoreItem::as_returning())
+                .get_result(conn)?;
+
+            add_events(conn, EventKind::FileUploaded, std::slice::from_ref(&res))?;
+
+            let keep = stored.keep_versions.unwrap_or(default_keep);
+            if keep <= 0 {
+                return Ok((res, Vec::new()));
+            }
+
+            let expired: Vec<i32> = store
+                .filter(key_id.eq(stored.id))
+                .order(version.desc())
+                .offset(keep as i64)
+                .select(id)
+                .load(conn)?;
+            // Versions a ref points at are kept until the ref is moved, locked
+            // ones until they are unlocked
+            let removed = diesel::delete(store.filter(id.eq_any(expired)))
+                .filter(unlocked())
+                .filter(not(exists(
+                    refs::table.filter(refs::file_hash.eq(store::file_hash)),
+                )))
+                .returning(StoreItem::as_returning())
+                .get_results(conn)?;
+
+            add_events(conn, EventKind::FileDeleted, &removed)?;
+            Ok((res, removed))
+        })
+    }
+
+    /// Returns the given version of a key, or its latest one.
+    pub fn get_version(
+        &self,
+        namespace: &str,
+        key: &str,
+        file_version: Option<i32>,
+    ) -> Option<StoreItem> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let mut query = store
+            .inner_join(file_keys::table)
+            .filter(file_keys::namespace.eq(namespace))
+            .filter(file_keys::file_key.eq(key))
+            .filter(not_expired())
+            .into_boxed();
+        if let Some(file_version) = file_version {
+            query = query.filter(version.eq(file_version));
+        }
+
+        query
+            .order(version.desc())
+            .select(StoreItem::as_select())
+            .first(&mut connection)
+            .ok()
+    }
+
+    /// Loads a key with its stored versions, newest first.
+    pub fn get_versions(
+        &self,
+        namespace: &str,
+        key: &str,
+    ) -> Result<(VersionedKey, Vec<StoreItem>), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let stored: VersionedKey = file_keys::table
+            .filter(file_keys::namespace.eq(namespace))
+            .filter(file_keys::file_key.eq(key))
+            .select(VersionedKey::as_select())
+            .first(&mut connection)?;
+
+        let versions = store
+            .filter(key_id.eq(stored.id))
+            .filter(not_expired())
+            .order(version.desc())
+            .select(StoreItem::as_select())
+            .load(&mut connection)?;
+
+        Ok((stored, versions))
+    }
+
+    /// Points a ref at a stored hash. With `expected` the ref is only moved
+    /// if it points at that hash, or only created if `expected` is empty.
+    /// Returns `None` if the comparison failed and `NotFound` if no file has
+    /// the hash.
+    pub fn set_ref(
+        &self,
+        new_ref: &NewFileRef,
+        expected: Option<&str>,
+    ) -> Result<Option<FileRef>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            // Fails if the file is deleted meanwhile. A delete waits for this
+            // lock and then checks for refs again, so it sees this one
+            store
+                .filter(file_hash.eq(&new_ref.file_hash))
+                .filter(not_expired())
+                .select(id)
+                .for_share()
+                .first::<i32>(conn)?;
+
+            match expected {
+                None => diesel::insert_into(refs::table)
+                    .values(new_ref)
+                    .on_conflict(refs::name)
+                    .do_update()
+                    .set((
+                        refs::file_hash.eq(&new_ref.file_hash),
+                        refs::updated_at.eq(now),
+                    ))
+                    .returning(FileRef::as_returning())
+                    .get_result(conn)
+                    .map(Some),
+                Some("") => diesel::insert_into(refs::table)
+                    .values(new_ref)
+                    .on_conflict_do_nothing()
+                    .returning(FileRef::as_returning())
+                    .get_result(conn)
+                    .optional(),
+                Some(expected) => diesel::update(refs::table.find(&new_ref.name))
+                    .filter(refs::file_hash.eq(expected))
+                    .set((
+                        refs::file_hash.eq(&new_ref.file_hash),
+                        refs::updated_at.eq(now),
+                    ))
+                    .returning(FileRef::as_returning())
+                    .get_result(conn)
+                    .optional(),
+            }
+        })
+    }
+
+    pub fn get_ref(&self, ref_name: &str) -> Option<FileRef> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        refs::table
+            .find(ref_name)
+            .select(FileRef::as_select())
+            .first(&mut connection)
+            .ok()
+    }
+
+    /// Removes a ref, only if it points at `expected` when given. Returns
+    /// `None` if there was no such ref.
+    pub fn remove_ref(
+        &self,
+        ref_name: &str,
+        expected: Option<&str>,
+    ) -> Result<Option<FileRef>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let mut query = diesel::delete(refs::table.find(ref_name)).into_boxed();
+        if let Some(expected) = expected {
+            query = query.filter(refs::file_hash.eq(expected));
+        }
+
+        query
+            .returning(FileRef::as_returning())
+            .get_result(&mut connection)
+            .optional()
+    }
+
+    /// Lists refs starting with `prefix` in name order, after `start_after`.
+    pub fn list_refs(
+        &self,
+        prefix: &str,
+        start_after: &str,
+        limit: i64,
+    ) -> Result<Vec<FileRef>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let pattern = format!(
+            "{}%",
+            prefix
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+
+        refs::table
+            .filter(refs::name.like(pattern))
+            .filter(refs::name.gt(start_after))
+            .order(refs::name.asc())
+            .limit(limit)
+            .select(FileRef::as_select())
+            .load(&mut connection)
+    }
+
+    /// Loads the refs pointing at any of the given hashes.
+    pub fn get_refs_to(&self, hashes: &[String]) -> Result<Vec<FileRef>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        refs::table
+            .filter(refs::file_hash.eq_any(hashes))
+            .order(refs::name.asc())
+            .select(FileRef::as_select())
+            .load(&mut connection)
+    }
+
+    pub fn register_storage_root(
+        &self,
+        root: &NewStorageRoot,
+    ) -> Result<StorageRoot, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::insert_into(storage_roots::table)
+            .values(root)
+            .on_conflict(storage_roots::name)
+            .do_update()
+            .set((
+                storage_roots::path.eq(&root.path),
+                storage_roots::weight.eq(root.weight),
+                storage_roots::min_free_bytes.eq(root.min_free_bytes),
+            ))
+            .returning(StorageRoot::as_returning())
+            .get_result(&mut connection)
+    }
+
+    pub fn set_storage_root_state(
+        &self,
+        root: i32,
+        root_state: &str,
+    ) -> Result<StorageRoot, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::update(storage_roots::table.find(root))
+            .set(storage_roots::state.eq(root_state))
+            .returning(StorageRoot::as_returning())
+            .get_result(&mut connection)
+    }
+
+    pub fn get_storage_root(&self, root: i32) -> Option<StorageRoot> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        storage_roots::table
+            .find(root)
+            .select(StorageRoot::as_select())
+            .first(&mut connection)
+            .ok()
+    }
+
+    pub fn get_storage_root_by_name(&self, root_name: &str) -> Option<StorageRoot> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        storage_roots::table
+            .filter(storage_roots::name.eq(root_name))
+            .select(StorageRoot::as_select())
+            .first(&mut connection)
+            .ok()
+    }
+
+    /// Rewrites legacy records that still hold an absolute `file_path` located
+    /// under `prefix` into paths relative to `root`. Records outside of the
+    /// prefix are left untouched. Returns the number of rewritten records.
+    pub fn relativize_paths(
+        &self,
+        root: &StorageRoot,
+        prefix: &Path,
+        dry_run: bool,
+    ) -> Result<usize, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let legacy = store
+                .filter(root_id.is_null())
+                .select(StoreItem::as_select())
+                .load(conn)?;
+
+            let mut rewritten = 0;
+            for item in legacy {
+                let relative = match Path::new(&item.file_path).strip_prefix(prefix) {
+                    Ok(rel) => rel.display().to_string(),
+                    Err(_) => continue,
+                };
+
+                info!("{} -> {}:{}", item.file_path, root.name, relative);
+                if !dry_run {
+                    diesel::update(store.filter(id.eq(item.id)))
+                        .set((file_path.eq(&relative), root_id.eq(root.id)))
+                        .execute(conn)?;
+                }
+                rewritten += 1;
+            }
+
+            Ok(rewritten)
+        })
+    }
+
+    pub fn create_multipart_upload(
+        &self,
+        item: &NewMultipartUpload,
+    ) -> Result<MultipartUpload, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::insert_into(multipart_uploads::table)
+            .values(item)
+            .returning(MultipartUpload::as_returning())
+            .get_result(&mut connection)
+    }
+
+    pub fn get_multipart_upload(&self, upload: &str) -> Option<MultipartUpload> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        multipart_uploads::table
+            .filter(multipart_uploads::upload_id.eq(upload))
+            .select(MultipartUpload::as_select())
+            .first(&mut connection)
+            .ok()
+    }
+
+    pub fn get_multipart_parts(
+        &self,
+        multipart: i32,
+    ) -> Result<Vec<MultipartPart>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        multipart_parts::table
+            .filter(multipart_parts::multipart_id.eq(multipart))
+            .order(multipart_parts::part_number.asc())
+            .select(MultipartPart::as_select())
+            .load(&mut connection)
+    }
+
+    /// Records an uploaded part, replacing a previous upload of the same part
+    /// number. Returns the replaced part, if any.
+    pub fn upsert_multipart_part(
+        &self,
+        part: &NewMultipartPart,
+    ) -> Result<Option<MultipartPart>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let replaced = multipart_parts::table
+                .filter(multipart_parts::multipart_id.eq(part.multipart_id))
+                .filter(multipart_parts::part_number.eq(part.part_number))
+                .select(MultipartPart::as_select())
+                .for_update()
+                .first(conn)
+                .optional()?;
+
+            diesel::insert_into(multipart_parts::table)
+                .values(part)
+                .on_conflict((multipart_parts::multipart_id, multipart_parts::part_number))
+                .do_update()
+                .set((
+                    multipart_parts::part_hash.eq(&part.part_hash),
+                    multipart_parts::part_size.eq(part.part_size),
+                    multipart_parts::file_path.eq(&part.file_path),
+                ))
+                .execute(conn)?;
+
+            Ok(replaced)
+        })
+    }
+
+    /// Records the file assembled from a multipart upload as a regular store
+    /// item and forgets the upload. Fails with `NotFound` if the upload was
+    /// completed or aborted meanwhile. `announce` as with `add_new_item`.
+    pub fn complete_multipart_upload(
+        &self,
+        multipart: i32,
+        item: &NewStoreItem,
+        announce: bool,
+    ) -> Result<StoreItem, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let removed = diesel::delete(multipart_uploads::table.find(multipart)).execute(conn)?;
+            if removed == 0 {
+                return Err(diesel::result::Error::NotFound);
+            }
+
+            let res = diesel::insert_into(store::table)
+                .values(item)
+                .returning(StoreItem::as_returning())
+                .get_result(conn)?;
+
+            if announce {
+                add_events(conn, EventKind::FileUploaded, std::slice::from_ref(&res))?;
+            }
+            Ok(res)
+        })
+    }
+
+    pub fn remove_multipart_upload(&self, multipart: i32) -> Result<(), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        match diesel::delete(multipart_uploads::table.find(multipart)).execute(&mut connection)? {
+            0 => Err(diesel::result::Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Looks up a stored chunk and marks it as used, so garbage collection
+    /// leaves it alone while an upload referencing it is in progress.
+    pub fn touch_chunk(&self, hash: &str) -> Option<Chunk> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::update(chunks::table.filter(chunks::chunk_hash.eq(hash)))
+            .set(chunks::last_used_at.eq(now))
+            .returning(Chunk::as_returning())
+            .get_result(&mut connection)
+            .ok()
+    }
+
+    /// Registers a written chunk. If the same chunk was registered meanwhile,
+    /// the existing record is returned.
+    pub fn add_chunk(&self, chunk: &NewChunk) -> Result<Chunk, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::insert_into(chunks::table)
+            .values(chunk)
+            .on_conflict(chunks::chunk_hash)
+            .do_update()
+            .set(chunks::last_used_at.eq(now))
+            .returning(Chunk::as_returning())
+            .get_result(&mut connection)
+    }
+
+    /// Records a chunked file together with its ordered chunk manifest.
+    /// `announce` as with `add_new_item`.
+    pub fn add_chunked_item(
+        &self,
+        item: &NewStoreItem,
+        chunk_ids: &[i32],
+        announce: bool,
+    ) -> Result<StoreItem, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let res = diesel::insert_into(store::table)
+                .values(item)
+                .returning(StoreItem::as_returning())
+                .get_result(conn)?;
+
+            let manifest: Vec<NewFileChunk> = chunk_ids
+                .iter()
+                .enumerate()
+                .map(|(position, chunk)| NewFileChunk {
+                    store_id: res.id,
+                    position: position as i32,
+                    chunk_id: *chunk,
+                })
+                .collect();
+
+            // Keeps every statement below the bind parameter limit of Postgres
+            for batch in manifest.chunks(10_000) {
+                diesel::insert_into(file_chunks::table)
+                    .values(batch)
+                    .execute(conn)?;
+            }
+
+            if announce {
+                add_events(conn, EventKind::FileUploaded, std::slice::from_ref(&res))?;
+            }
+            Ok(res)
+        })
+    }
+
+    pub fn get_file_chunks(&self, item: i32) -> Result<Vec<Chunk>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        file_chunks::table
+            .inner_join(chunks::table)
+            .filter(file_chunks::store_id.eq(item))
+            .order(file_chunks::position.asc())
+            .select(Chunk::as_select())
+            .load(&mut connection)
+    }
+
+    /// Removes chunk records that no file references and that weren't used for
+    /// `grace_secs`. Returns the removed (or, on a dry run, removable) chunks.
+    pub fn remove_unreferenced_chunks(
+        &self,
+        grace_secs: i64,
+        dry_run: bool,
+    ) -> Result<Vec<Chunk>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let unreferenced = chunks::table
+            .filter(chunks::last_used_at.lt(now - grace_secs.seconds()))
+            .filter(not(exists(
+                file_chunks::table.filter(file_chunks::chunk_id.eq(chunks::id)),
+            )));
+
+        if dry_run {
+            return unreferenced
+                .select(Chunk::as_select())
+                .load(&mut connection);
+        }
+
+        diesel::delete(unreferenced)
+            .returning(Chunk::as_returning())
+            .get_results(&mut connection)
+    }
+
+    pub fn add_audit_record(&self, record: &NewAuditRecord) -> Result<(), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::insert_into(audit_log::table)
+            .values(record)
+            .execute(&mut connection)
+            .map(|_| ())
+    }
+
+    /// Loads up to `limit` audit records matching the filter after `after_id`,
+    /// in the order they were added. `to` is exclusive.
+    pub fn query_audit_log(
+        &self,
+        filter: &AuditFilter,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditRecord>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let mut query = audit_log::table
+            .filter(audit_log::id.gt(after_id))
+            .into_boxed();
+        if let Some(from) = filter.from {
+            query = query.filter(audit_log::created_at.ge(from));
+        }
+        if let Some(to) = filter.to {
+            query = query.filter(audit_log::created_at.lt(to));
+        }
+        if let Some(caller) = filter.caller {
+            query = query.filter(audit_log::caller.eq(caller));
+        }
+        if let Some(hash) = filter.file_hash {
+            query = query.filter(audit_log::file_hash.eq(hash));
+        }
+        if let Some(operation) = filter.operation {
+            query = query.filter(audit_log::operation.eq(operation));
+        }
+
+        query
+            .order(audit_log::id.asc())
+            .limit(limit)
+            .select(AuditRecord::as_select())
+            .load(&mut connection)
+    }
+
+    /// Flags a record as corrupted and records the event of it.
+    pub fn flag_corrupted(&self, rec_id: i32) -> Result<StoreItem, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let item = diesel::update(store.filter(id.eq(rec_id)))
+                .set(file_is_error.eq(true))
+                .returning(StoreItem::as_returning())
+                .get_result(conn)?;
+
+            add_events(conn, EventKind::FileCorrupted, std::slice::from_ref(&item))?;
+            Ok(item)
+        })
+    }
+
+    /// Loads up to `limit` storage events after the given position, in the
+    /// order they are passed on. Events of transactions that may still be
+    /// followed by earlier ones are held back.
+    pub fn get_events_after(
+        &self,
+        position: (i64, i64),
+        limit: i64,
+    ) -> Result<Vec<StoredEvent>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        storage_events::table
+            .filter(event_after(position))
+            .filter(storage_events::txid.lt(event_horizon()))
+            .order((storage_events::txid.asc(), storage_events::id.asc()))
+            .limit(limit)
+            .select(StoredEvent::as_select())
+            .load(&mut connection)
+    }
+
+    /// Whether there are events after the given position, including the
+    /// held back ones.
+    pub fn has_events_after(&self, position: (i64, i64)) -> Result<bool, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::select(exists(storage_events::table.filter(event_after(position))))
+            .get_result(&mut connection)
+    }
+
+    /// Position of the latest event that isn't held back.
+    pub fn get_latest_event_position(&self) -> Result<Option<(i64, i64)>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        storage_events::table
+            .filter(storage_events::txid.lt(event_horizon()))
+            .order((storage_events::txid.desc(), storage_events::id.desc()))
+            .select((storage_events::txid, storage_events::id))
+            .first(&mut connection)
+            .optional()
+    }
+
+    /// Position of the event with the given id.
+    pub fn get_event_position(&self, event: i64) -> Option<(i64, i64)> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        storage_events::table
+            .find(event)
+            .select((storage_events::txid, storage_events::id))
+            .first(&mut connection)
+            .ok()
+    }
+
+    /// Returns the number of stored files, their total size and the number
+    /// of them found missing or corrupted.
+    pub fn get_store_totals(&self) -> Result<(i64, i64, i64), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let (count, size) = store
+            .select((
+                diesel::dsl::count_star(),
+                diesel::dsl::sql::<diesel::sql_types::BigInt>(
+                    "COALESCE(SUM(file_size), 0)::BIGINT",
+                ),
+            ))
+            .first::<(i64, i64)>(&mut connection)?;
+        let damaged = store
+            .filter(file_is_error.eq(true))
+            .count()
+            .get_result(&mut connection)?;
+
+        Ok((count, size, damaged))
+    }
+
+    /// Removes the storage events added before the given time.
+    pub fn remove_events_before(
+        &self,
+        before: NaiveDateTime,
+    ) -> Result<usize, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::delete(storage_events::table.filter(storage_events::created_at.lt(before)))
+            .execute(&mut connection)
+    }
+
+    pub fn add_webhook(&self, webhook: &NewWebhook) -> Result<Webhook, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::insert_into(webhooks::table)
+            .values(webhook)
+            .returning(Webhook::as_returning())
+            .get_result(&mut connection)
+    }
+
+    pub fn get_webhooks(&self) -> Result<Vec<Webhook>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        webhooks::table
+            .order(webhooks::id.asc())
+            .select(Webhook::as_select())
+            .load(&mut connection)
+    }
+
+    /// Removes a webhook with its deliveries.
+    pub fn remove_webhook(&self, webhook: i32) -> Result<(), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        match diesel::delete(webhooks::table.find(webhook)).execute(&mut connection)? {
+            0 => Err(diesel::result::Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds deliveries of up to `limit` new outbox events to the webhooks
+    /// subscribed to them. Events no webhook is subscribed to are marked as
+    /// handled all the same, and kept until `prune_deliveries` removes them.
+    /// Returns the number of events handled.
+    pub fn fan_out_outbox(&self, limit: i64) -> Result<usize, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let events = outbox::table
+                .filter(outbox::dispatched.eq(false))
+                .order(outbox::id.asc())
+                .limit(limit)
+                .for_update()
+                .skip_locked()
+                .select(OutboxEvent::as_select())
+                .load(conn)?;
+            if events.is_empty() {
+                return Ok(0);
+            }
+            let hooks = webhooks::table.select(Webhook::as_select()).load(conn)?;
+
+            let mut deliveries = Vec::new();
+            for event in &events {
+                deliveries.extend(
+                    hooks
+                        .iter()
+                        .filter(|hook| hook.events.is_empty() || hook.events.contains(&event.kind))
+                        .map(|hook| NewWebhookDelivery {
+                            webhook_id: hook.id,
+                            outbox_id: event.id,
+                        }),
+                );
+            }
+
+            for batch in deliveries.chunks(1000) {
+                diesel::insert_into(webhook_deliveries::table)
+                    .values(batch)
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+            let ids: Vec<i64> = events.iter().map(|event| event.id).collect();
+            diesel::update(outbox::table.filter(outbox::id.eq_any(&ids)))
+                .set(outbox::dispatched.eq(true))
+                .execute(conn)?;
+
+            Ok(events.len())
+        })
+    }
+
+    /// Takes up to `limit` due deliveries, which aren't due again for
+    /// `lease_secs` so no other dispatcher takes them meanwhile.
+    pub fn claim_deliveries(
+        &self,
+        limit: i64,
+        lease_secs: i64,
+    ) -> Result<Vec<(WebhookDelivery, Webhook, OutboxEvent)>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let ids: Vec<i64> = webhook_deliveries::table
+                .filter(webhook_deliveries::state.eq(DELIVERY_PENDING))
+                .filter(webhook_deliveries::next_attempt_at.le(now))
+                .order(webhook_deliveries::next_attempt_at.asc())
+                .limit(limit)
+                .for_update()
+                .skip_locked()
+                .select(webhook_deliveries::id)
+                .load(conn)?;
+            if ids.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)))
+                .set(webhook_deliveries::next_attempt_at.eq(now + lease_secs.seconds()))
+                .execute(conn)?;
+
+            webhook_deliveries::table
+                .inner_join(webhooks::table)
+                .inner_join(outbox::table)
+                .filter(webhook_deliveries::id.eq_any(&ids))
+                .order(webhook_deliveries::outbox_id.asc())
+                .select((
+                    WebhookDelivery::as_select(),
+                    Webhook::as_select(),
+                    OutboxEvent::as_select(),
+                ))
+                .load(conn)
+        })
+    }
+
+    /// Records an attempt of a delivery. Pending deliveries are attempted
+    /// again in `retry_secs`.
+    pub fn finish_delivery(
+        &self,
+        delivery: i64,
+        delivery_state: &str,
+        retry_secs: i64,
+        status: Option<i32>,
+        error: Option<String>,
+    ) -> Result<(), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        diesel::update(webhook_deliveries::table.find(delivery))
+            .set((
+                webhook_deliveries::state.eq(delivery_state),
+                webhook_deliveries::attempts.eq(webhook_deliveries::attempts + 1),
+                webhook_deliveries::next_attempt_at.eq(now + retry_secs.seconds()),
+                webhook_deliveries::last_status.eq(status),
+                webhook_deliveries::last_error.eq(error),
+                webhook_deliveries::updated_at.eq(now),
+            ))
+            .execute(&mut connection)
+            .map(|_| ())
+    }
+
+    /// Loads up to `limit` deliveries before `before_id` with their events,
+    /// newest first.
+    pub fn list_deliveries(
+        &self,
+        webhook: Option<i32>,
+        delivery_state: Option<&str>,
+        before_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<(WebhookDelivery, OutboxEvent)>, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        let mut query = webhook_deliveries::table
+            .inner_join(outbox::table)
+            .into_boxed();
+        if let Some(webhook) = webhook {
+            query = query.filter(webhook_deliveries::webhook_id.eq(webhook));
+        }
+        if let Some(delivery_state) = delivery_state {
+            query = query.filter(webhook_deliveries::state.eq(delivery_state));
+        }
+        if let Some(before_id) = before_id {
+            query = query.filter(webhook_deliveries::id.lt(before_id));
+        }
+
+        query
+            .order(webhook_deliveries::id.desc())
+            .limit(limit)
+            .select((WebhookDelivery::as_select(), OutboxEvent::as_select()))
+            .load(&mut connection)
+    }
+
+    /// Makes a delivery pending again and due now, with its attempts reset.
+    /// Returns it with its event.
+    pub fn retry_delivery(
+        &self,
+        delivery: i64,
+    ) -> Result<(WebhookDelivery, OutboxEvent), diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let delivery = diesel::update(webhook_deliveries::table.find(delivery))
+                .set((
+                    webhook_deliveries::state.eq(DELIVERY_PENDING),
+                    webhook_deliveries::attempts.eq(0),
+                    webhook_deliveries::next_attempt_at.eq(now),
+                    webhook_deliveries::updated_at.eq(now),
+                ))
+                .returning(WebhookDelivery::as_returning())
+                .get_result(conn)?;
+
+            let event = outbox::table
+                .find(delivery.outbox_id)
+                .select(OutboxEvent::as_select())
+                .first(conn)?;
+            Ok((delivery, event))
+        })
+    }
+
+    /// Removes the deliveries made before the given time, and the events
+    /// made before it left without deliveries. Dead deliveries are kept for
+    /// inspection.
+    pub fn prune_deliveries(&self, before: NaiveDateTime) -> Result<usize, diesel::result::Error> {
+        let mut connection = self.db_pool.get().unwrap();
+
+        connection.transaction(|conn| {
+            let removed = diesel::delete(
+                webhook_deliveries::table
+                    .filter(webhook_deliveries::state.eq(DELIVERY_DELIVERED))
+                    .filter(webhook_deliveries::updated_at.lt(before)),
+            )
+            .execute(conn)?;
+
+            diesel::delete(
+                outbox::table
+                    .filter(outbox::dispatched.eq(true))
+                    .filter(outbox::created_at.lt(before))
+                    .filter(not(exists(
+                        webhook_deliveries::table
+                            .filter(webhook_deliveries::outbox_id.eq(outbox::id)),
+                    ))),
+            )
+            .execute(conn)?;
+
+            Ok(removed)
+        })
+    }
 }
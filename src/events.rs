@@ -0,0 +1,224 @@
+use chrono::{TimeDelta, Utc};
+use dotenvy::dotenv;
+use std::{env, time::Duration};
+use tokio::{sync::broadcast, time::MissedTickBehavior};
+use tracing::{error, info, warn};
+
+use crate::{db::DbState, listener::PgListener, models::StoredEvent};
+
+/// Capacity of the in-process event channel, slow subscribers lose the oldest
+/// events beyond it.
+const EVENT_BUFFER: usize = 1024;
+
+/// Postgres channel the ids of new events are announced on.
+const EVENTS_CHANNEL: &str = "storage_events";
+
+/// Longest wait before connecting the listener again.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How often held back events are looked for again.
+const HELD_BACK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often events past their retention are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Events loaded at once when catching up.
+pub const EVENT_BATCH_SIZE: i64 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    FileUploaded,
+    FileDeleted,
+    FileCorrupted,
+    FileExpired,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::FileUploaded => "FileUploaded",
+            Self::FileDeleted => "FileDeleted",
+            Self::FileCorrupted => "FileCorrupted",
+            Self::FileExpired => "FileExpired",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "FileUploaded" => Some(Self::FileUploaded),
+            "FileDeleted" => Some(Self::FileDeleted),
+            "FileCorrupted" => Some(Self::FileCorrupted),
+            "FileExpired" => Some(Self::FileExpired),
+            _ => None,
+        }
+    }
+}
+
+/// Where the listener connects to and how long events are kept.
+struct ListenerConfig {
+    database_url: String,
+    retention: Option<TimeDelta>,
+}
+
+impl ListenerConfig {
+    /// Reads `DATABASE_URL` and `EVENT_RETENTION_SECS` (0 keeps events
+    /// forever).
+    fn from_env() -> Self {
+        dotenv().ok();
+
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let retention: i64 = env::var("EVENT_RETENTION_SECS")
+            .unwrap_or("604800".to_owned())
+            .parse()
+            .ok()
+            .filter(|secs| *secs >= 0)
+            .unwrap_or_else(|| {
+                error!(
+                    "'EVENT_RETENTION_SECS' - should be an integer value in range: [0;{}]",
+                    i64::MAX
+                );
+                panic!()
+            });
+
+        Self {
+            database_url,
+            retention: (retention > 0).then(|| TimeDelta::seconds(retention)),
+        }
+    }
+}
+
+/// Storage events of every server sharing the database. Events are recorded
+/// in the `storage_events` table along with the change they are about, which
+/// announces them with NOTIFY, and each server passes them on to its
+/// subscribers in the order of their position: the transaction that added
+/// them, then their id.
+#[derive(Clone)]
+pub struct EventBus {
+    db: DbState,
+    sender: broadcast::Sender<StoredEvent>,
+}
+
+impl EventBus {
+    pub fn new(db: DbState) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { db, sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StoredEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Starts passing the events announced by Postgres on to the subscribers,
+    /// and removing the events past their retention.
+    pub fn spawn_listener(&self) {
+        let config = ListenerConfig::from_env();
+        let bus = self.clone();
+
+        tokio::spawn(async move { bus.listen(config).await });
+    }
+
+    async fn listen(self, config: ListenerConfig) {
+        let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+        prune.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut poll = tokio::time::interval(HELD_BACK_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut delay = Duration::from_secs(1);
+        // Position of the latest event passed on
+        let mut position = None;
+
+        loop {
+            let mut listener = match PgListener::connect(&config.database_url, EVENTS_CHANNEL).await
+            {
+                Ok(listener) => {
+                    info!("Listening for storage events");
+                    delay = Duration::from_secs(1);
+                    listener
+                }
+                Err(e) => {
+                    error!("Couldn't listen for storage events! Error: {}", e);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+
+            // Events added while disconnected are passed on now
+            let mut held_back = true;
+
+            loop {
+                if held_back {
+                    held_back = self.catch_up(&mut position);
+                }
+
+                tokio::select! {
+                    received = listener.recv() => match received {
+                        Ok(_) => held_back = true,
+                        Err(e) => {
+                            warn!("Lost the storage events connection: {}", e);
+                            break;
+                        }
+                    },
+                    // Announced events of a transaction that is older than a
+                    // running one wait for it to end
+                    _ = poll.tick(), if held_back => {}
+                    _ = prune.tick(), if config.retention.is_some() => {
+                        self.prune(config.retention.unwrap_or_default());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes on the events after `position`, which starts at the latest one
+    /// if unset. Returns whether any are held back.
+    fn catch_up(&self, position: &mut Option<(i64, i64)>) -> bool {
+        let mut after = match *position {
+            Some(after) => after,
+            None => match self.db.get_latest_event_position() {
+                Ok(latest) => *position.insert(latest.unwrap_or_default()),
+                Err(e) => {
+                    error!("Error during loading storage events from DB! Error: {}", &e);
+                    return true;
+                }
+            },
+        };
+
+        loop {
+            let events = match self.db.get_events_after(after, EVENT_BATCH_SIZE) {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Error during loading storage events from DB! Error: {}", &e);
+                    return true;
+                }
+            };
+            let done = (events.len() as i64) < EVENT_BATCH_SIZE;
+
+            for event in events {
+                after = (event.txid, event.id);
+                *position = Some(after);
+                // Nobody listening is fine
+                let _ = self.sender.send(event);
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        self.db.has_events_after(after).unwrap_or_else(|e| {
+            error!("Error during loading storage events from DB! Error: {}", &e);
+            true
+        })
+    }
+
+    fn prune(&self, retention: TimeDelta) {
+        match self
+            .db
+            .remove_events_before((Utc::now() - retention).naive_utc())
+        {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} old storage events", removed),
+            Err(e) => error!("Error during removing old storage events! Error: {}", &e),
+        }
+    }
+}
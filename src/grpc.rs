@@ -1,38 +1,243 @@
+// Handlers and their helpers fail with the `Status` sent back to the client
+#![allow(clippy::result_large_err)]
+
 use chrono::Utc;
 use dotenvy::dotenv;
-use log::{error, info, warn};
 use sha2::{Digest, Sha256};
-use std::{env, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    env, io,
+    io::SeekFrom,
+    path::PathBuf,
+    sync::RwLock,
+};
 use tokio::{
-    fs::{remove_file, File},
-    io::{AsyncReadExt, AsyncWriteExt},
+    fs::{remove_file, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
     sync::mpsc,
 };
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status, Streaming};
+use tracing::{error, info, instrument, warn, Instrument};
 
 use crate::{
+    access_log::AccessNote,
+    admin,
+    audit::{AuditLog, Caller},
+    bao,
+    chunker::ChunkerConfig,
     db::DbState,
-    models::NewStoreItem,
+    events::EventBus,
+    metrics::{Metrics, StorageGauges},
+    models::{NewStoreItem, StoreItem},
+    ratelimit::{ClientLimit, RateLimits},
     storage::{
-        storage_server::Storage, upload_file_request::Data, DeleteFileRequest, DeleteFileResponse,
-        FetchFileRequest, FetchFileResponse, UploadFileRequest, UploadFileResponse,
+        fetch_file_response::Data as FetchData, storage_server::Storage, upload_file_request::Data,
+        AbortMultipartUploadRequest, AbortMultipartUploadResponse, BatchDeleteRequest,
+        BatchDeleteResponse, BatchStatRequest, BatchStatResponse, CompleteMultipartUploadRequest,
+        CreateMultipartUploadRequest, CreateMultipartUploadResponse, DeleteFileRequest,
+        DeleteFileResponse, DeleteRefRequest, DeleteRefResponse, ExtendTtlRequest,
+        FetchArchiveRequest, FetchArchiveResponse, FetchFileRequest, FetchFileResponse,
+        FetchManyRequest, FetchTrailer, FileEvent, FileInfo, FileStat, GetRefRequest,
+        HasFilesRequest, HasFilesResponse, ItemResult, ListFilesRequest, ListFilesResponse,
+        ListRefsRequest, ListRefsResponse, ListVersionsRequest, ListVersionsResponse, ProofNode,
+        Ref, ServerStatus, ServiceMode, SetRefRequest, SetRetentionRequest, UpdateMetadataRequest,
+        UploadArchiveRequest, UploadArchiveResponse, UploadFileRequest, UploadFileResponse,
+        UploadHeader, UploadPartRequest, UploadPartResponse, VerifiedChunk, VolumeStatus,
+        WatchEventsRequest,
     },
+    volumes::{Volume, VolumeSet, VolumeState},
+    webhooks::Webhooks,
 };
 
+mod archive;
+mod chunked;
+mod expiry;
+mod metadata;
+mod multipart;
+mod refs;
+mod retention;
+mod tree;
+mod unpack;
+mod versions;
+mod watch;
+
+use chunked::ChunkedUpload;
+use expiry::{expires_at, resolve_expiration};
+use metadata::{normalize_tags, validate_tags};
+use refs::referenced;
+use retention::{locked, retain_until, RetentionMode};
+use tree::PendingTree;
+use unpack::ArchiveLimits;
+use versions::{validate_version_header, versioned_key};
+
+/// Bytes written to an upload between two free-space checks.
+const SPACE_CHECK_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// Hashes a single batch call (`HasFiles`, `BatchStat`, ...) may ask about.
+const MAX_BATCH_HASHES: usize = 10_000;
+
+#[derive(Clone, Debug)]
+struct ModeState {
+    mode: ServiceMode,
+    reason: String,
+    automatic: bool,
+}
+
 pub struct FileStorage {
     db: DbState,
-    storage_folder: PathBuf,
+    volumes: VolumeSet,
+    mode: RwLock<ModeState>,
+    events: EventBus,
+    audit: AuditLog,
+    webhooks: Webhooks,
+    metrics: Metrics,
+    rate_limits: RateLimits,
     chunk_size: u64, //in bytes
+    verify_on_read: bool,
+    chunker: Option<ChunkerConfig>,
+    archive_limits: ArchiveLimits,
+    // Versions kept per key without a retention of its own, 0 keeps all
+    keep_versions: i32,
+    retention_mode: RetentionMode,
+}
+
+struct PendingUpload<'a> {
+    volume: &'a Volume,
+    path: PathBuf,
+    rel_path: String,
+    file: File,
+    hasher: Sha256,
+    written: u64,
+    unchecked_bytes: u64,
+    tree: Option<PendingTree<'a>>,
+}
+
+impl PendingUpload<'_> {
+    /// Removes the partially written file of a failed upload.
+    async fn discard(self) {
+        if let Some(tree) = self.tree {
+            tree.discard().await;
+        }
+
+        drop(self.file);
+        match remove_file(&self.path).await {
+            Ok(_) => info!("Removed incomplete upload: {}", self.path.display()),
+            Err(e) => warn!(
+                "Couldn't remove incomplete upload \"{}\": {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// An upload in progress, written either to a single file or, with chunked
+/// storage enabled, split into deduplicated chunks.
+enum Upload<'a> {
+    File(PendingUpload<'a>),
+    Chunked(ChunkedUpload<'a>),
+}
+
+impl Upload<'_> {
+    fn written(&self) -> u64 {
+        match self {
+            Upload::File(upload) => upload.written,
+            Upload::Chunked(upload) => upload.written,
+        }
+    }
+
+    async fn discard(self) {
+        match self {
+            Upload::File(upload) => upload.discard().await,
+            // Chunks already stored are left to garbage collection, another
+            // upload might be referencing them
+            Upload::Chunked(upload) => upload.tree.discard().await,
+        }
+    }
+}
+
+/// Reads the data of a stored item, which is either a single file or a list of
+/// chunk files with their sizes.
+struct BlobReader {
+    current: Option<File>,
+    pending: VecDeque<(PathBuf, u64)>,
+}
+
+impl BlobReader {
+    /// Skips the first `bytes` of the data, before anything was read.
+    async fn skip(&mut self, mut bytes: u64) -> io::Result<()> {
+        if self.current.is_none() {
+            while let Some(&(_, len)) = self.pending.front() {
+                if len > bytes {
+                    break;
+                }
+                bytes -= len;
+                self.pending.pop_front();
+            }
+
+            match self.pending.pop_front() {
+                Some((path, _)) => self.current = Some(File::open(path).await?),
+                None => return Ok(()),
+            }
+        }
+
+        if let Some(file) = self.current.as_mut() {
+            file.seek(SeekFrom::Start(bytes)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next `capacity` bytes (less only at the end of the data),
+    /// or `None` at the end of the data.
+    async fn next_chunk(&mut self, capacity: u64) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = Vec::with_capacity(capacity as usize);
+
+        while (chunk.len() as u64) < capacity {
+            let file = match self.current.as_mut() {
+                Some(file) => file,
+                None => match self.pending.pop_front() {
+                    Some((path, _)) => self.current.insert(File::open(path).await?),
+                    None => break,
+                },
+            };
+
+            let limit = capacity - chunk.len() as u64;
+            if file.take(limit).read_to_end(&mut chunk).await? == 0 {
+                self.current = None;
+            }
+        }
+
+        Ok(Some(chunk).filter(|chunk| !chunk.is_empty()))
+    }
+}
+
+type FetchSender = mpsc::Sender<Result<FetchFileResponse, Status>>;
+
+/// A stored item located and checked for streaming.
+struct BlobStream {
+    item: StoreItem,
+    reader: BlobReader,
+    size: u64,
+    start: u64,
+    end: u64,
+    verify: bool,
+    proofs: bool,
+    tree: Option<(File, blake3::Hash)>,
+    // Bandwidth of the client fetching it
+    limit: ClientLimit,
+}
+
+impl Default for FileStorage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FileStorage {
     pub fn new() -> Self {
         dotenv().ok();
-        let env_dir =
-            env::var("STORAGE_FOLDER").unwrap_or(env::current_dir().unwrap().display().to_string());
-        info!("Storage folder: {}", &env_dir);
-        let dir = PathBuf::from(env_dir);
 
         let limit: u64 = env::var("CHUNK_SIZE_BYTES")
             .unwrap_or("1048576".to_owned())
@@ -45,7 +250,7 @@ impl FileStorage {
                 panic!()
             });
 
-        if limit < 1 || limit > u64::MAX {
+        if limit < 1 {
             error!(
                 "'CHUNK_SIZE_BYTES' - should be an integer value in range: [1;{}]",
                 u64::MAX
@@ -53,93 +258,1118 @@ impl FileStorage {
             panic!();
         }
 
-        if dir.exists() && dir.is_dir() {
-            return Self {
-                db: DbState::new(),
-                storage_folder: dir,
-                chunk_size: limit,
+        let verify_on_read: bool = env::var("VERIFY_ON_READ")
+            .unwrap_or("false".to_owned())
+            .parse()
+            .unwrap_or_else(|_| {
+                error!("'VERIFY_ON_READ' - should be 'true' or 'false'");
+                panic!()
+            });
+
+        let keep_versions: i32 = env::var("KEEP_VERSIONS")
+            .unwrap_or("0".to_owned())
+            .parse()
+            .ok()
+            .filter(|keep| *keep >= 0)
+            .unwrap_or_else(|| {
+                error!(
+                    "'KEEP_VERSIONS' - should be an integer value in range: [0;{}]",
+                    i32::MAX
+                );
+                panic!()
+            });
+
+        let chunker = ChunkerConfig::from_env();
+        if let Some(config) = chunker {
+            info!(
+                "Chunked storage enabled, chunk sizes: {}/{}/{} bytes",
+                config.min_size, config.avg_size, config.max_size
+            );
+        }
+
+        let db = DbState::new();
+        let volumes = VolumeSet::from_env(&db);
+        let audit = AuditLog::new(db.clone());
+        let events = EventBus::new(db.clone());
+        let webhooks = Webhooks::new(db.clone());
+
+        Self {
+            db,
+            volumes,
+            mode: RwLock::new(ModeState {
+                mode: ServiceMode::ReadWrite,
+                reason: String::new(),
+                automatic: false,
+            }),
+            events,
+            audit,
+            webhooks,
+            metrics: Metrics::new(),
+            rate_limits: RateLimits::from_env(),
+            chunk_size: limit,
+            verify_on_read,
+            chunker,
+            archive_limits: ArchiveLimits::from_env(),
+            keep_versions,
+            retention_mode: RetentionMode::from_env(),
+        }
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    pub fn webhooks(&self) -> &Webhooks {
+        &self.webhooks
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Samples the DB pool, the store and the volumes for the metrics.
+    pub fn gauges(&self) -> StorageGauges {
+        let pool = self.db.db_pool.state();
+        let totals = self
+            .db
+            .get_store_totals()
+            .map_err(|e| error!("Error during counting stored files! Error: {}", &e))
+            .ok();
+
+        StorageGauges {
+            pool_connections: pool.connections,
+            pool_idle_connections: pool.idle_connections,
+            pool_max_size: self.db.db_pool.max_size(),
+            objects: totals.map(|(count, _, _)| count),
+            stored_bytes: totals.map(|(_, size, _)| size),
+            damaged_files: totals.map(|(_, _, damaged)| damaged),
+            volumes: self.status().volumes,
+        }
+    }
+
+    pub fn status(&self) -> ServerStatus {
+        let mode = self.mode.read().unwrap().clone();
+
+        ServerStatus {
+            mode: mode.mode as i32,
+            reason: mode.reason,
+            low_watermark_percent: self.volumes.low_watermark,
+            high_watermark_percent: self.volumes.high_watermark,
+            volumes: self
+                .volumes
+                .volumes()
+                .iter()
+                .map(|vol| VolumeStatus {
+                    name: vol.name.clone(),
+                    path: vol.path.display().to_string(),
+                    state: admin::volume_state_to_proto(vol.state()) as i32,
+                    free_bytes: vol.free_space().unwrap_or(0),
+                    total_bytes: vol.total_space().unwrap_or(0),
+                    usage_percent: vol.usage_percent().unwrap_or(100.0),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn set_mode(&self, mode: ServiceMode, reason: String) {
+        info!("Service mode: {} ({})", mode.as_str_name(), reason);
+        *self.mode.write().unwrap() = ModeState {
+            mode,
+            reason,
+            automatic: false,
+        };
+    }
+
+    pub fn set_volume_state(&self, volume_name: &str, state: VolumeState) -> Result<(), Status> {
+        let vol = self
+            .volumes
+            .get_by_name(volume_name)
+            .ok_or_else(|| Status::not_found(format!("Unknown volume '{}'", volume_name)))?;
+
+        self.volumes.set_state(&self.db, vol, state).map_err(|e| {
+            error!("Could not update volume state in DB! Error: {}", e);
+            Status::internal("Internal service error!")
+        })?;
+
+        info!("Volume '{}' is now {}", vol.name, state);
+        Ok(())
+    }
+
+    /// Rejects every storage call while the service is in maintenance.
+    fn check_available(&self) -> Result<(), Status> {
+        let mode = self.mode.read().unwrap();
+        if mode.mode == ServiceMode::Maintenance {
+            return Err(Status::unavailable(format!(
+                "Service is in maintenance: {}",
+                mode.reason
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Rejects uploads unless the service accepts writes. A read-only mode
+    /// entered because of full disks is left once usage falls under the low
+    /// watermark.
+    fn check_writable(&self) -> Result<(), Status> {
+        self.check_available()?;
+
+        let mut mode = self.mode.write().unwrap();
+        if mode.mode != ServiceMode::ReadOnly {
+            return Ok(());
+        }
+
+        if !mode.automatic {
+            return Err(Status::failed_precondition(format!(
+                "Service is read-only: {}",
+                mode.reason
+            )));
+        }
+
+        if self.volumes.below_low_watermark() {
+            info!("Disk usage is below the low watermark, accepting uploads again");
+            *mode = ModeState {
+                mode: ServiceMode::ReadWrite,
+                reason: String::new(),
+                automatic: false,
             };
-        } else {
-            error!("'STORAGE_FOLDER' path - doesn't exists or it's not a directory!");
-            panic!()
+            return Ok(());
         }
+
+        Err(Status::resource_exhausted(format!(
+            "Service is read-only: {}",
+            mode.reason
+        )))
     }
-}
 
-#[tonic::async_trait]
-impl Storage for FileStorage {
-    type FetchFileStream = ReceiverStream<Result<FetchFileResponse, Status>>;
+    /// Counts a call against the request rate of its caller, with the limits
+    /// of `namespace` if it has its own.
+    fn check_rate(&self, caller: &Caller, namespace: Option<&str>) -> Result<(), Status> {
+        self.rate_limits.client(caller, namespace).check_request()
+    }
 
-    async fn upload_file(
+    /// Switches to read-only once no volume can take new files anymore.
+    fn on_disk_full(&self) -> Status {
+        if !self.volumes.any_writable() {
+            let mut mode = self.mode.write().unwrap();
+            if mode.mode == ServiceMode::ReadWrite {
+                warn!(
+                    "All volumes are above the high watermark ({}%), switching to read-only",
+                    self.volumes.high_watermark
+                );
+                *mode = ModeState {
+                    mode: ServiceMode::ReadOnly,
+                    reason: String::from("storage is full"),
+                    automatic: true,
+                };
+            }
+        }
+
+        Status::resource_exhausted("Not enough free space on storage volume!")
+    }
+
+    /// Resolves the on-disk location of a stored item, refusing items that live
+    /// on an offline or unreachable volume.
+    fn blob_path(&self, item: &StoreItem) -> Result<(Option<&Volume>, PathBuf), Status> {
+        let (volume, path) = self.volumes.locate(&self.db, item);
+
+        if let Some(vol) = volume {
+            if !self.volumes.check_reachable(&self.db, vol) {
+                warn!("Volume '{}' is offline", vol.name);
+                return Err(Status::unavailable(format!(
+                    "Storage volume '{}' is offline!",
+                    vol.name
+                )));
+            }
+        }
+
+        Ok((volume, path))
+    }
+
+    async fn start_upload(&self, header: &UploadHeader) -> Result<Upload<'_>, Status> {
+        if let Some(config) = self.chunker {
+            return Ok(Upload::Chunked(self.start_chunked(config, header).await?));
+        }
+
+        let vol = match self
+            .volumes
+            .place(&self.db, &header.file_name, header.size.unwrap_or(0))
+        {
+            Some(vol) => vol,
+            None => {
+                error!("No storage volume available for writing!");
+                return Err(self.on_disk_full());
+            }
+        };
+
+        let rel_path = unique_rel_path(vol, |stamp| format!("{}_{}", stamp, header.file_name));
+        let mut upload = self.open_pending(vol, rel_path).await?;
+        self.attach_tree(&mut upload).await?;
+
+        Ok(Upload::File(upload))
+    }
+
+    /// Builds the hash tree of an upload next to its file.
+    async fn attach_tree(&self, upload: &mut PendingUpload<'_>) -> Result<(), Status> {
+        match self
+            .open_tree(upload.volume, format!("{}.obao", upload.rel_path))
+            .await
+        {
+            Ok(tree) => {
+                upload.tree = Some(tree);
+                Ok(())
+            }
+            Err(status) => {
+                if let Err(e) = remove_file(&upload.path).await {
+                    warn!("Couldn't remove \"{}\": {}", upload.path.display(), e);
+                }
+                Err(status)
+            }
+        }
+    }
+
+    /// Creates a new file at `rel_path` on the given volume to receive data.
+    /// Fails rather than truncating a file already there.
+    #[instrument(name = "fs.create", skip_all, fields(volume = %vol.name, path = %rel_path))]
+    async fn open_pending<'a>(
         &self,
-        request: Request<Streaming<UploadFileRequest>>,
-    ) -> Result<Response<UploadFileResponse>, Status> {
-        let mut stream = request.into_inner();
+        vol: &'a Volume,
+        rel_path: String,
+    ) -> Result<PendingUpload<'a>, Status> {
+        let path = vol.path.join(&rel_path);
+
+        info!("Writing in file: {} ({})", path.display(), vol.name);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+            .map_err(|e| {
+                error!("Failed to create file: {}", &e);
+                self.volumes.report_io_error(&self.db, vol, &e, true);
+                Status::internal(format!("Failed to create file: {}", e))
+            })?;
+
+        Ok(PendingUpload {
+            volume: vol,
+            path,
+            rel_path,
+            file,
+            hasher: Sha256::new(),
+            written: 0,
+            unchecked_bytes: 0,
+            tree: None,
+        })
+    }
+
+    #[instrument(name = "fs.write", level = "debug", skip_all, fields(bytes = data.len()))]
+    async fn write_chunk(&self, upload: &mut PendingUpload<'_>, data: &[u8]) -> Result<(), Status> {
+        upload.hasher.update(data);
+        if let Err(e) = upload.file.write_all(data).await {
+            error!("Failed to write data in file: {}", &e);
+            if e.kind() == io::ErrorKind::StorageFull {
+                return Err(self.on_disk_full());
+            }
 
-        let mut file_name: Option<String> = None;
-        let mut file_handler: Option<File> = None;
-        let mut file_path = self.storage_folder.clone();
+            self.volumes
+                .report_io_error(&self.db, upload.volume, &e, true);
+            return Err(Status::internal(format!(
+                "Failed to write data in file: {}",
+                e
+            )));
+        }
 
-        let file_hash: Option<String>;
-        let mut hasher = Sha256::new();
+        if let Some(tree) = upload.tree.as_mut() {
+            self.write_tree(tree, data).await?;
+        }
+
+        upload.written += data.len() as u64;
+        upload.unchecked_bytes += data.len() as u64;
+        if upload.unchecked_bytes >= SPACE_CHECK_INTERVAL {
+            upload.unchecked_bytes = 0;
+
+            match self.volumes.has_room(upload.volume) {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(
+                        "Volume '{}' ran out of space during upload",
+                        upload.volume.name
+                    );
+                    return Err(self.on_disk_full());
+                }
+                Err(e) => {
+                    self.volumes
+                        .report_io_error(&self.db, upload.volume, &e, false);
+                    return Err(Status::internal("Failed to check free space"));
+                }
+            }
+        }
 
-        while let Some(chunk) = stream.message().await? {
-            if let Some(data) = chunk.data {
-                match data {
-                    Data::FileName(name) => {
-                        file_name = Some(name.clone());
+        Ok(())
+    }
+
+    /// Checks the received data against the declared size and hash and flushes
+    /// it to disk. Returns the SHA-256 of the data.
+    #[instrument(name = "fs.sync", skip_all, fields(path = %upload.rel_path))]
+    async fn finish_pending(
+        &self,
+        upload: &mut PendingUpload<'_>,
+        size: Option<u64>,
+        expected_hash: &str,
+    ) -> Result<String, Status> {
+        let file_hash = check_received(upload.written, &upload.hasher, size, expected_hash)?;
+
+        if let Some(tree) = upload.tree.as_mut() {
+            self.finish_tree(tree).await?;
+        }
+
+        upload.file.sync_all().await.map_err(|e| {
+            error!("Failed to sync file: {}", &e);
+            self.volumes
+                .report_io_error(&self.db, upload.volume, &e, true);
+            Status::internal(format!("Failed to sync file: {}", e))
+        })?;
+
+        Ok(file_hash)
+    }
+
+    /// Checks a fetch request against a located item and opens its hash tree
+    /// if the request needs it. Verified reads use the tree when there is one,
+    /// so each slice is checked before it is sent.
+    async fn prepare_blob(
+        &self,
+        res: StoreItem,
+        reader: BlobReader,
+        size: u64,
+        req: &FetchFileRequest,
+        limit: &ClientLimit,
+    ) -> Result<BlobStream, Status> {
+        let start = req.offset.unwrap_or(0);
+        if start > size {
+            return Err(Status::out_of_range(format!(
+                "Offset {} is beyond the file size of {} bytes!",
+                start, size
+            )));
+        }
+        let end = match req.length {
+            Some(len) => start.saturating_add(len).min(size),
+            None => size,
+        };
+        let ranged = start != 0 || end != size;
+        let verify = req.verify || self.verify_on_read;
+        let proofs = req.proofs;
+
+        let tree = if proofs || verify {
+            self.open_outboard(&res).await?
+        } else {
+            None
+        };
+        if tree.is_none() {
+            if proofs {
+                return Err(Status::failed_precondition("File has no hash tree!"));
+            }
+            if verify && ranged {
+                warn!(
+                    "File \"{}\" has no hash tree, ranged read isn't verified",
+                    res.file_name
+                );
+            }
+        }
+
+        Ok(BlobStream {
+            item: res,
+            reader,
+            size,
+            start,
+            end,
+            verify,
+            proofs,
+            tree,
+            limit: limit.clone(),
+        })
+    }
+
+    /// Streams a stored item: its `FileInfo`, the data chunks (or verified
+    /// slices) of the requested range and a trailer with the SHA-256 of the
+    /// sent data.
+    async fn stream_blob(
+        &self,
+        res: StoreItem,
+        reader: BlobReader,
+        size: u64,
+        req: &FetchFileRequest,
+        caller: Caller,
+        limit: &ClientLimit,
+    ) -> Result<Response<ReceiverStream<Result<FetchFileResponse, Status>>>, Status> {
+        let blob = self.prepare_blob(res, reader, size, req, limit).await?;
+        let db = self.db.clone();
+        let metrics = self.metrics.clone();
+        let audit = self.audit.clone();
+        let capacity = self.chunk_size;
+
+        let (tx, rx) = mpsc::channel(self.chunk_size as usize);
+
+        tokio::spawn(
+            async move {
+                let file_hash = blob.item.file_hash.clone();
+                let mut sent = 0;
+                let result = send_blob(blob, &tx, &db, &metrics, capacity, &mut sent).await;
+                audit.record("FetchFile", &caller, Some(&file_hash), sent, &result);
+
+                if let Err(status) = result {
+                    if !tx.is_closed() {
+                        if let Err(err) = tx.send(Err(status)).await {
+                            error!("{}", err);
+                        }
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 
-                        file_path.push(format!(
-                            "{}_{}",
-                            Utc::now().timestamp_millis(),
-                            file_name.clone().unwrap()
+    /// Resolves the blob of a regular item, flagging the record if the file
+    /// is missing.
+    fn locate_blob(&self, res: &StoreItem) -> Result<(Option<&Volume>, PathBuf), Status> {
+        let (volume, path) = self.blob_path(res)?;
+        if !path.exists() || !path.is_file() {
+            match self.db.update_last_read_state(res.id, true) {
+                Ok(res) => {
+                    warn!(
+                        "File \"{}\" with id:{} has problems with itself or path!",
+                        &res.file_path, res.id
+                    );
+                    return Err(Status::new(tonic::Code::NotFound, "File not found!"));
+                }
+                Err(_) => unreachable!(),
+            }
+        }
+
+        Ok((volume, path))
+    }
+
+    /// Loads the records of the given hashes, the oldest one for each hash.
+    fn find_files(&self, hashes: &[String]) -> Result<HashMap<String, StoreItem>, Status> {
+        let items = self.db.get_files_by_hashes(hashes).map_err(|e| {
+            error!("Error during loading records from DB! Error: {}", &e);
+            Status::internal("Internal service error!")
+        })?;
+
+        let mut found = HashMap::with_capacity(items.len());
+        for item in items {
+            found.entry(item.file_hash.clone()).or_insert(item);
+        }
+
+        Ok(found)
+    }
+
+    /// Locates the data of an item without opening its files yet.
+    fn lazy_reader(&self, res: &StoreItem) -> Result<(BlobReader, u64), Status> {
+        if res.chunked {
+            return Ok((self.open_chunked(res)?, res.file_size.unwrap_or(0) as u64));
+        }
+
+        let (_, path) = self.locate_blob(res)?;
+        let size = match path.metadata() {
+            Ok(meta) => meta.len(),
+            Err(_) => res.file_size.unwrap_or(0) as u64,
+        };
+        let reader = BlobReader {
+            current: None,
+            pending: VecDeque::from([(path, size)]),
+        };
+
+        Ok((reader, size))
+    }
+
+    /// Removes the blob of a deleted item. Chunks of chunked items are left to
+    /// garbage collection.
+    #[instrument(name = "fs.remove", skip_all, fields(file_hash = %item.file_hash))]
+    async fn remove_blob(&self, item: &StoreItem) -> Result<(), Status> {
+        if item.chunked {
+            info!("Delete: {} (chunked)", item.file_hash);
+            self.remove_outboard(item).await;
+            return Ok(());
+        }
+
+        let (volume, path) = self.blob_path(item)?;
+        if !path.exists() {
+            if !item.file_is_error {
+                match self.db.update_last_read_state(item.id, true) {
+                    Ok(res) => {
+                        warn!("There is a problem with file \"{}\"", &res.file_path);
+                        return Err(Status::new(tonic::Code::Internal, "Could not find file!"));
+                    }
+                    Err(e) => {
+                        error!("Could not update error state in DB! Error: {}", e);
+                        return Err(Status::new(
+                            tonic::Code::Internal,
+                            "Internal service error!",
+                        ));
+                    }
+                }
+            }
+            return Err(Status::new(tonic::Code::NotFound, "File not found!"));
+        }
+
+        match remove_file(&path).await {
+            Ok(_) => {
+                info!("Delete: {}", path.display());
+                self.remove_outboard(item).await;
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(vol) = volume {
+                    self.volumes.report_io_error(&self.db, vol, &e, true);
+                }
+
+                match self.db.update_last_read_state(item.id, true) {
+                    Ok(res) => {
+                        warn!("There is a problem with file \"{}\"", &res.file_path);
+                        Err(Status::new(
+                            tonic::Code::Internal,
+                            "Internal service error!",
+                        ))
+                    }
+                    Err(e) => {
+                        error!("Could not update error state in DB! Error: {}", e);
+                        Err(Status::new(
+                            tonic::Code::Internal,
+                            "Internal service error!",
+                        ))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Receives an upload stream and records the file. The flag is set when an
+    /// already stored file was returned instead (see `skipIfExists`).
+    async fn receive_upload<'a>(
+        &'a self,
+        caller: &Caller,
+        stream: &mut Streaming<UploadFileRequest>,
+        pending: &mut Option<Upload<'a>>,
+    ) -> Result<(StoreItem, bool), Status> {
+        let mut header: Option<UploadHeader> = None;
+        let mut chunks_received = false;
+        let mut limit = ClientLimit::default();
+
+        while let Some(message) = stream.message().await? {
+            match message.data {
+                Some(Data::Header(mut new_header)) => {
+                    if header.is_some() {
+                        warn!("Upload header was sent twice!");
+                        return Err(Status::invalid_argument("Upload header was already sent!"));
+                    }
+                    if chunks_received {
+                        warn!("Upload header was sent after chunks!");
+                        return Err(Status::invalid_argument(
+                            "Upload header should be sent before chunks!",
                         ));
+                    }
 
-                        info!("Writing in file: {}", file_path.display());
+                    validate_header(&new_header)?;
+                    let namespace = new_header.key.as_ref().map(|key| key.namespace.as_str());
+                    limit = self.rate_limits.client(caller, namespace);
+                    limit.check_request()?;
 
-                        let file = File::create(&file_path).await.map_err(|e| {
-                            error!("Failed to create file: {}", &e);
-                            Status::internal(format!("Failed to create file: {}", e))
-                        })?;
+                    resolve_expiration(&mut new_header)?;
+                    self.resolve_retention(&mut new_header)?;
 
-                        file_handler = Some(file);
+                    if new_header.skip_if_exists {
+                        let expected_hash = new_header.expected_hash.to_ascii_lowercase();
+                        // A keyed upload is only skipped if it matches the latest version
+                        let existing = match &new_header.key {
+                            Some(key) => self
+                                .db
+                                .get_version(&key.namespace, &key.key, None)
+                                .filter(|item| {
+                                    !item.file_is_error && item.file_hash == expected_hash
+                                }),
+                            None => self.db.get_healthy_file_by_hash(&expected_hash),
+                        };
+                        if let Some(item) = existing {
+                            info!(
+                                "Upload of \"{}\" skipped, {} is already stored",
+                                new_header.file_name, expected_hash
+                            );
+                            return Ok((item, true));
+                        }
                     }
-                    Data::Chunk(chunk_data) => {
-                        if let Some(ref mut fh) = file_handler {
-                            hasher.update(&chunk_data);
-                            fh.write_all(&chunk_data).await.map_err(|e| {
-                                error!("Failed to write data in file: {}", &e);
-                                Status::internal(format!("Failed to write data in file: {}", e))
-                            })?;
-                        } else {
-                            warn!("File name should be sent before chunks!");
-                            return Err(Status::internal(format!(
-                                "File name didn't specified yet!"
+
+                    *pending = Some(self.start_upload(&new_header).await?);
+                    header = Some(*new_header);
+                }
+                Some(Data::Chunk(chunk_data)) => {
+                    chunks_received = true;
+                    let (Some(upload), Some(header)) = (pending.as_mut(), &header) else {
+                        warn!("Upload header should be sent before chunks!");
+                        return Err(Status::invalid_argument(
+                            "Upload header should be sent before chunks!",
+                        ));
+                    };
+
+                    if let Some(size) = header.size {
+                        if upload.written() + chunk_data.len() as u64 > size {
+                            warn!("Upload exceeds its declared size of {} bytes", size);
+                            return Err(Status::invalid_argument(format!(
+                                "Upload exceeds its declared size of {} bytes!",
+                                size
                             )));
                         }
                     }
+
+                    limit.throttle(chunk_data.len()).await?;
+                    self.write_upload(upload, &chunk_data).await?;
+                }
+                None => {}
+            }
+        }
+
+        let (Some(upload), Some(header)) = (pending.as_mut(), header) else {
+            warn!("Upload finished without a header!");
+            return Err(Status::invalid_argument("Upload header didn't specified!"));
+        };
+
+        Ok((self.finish_upload(upload, header, true).await?, false))
+    }
+
+    async fn write_upload(&self, upload: &mut Upload<'_>, data: &[u8]) -> Result<(), Status> {
+        match upload {
+            Upload::File(upload) => self.write_chunk(upload, data).await,
+            Upload::Chunked(upload) => self.write_chunked(upload, data).await,
+        }
+    }
+
+    /// Checks the received data against the header and records the file, as
+    /// the next version of its key if it has one. Without `announce` the
+    /// upload of an unkeyed file is left to the caller to record as an event.
+    async fn finish_upload(
+        &self,
+        upload: &mut Upload<'_>,
+        header: UploadHeader,
+        announce: bool,
+    ) -> Result<StoreItem, Status> {
+        let key = versioned_key(&header);
+        let item = match upload {
+            Upload::File(upload) => self.finish_file(upload, header, announce).await?,
+            Upload::Chunked(upload) => self.finish_chunked(upload, header, announce).await?,
+        };
+
+        let item = match key {
+            Some(key) => self.record_version(item, key).await?,
+            None => item,
+        };
+        Ok(item)
+    }
+
+    async fn finish_file(
+        &self,
+        upload: &mut PendingUpload<'_>,
+        header: UploadHeader,
+        announce: bool,
+    ) -> Result<StoreItem, Status> {
+        let file_hash = self
+            .finish_pending(upload, header.size, &header.expected_hash)
+            .await?;
+        // Keyed uploads are announced once they become a version
+        let announce = announce && header.key.is_none();
+
+        self.db
+            .add_new_item(
+                &NewStoreItem {
+                    file_name: header.file_name,
+                    file_path: upload.rel_path.clone(),
+                    file_hash,
+                    root_id: Some(upload.volume.id),
+                    file_size: Some(upload.written as i64),
+                    content_type: Some(header.content_type).filter(|ct| !ct.is_empty()),
+                    metadata: serde_json::to_value(header.metadata).unwrap_or_default(),
+                    chunked: false,
+                    blake3_hash: upload.tree.as_ref().and_then(|tree| tree.root_hash.clone()),
+                    outboard_path: upload.tree.as_ref().map(|tree| tree.rel_path.clone()),
+                    expires_at: expires_at(header.expiration.as_ref()),
+                    retain_until: retain_until(header.retain_until),
+                    tags: normalize_tags(header.tags),
+                },
+                announce,
+            )
+            .map_err(|e| {
+                error!("Error during adding new item to DB! Error: {}", &e);
+                Status::new(tonic::Code::Internal, format!("{}", e))
+            })
+    }
+}
+
+/// Names the file of a new upload after the current time, adding a counter if
+/// an upload of the same name started within the same millisecond.
+fn unique_rel_path(vol: &Volume, name: impl Fn(&str) -> String) -> String {
+    let stamp = Utc::now().timestamp_millis().to_string();
+    let mut rel_path = name(&stamp);
+
+    let mut n = 1;
+    while vol.path.join(&rel_path).exists() {
+        rel_path = name(&format!("{}-{}", stamp, n));
+        n += 1;
+    }
+
+    rel_path
+}
+
+/// Checks the received byte count and SHA-256 against the declared ones.
+/// Returns the hex encoded hash.
+fn check_received(
+    written: u64,
+    hasher: &Sha256,
+    size: Option<u64>,
+    expected_hash: &str,
+) -> Result<String, Status> {
+    if let Some(size) = size {
+        if written != size {
+            warn!(
+                "Upload size mismatch: declared {}, received {}",
+                size, written
+            );
+            return Err(Status::invalid_argument(format!(
+                "Received {} bytes, but {} were declared!",
+                written, size
+            )));
+        }
+    }
+
+    let file_hash = format!("{:x}", hasher.clone().finalize());
+
+    if !expected_hash.is_empty() && !expected_hash.eq_ignore_ascii_case(&file_hash) {
+        warn!(
+            "Upload hash mismatch: expected {}, got {}",
+            expected_hash, file_hash
+        );
+        return Err(Status::invalid_argument(format!(
+            "Uploaded data hash {} doesn't match the expected {}!",
+            file_hash, expected_hash
+        )));
+    }
+
+    Ok(file_hash)
+}
+
+/// Names the file of a call answered with its info in the access log.
+fn note_info(access: &AccessNote, file_hash: &str, result: &Result<FileInfo, Status>) {
+    match result {
+        Ok(info) => access.file(&info.file_hash, &info.file_name),
+        Err(_) => access.file(file_hash, ""),
+    }
+}
+
+/// Sends a located item to a fetch stream. An error is returned when the
+/// stream should end with it, such as `DATA_LOSS` for corrupted data.
+#[instrument(name = "fs.read", skip_all, fields(file_hash = %blob.item.file_hash))]
+async fn send_blob(
+    mut blob: BlobStream,
+    tx: &FetchSender,
+    db: &DbState,
+    metrics: &Metrics,
+    capacity: u64,
+    sent: &mut u64,
+) -> Result<(), Status> {
+    let (start, end, size) = (blob.start, blob.end, blob.size);
+    let ranged = start != 0 || end != size;
+    let res = blob.item;
+
+    send_response(tx, FetchData::Info(file_info(&res, size))).await?;
+
+    let mut hasher = Sha256::new();
+    // Expected and actual hash of corrupted data
+    let mut corrupted = None;
+
+    if let Some((mut outboard, root_hash)) = blob.tree {
+        let slice_len = bao::slice_len(capacity);
+        let mut pos = start / slice_len * slice_len;
+        blob.reader.skip(pos).await.map_err(read_failed)?;
+
+        while pos < end {
+            let Some(data) = blob
+                .reader
+                .next_chunk(slice_len)
+                .await
+                .map_err(read_failed)?
+            else {
+                break;
+            };
+            let len = data.len() as u64;
+            let steps = bao::proof_steps(size, pos, slice_len);
+            let proof = tree::read_proof(&mut outboard, &steps)
+                .await
+                .map_err(read_failed)?;
+
+            if blob.verify {
+                let actual = bao::slice_root(size, pos, &data, &proof);
+                if actual != root_hash {
+                    corrupted = Some((root_hash.to_hex().to_string(), actual.to_hex().to_string()));
+                    break;
                 }
             }
+
+            let data = if blob.proofs {
+                hasher.update(&data);
+                *sent += len;
+                FetchData::Verified(VerifiedChunk {
+                    offset: pos,
+                    data,
+                    proof: proof
+                        .into_iter()
+                        .map(|(hash, is_left)| ProofNode {
+                            hash: hash.to_vec(),
+                            is_left,
+                        })
+                        .collect(),
+                })
+            } else {
+                let from = (start.max(pos) - pos) as usize;
+                let to = (end.min(pos + len) - pos) as usize;
+                hasher.update(&data[from..to]);
+                *sent += (to - from) as u64;
+                FetchData::Chunk(data[from..to].to_vec())
+            };
+
+            blob.limit.throttle(len as usize).await?;
+            send_response(tx, data).await?;
+            pos += len;
         }
+    } else {
+        blob.reader.skip(start).await.map_err(read_failed)?;
+        let mut remaining = end - start;
+
+        while remaining > 0 {
+            let Some(chunk) = blob
+                .reader
+                .next_chunk(capacity.min(remaining))
+                .await
+                .map_err(read_failed)?
+            else {
+                break;
+            };
+            hasher.update(&chunk);
+            *sent += chunk.len() as u64;
+            remaining -= chunk.len() as u64;
 
-        if file_handler.is_some() {
-            file_handler.unwrap().sync_all().await.unwrap();
+            blob.limit.throttle(chunk.len()).await?;
+            send_response(tx, FetchData::Chunk(chunk)).await?;
         }
+    }
+
+    let digest = format!("{:x}", hasher.finalize());
+
+    if corrupted.is_none() && blob.verify && !ranged && digest != res.file_hash {
+        corrupted = Some((res.file_hash.clone(), digest.clone()));
+    }
+
+    if let Some((expected, actual)) = corrupted {
+        warn!(
+            "Corrupted file \"{}\" with id:{}: expected {}, read {}",
+            res.file_name, res.id, expected, actual
+        );
+        if let Err(e) = db.flag_corrupted(res.id) {
+            error!("Could not update error state in DB! Error: {}", e);
+        }
+        metrics.record_corrupted_read();
+
+        return Err(Status::data_loss("File content doesn't match its hash!"));
+    }
+
+    send_response(
+        tx,
+        FetchData::Trailer(FetchTrailer {
+            file_hash: digest,
+            size: *sent,
+        }),
+    )
+    .await
+}
+
+async fn send_response(tx: &FetchSender, data: FetchData) -> Result<(), Status> {
+    let response = FetchFileResponse { data: Some(data) };
+    tx.send(Ok(response)).await.map_err(|err| {
+        error!("Error occured during sending fetch response! Err: {}", err);
+        Status::cancelled("Fetch stream was closed")
+    })
+}
+
+fn read_failed(err: io::Error) -> Status {
+    error!("{}", err);
+    Status::internal("Failed to send file")
+}
+
+/// Checks the size of a batch call and normalizes its hashes.
+fn batch_hashes(hashes: Vec<String>) -> Result<Vec<String>, Status> {
+    if hashes.len() > MAX_BATCH_HASHES {
+        return Err(Status::invalid_argument(format!(
+            "At most {} hashes can be handled at once!",
+            MAX_BATCH_HASHES
+        )));
+    }
+
+    Ok(hashes
+        .into_iter()
+        .map(|hash| hash.to_ascii_lowercase())
+        .collect())
+}
 
-        file_hash = Some(format!("{:x}", hasher.finalize()));
+/// The error for a delete refused because the file was locked or referenced
+/// after it was checked.
+fn kept() -> Status {
+    Status::failed_precondition("File was locked or referenced meanwhile!")
+}
+
+fn item_result(file_hash: String, outcome: Result<(), Status>) -> ItemResult {
+    match outcome {
+        Ok(()) => ItemResult {
+            file_hash,
+            code: tonic::Code::Ok as i32,
+            message: String::from("Ok"),
+        },
+        Err(status) => ItemResult {
+            file_hash,
+            code: status.code() as i32,
+            message: status.message().to_owned(),
+        },
+    }
+}
+
+/// Describes a stored item at the start of a fetch stream.
+fn file_info(item: &StoreItem, size: u64) -> FileInfo {
+    let metadata = match &item.metadata {
+        serde_json::Value::Object(map) => map
+            .iter()
+            .map(|(key, val)| match val {
+                serde_json::Value::String(text) => (key.clone(), text.clone()),
+                other => (key.clone(), other.to_string()),
+            })
+            .collect(),
+        _ => HashMap::new(),
+    };
+
+    FileInfo {
+        file_name: item.file_name.clone(),
+        size,
+        content_type: item.content_type.clone().unwrap_or_default(),
+        file_hash: item.file_hash.clone(),
+        metadata,
+        blake3_hash: item.blake3_hash.clone().unwrap_or_default(),
+        version: item.version.unwrap_or_default() as u32,
+        tags: item.tags.clone(),
+        generation: item.generation as u64,
+        expires_at: item
+            .expires_at
+            .map(|at| at.and_utc().timestamp())
+            .unwrap_or_default(),
+        retain_until: item
+            .retain_until
+            .map(|at| at.and_utc().timestamp())
+            .unwrap_or_default(),
+        legal_hold: item.legal_hold,
+    }
+}
 
-        match self.db.add_new_item(&NewStoreItem {
-            file_name: file_name.unwrap(),
-            file_path: file_path.display().to_string(),
-            file_hash: file_hash.unwrap(),
-        }) {
-            Ok(res) => Ok(Response::new(UploadFileResponse {
+/// Checks an upload header before anything is written to disk.
+fn validate_header(header: &UploadHeader) -> Result<(), Status> {
+    let name = &header.file_name;
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Status::invalid_argument("File name should not be empty!"));
+    }
+
+    if name.contains(['/', '\\', '\0']) {
+        warn!("Rejected file name: {:?}", name);
+        return Err(Status::invalid_argument(
+            "File name should not contain path separators!",
+        ));
+    }
+
+    if header.skip_if_exists && header.expected_hash.is_empty() {
+        return Err(Status::invalid_argument(
+            "Expected hash is required to skip existing files!",
+        ));
+    }
+
+    validate_version_header(header)?;
+    validate_tags(&header.tags)?;
+    validate_hash(&header.expected_hash)
+}
+
+/// Accepts an empty string or a hex encoded SHA-256.
+fn validate_hash(hash: &str) -> Result<(), Status> {
+    if !hash.is_empty() && (hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit())) {
+        return Err(Status::invalid_argument(
+            "Expected hash should be a hex encoded SHA-256!",
+        ));
+    }
+
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl Storage for FileStorage {
+    type FetchFileStream = ReceiverStream<Result<FetchFileResponse, Status>>;
+    type FetchManyStream = ReceiverStream<Result<FetchFileResponse, Status>>;
+    type FetchArchiveStream = ReceiverStream<Result<FetchArchiveResponse, Status>>;
+    type WatchEventsStream = ReceiverStream<Result<FileEvent, Status>>;
+
+    async fn upload_file(
+        &self,
+        request: Request<Streaming<UploadFileRequest>>,
+    ) -> Result<Response<UploadFileResponse>, Status> {
+        self.check_writable()?;
+
+        let caller = Caller::of(&request);
+        let access = AccessNote::of(&request);
+        let mut stream = request.into_inner();
+        let mut pending: Option<Upload> = None;
+
+        let result = self
+            .receive_upload(&caller, &mut stream, &mut pending)
+            .await;
+        match &result {
+            Ok((res, existing)) => {
+                access.file(&res.file_hash, &res.file_name);
+                let received = match existing {
+                    true => 0,
+                    false => res.file_size.unwrap_or(0) as u64,
+                };
+                self.audit.record(
+                    "UploadFile",
+                    &caller,
+                    Some(&res.file_hash),
+                    received,
+                    &result,
+                );
+            }
+            Err(_) => {
+                let received = pending.as_ref().map(Upload::written).unwrap_or(0);
+                self.audit
+                    .record("UploadFile", &caller, None, received, &result);
+            }
+        }
+
+        match result {
+            Ok((res, existing)) => Ok(Response::new(UploadFileResponse {
                 file_name: res.file_name,
                 file_hash: res.file_hash,
+                size: res.file_size.unwrap_or(0) as u64,
+                existing,
+                version: res.version.unwrap_or_default() as u32,
             })),
-            Err(e) => {
-                error!("Error during adding new item to DB! Error: {}", &e);
-                return Err(Status::new(tonic::Code::Internal, format!("{}", e)));
+            Err(status) => {
+                if let Some(upload) = pending {
+                    upload.discard().await;
+                }
+                Err(status)
             }
         }
     }
@@ -148,154 +1378,608 @@ impl Storage for FileStorage {
         &self,
         request: Request<FetchFileRequest>,
     ) -> Result<Response<Self::FetchFileStream>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        let access = AccessNote::of(&request);
         let req = request.into_inner();
+        // What failed fetches are audited with: the hash once known, else the
+        // key or ref looked up
+        let mut target = match (&req.key, req.ref_name.as_str()) {
+            (Some(key), _) => format!("key:{}/{}", key.namespace, key.key),
+            (None, "") => req.file_hash.clone(),
+            (None, ref_name) => format!("ref:{}", ref_name),
+        };
 
-        match self.db.get_file_by_hash(req.file_hash) {
-            Some(res) => {
-                let path = PathBuf::from(res.file_path);
-                if !path.exists() || !path.is_file() {
-                    match self.db.update_last_read_state(res.id, true) {
-                        Ok(res) => {
-                            warn!(
-                                "File \"{}\" with id:{} has problems with itself or path!",
-                                &res.file_path, res.id
-                            );
-                            return Err(Status::new(
-                                tonic::Code::NotFound,
-                                format!("File not found!"),
-                            ));
+        let result = async {
+            let targets = [
+                !req.file_hash.is_empty(),
+                req.key.is_some(),
+                !req.ref_name.is_empty(),
+            ];
+            if targets.into_iter().filter(|set| *set).count() > 1 {
+                return Err(Status::invalid_argument(
+                    "Only one of a file hash, a key or a ref should be set!",
+                ));
+            }
+            if req.version.is_some() && req.key.is_none() {
+                return Err(Status::invalid_argument("Version requires a key!"));
+            }
+
+            let namespace = req.key.as_ref().map(|key| key.namespace.as_str());
+            let limit = self.rate_limits.client(&caller, namespace);
+            limit.check_request()?;
+
+            let found = match &req.key {
+                Some(key) => Some(self.find_version(key, req.version)?),
+                None if !req.ref_name.is_empty() => {
+                    let file_hash = self.resolve_ref(&req.ref_name)?;
+                    self.db.get_file_by_hash(file_hash)
+                }
+                None => self.db.get_file_by_hash(req.file_hash.clone()),
+            };
+            match &found {
+                Some(res) => {
+                    target = res.file_hash.clone();
+                    access.file(&res.file_hash, &res.file_name);
+                }
+                None => access.file(&req.file_hash, ""),
+            }
+
+            match found {
+                Some(res) if res.chunked => {
+                    let reader = self.open_chunked(&res)?;
+                    let size = res.file_size.unwrap_or(0) as u64;
+                    self.stream_blob(res, reader, size, &req, caller.clone(), &limit)
+                        .await
+                }
+                Some(res) => {
+                    let (volume, path) = self.locate_blob(&res)?;
+
+                    info!("Reading file {}", path.display());
+
+                    let fh = File::open(&path).await.map_err(|e| {
+                        error!("Failed to open file: {}", &e);
+                        if let Some(vol) = volume {
+                            self.volumes.report_io_error(&self.db, vol, &e, false);
                         }
-                        Err(_) => unreachable!(),
-                    }
+                        Status::internal("Failed to open file")
+                    })?;
+
+                    let size = match fh.metadata().await {
+                        Ok(meta) => meta.len(),
+                        Err(_) => res.file_size.unwrap_or(0) as u64,
+                    };
+                    let reader = BlobReader {
+                        current: Some(fh),
+                        pending: VecDeque::new(),
+                    };
+                    self.stream_blob(res, reader, size, &req, caller.clone(), &limit)
+                        .await
+                }
+                None => {
+                    error!("Could not found such hash!");
+                    Err(Status::new(
+                        tonic::Code::NotFound,
+                        "Could not found such hash!",
+                    ))
                 }
+            }
+        }
+        .await;
+        if result.is_err() {
+            self.audit
+                .record("FetchFile", &caller, Some(&target), 0, &result);
+        }
+        result
+    }
+
+    async fn has_files(
+        &self,
+        request: Request<HasFilesRequest>,
+    ) -> Result<Response<HasFilesResponse>, Status> {
+        self.check_available()?;
+        self.check_rate(&Caller::of(&request), None)?;
+        let hashes = batch_hashes(request.into_inner().file_hashes)?;
+
+        match self.db.get_healthy_hashes(&hashes) {
+            Ok(file_hashes) => Ok(Response::new(HasFilesResponse { file_hashes })),
+            Err(e) => {
+                error!("Error during checking stored hashes! Error: {}", &e);
+                Err(Status::internal("Internal service error!"))
+            }
+        }
+    }
 
-                info!("Reading file {}", path.display());
+    async fn batch_stat(
+        &self,
+        request: Request<BatchStatRequest>,
+    ) -> Result<Response<BatchStatResponse>, Status> {
+        self.check_available()?;
+        self.check_rate(&Caller::of(&request), None)?;
+        let hashes = batch_hashes(request.into_inner().file_hashes)?;
+        let found = self.find_files(&hashes)?;
 
-                let (tx, rx) = mpsc::channel(self.chunk_size as usize);
-                let tx_error = tx.clone();
-                let capacity = self.chunk_size;
+        let files = hashes
+            .into_iter()
+            .map(|hash| match found.get(&hash) {
+                Some(item) => FileStat {
+                    file_hash: hash,
+                    found: true,
+                    info: Some(file_info(item, item.file_size.unwrap_or(0) as u64)),
+                    is_error: item.file_is_error,
+                },
+                None => FileStat {
+                    file_hash: hash,
+                    ..Default::default()
+                },
+            })
+            .collect();
 
-                tokio::spawn(async move {
-                    let result = async move {
-                        let fh = File::open(path).await.unwrap();
-                        let mut handler = fh.take(capacity);
+        Ok(Response::new(BatchStatResponse { files }))
+    }
 
-                        loop {
-                            let mut response = FetchFileResponse {
-                                chunk: Vec::with_capacity(capacity as usize),
-                            };
+    async fn batch_delete(
+        &self,
+        request: Request<BatchDeleteRequest>,
+    ) -> Result<Response<BatchDeleteResponse>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        self.check_rate(&caller, None)?;
+        let hashes = batch_hashes(request.into_inner().file_hashes)?;
+        let found = self.find_files(&hashes)?;
+        let refs = self.referencing_refs(&hashes)?;
 
-                            let bytes_read = handler.read_to_end(&mut response.chunk).await?;
+        // Files on unavailable volumes are kept, like with single deletes
+        let mut outcomes: HashMap<String, Result<(), Status>> = HashMap::new();
+        let mut ids = Vec::with_capacity(found.len());
+        for item in found.values() {
+            if let Some(names) = refs.get(&item.file_hash) {
+                outcomes.insert(item.file_hash.clone(), Err(referenced(names)));
+                continue;
+            }
+            if let Some(status) = locked(item) {
+                outcomes.insert(item.file_hash.clone(), Err(status));
+                continue;
+            }
+            if !item.chunked {
+                if let Err(status) = self.blob_path(item) {
+                    outcomes.insert(item.file_hash.clone(), Err(status));
+                    continue;
+                }
+            }
+            ids.push(item.id);
+        }
 
-                            if bytes_read == 0 {
-                                break;
-                            } else {
-                                handler.set_limit(capacity);
-                            }
+        // Locks and refs placed meanwhile are checked again by the delete
+        let removed = self.db.remove_unlocked(&ids).map_err(|e| {
+            error!("Error during removing records from DB! Error: {}", &e);
+            Status::internal("Internal service error!")
+        })?;
 
-                            if let Err(err) = tx.send(Ok(response)).await {
-                                error!("Error occured during sending chunk! Err: {}", err);
-                                break;
-                            }
+        for item in removed {
+            let outcome = self.remove_blob(&item).await;
+            outcomes.insert(item.file_hash, outcome);
+        }
+        for item in found.values() {
+            if ids.contains(&item.id) && !outcomes.contains_key(&item.file_hash) {
+                outcomes.insert(item.file_hash.clone(), Err(kept()));
+            }
+        }
 
-                            if bytes_read < capacity as usize {
-                                break;
-                            }
-                        }
+        let results = hashes
+            .into_iter()
+            .map(|hash| {
+                let outcome = match outcomes.get(&hash) {
+                    Some(outcome) => outcome.clone(),
+                    None => Err(Status::not_found("Could not found such hash!")),
+                };
+                self.audit
+                    .record("BatchDelete", &caller, Some(&hash), 0, &outcome);
+                item_result(hash, outcome)
+            })
+            .collect();
 
-                        Ok::<(), anyhow::Error>(())
-                    };
+        Ok(Response::new(BatchDeleteResponse { results }))
+    }
+
+    async fn fetch_many(
+        &self,
+        request: Request<FetchManyRequest>,
+    ) -> Result<Response<Self::FetchManyStream>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        let request = request.into_inner();
+        let hashes = batch_hashes(request.file_hashes)?;
+        let limit = self.rate_limits.client(&caller, None);
+        limit.check_request()?;
+        let found = self.find_files(&hashes)?;
 
-                    if let Err(err) = result.await {
-                        error!("{}", err);
+        let fetch = FetchFileRequest {
+            verify: request.verify,
+            ..Default::default()
+        };
+        let mut blobs = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let blob = match found.get(&hash) {
+                Some(item) => match self.lazy_reader(item) {
+                    Ok((reader, size)) => {
+                        self.prepare_blob(item.clone(), reader, size, &fetch, &limit)
+                            .await
+                    }
+                    Err(status) => Err(status),
+                },
+                None => Err(Status::not_found("Could not found such hash!")),
+            };
+            blobs.push((hash, blob));
+        }
 
-                        let send_result = tx_error
-                            .send(Err(Status::internal("Failed to send file")))
-                            .await;
+        let db = self.db.clone();
+        let metrics = self.metrics.clone();
+        let audit = self.audit.clone();
+        let capacity = self.chunk_size;
 
-                        if let Err(err) = send_result {
-                            error!("{}", err);
+        let (tx, rx) = mpsc::channel(self.chunk_size as usize);
+
+        tokio::spawn(
+            async move {
+                for (hash, blob) in blobs {
+                    let mut sent = 0;
+                    let result = match blob {
+                        Ok(blob) => send_blob(blob, &tx, &db, &metrics, capacity, &mut sent).await,
+                        Err(status) => Err(status),
+                    };
+                    audit.record("FetchMany", &caller, Some(&hash), sent, &result);
+
+                    if let Err(status) = result {
+                        if tx.is_closed() {
+                            break;
+                        }
+                        let error = FetchData::Error(item_result(hash, Err(status)));
+                        if send_response(&tx, error).await.is_err() {
+                            break;
                         }
                     }
-                });
-                Ok(Response::new(ReceiverStream::new(rx)))
+                }
             }
-            None => {
-                error!("Could not found such hash!");
-                return Err(Status::new(
-                    tonic::Code::NotFound,
-                    format!("Could not found such hash!"),
-                ));
+            .in_current_span(),
+        );
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn fetch_archive(
+        &self,
+        request: Request<FetchArchiveRequest>,
+    ) -> Result<Response<Self::FetchArchiveStream>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+
+        let result = self
+            .fetch_archive(request.into_inner(), caller.clone())
+            .await;
+        if result.is_err() {
+            self.audit.record("FetchArchive", &caller, None, 0, &result);
+        }
+        Ok(Response::new(result?))
+    }
+
+    async fn upload_archive(
+        &self,
+        request: Request<Streaming<UploadArchiveRequest>>,
+    ) -> Result<Response<UploadArchiveResponse>, Status> {
+        self.check_writable()?;
+        let caller = Caller::of(&request);
+
+        let result = self.upload_archive(request.into_inner(), &caller).await;
+        match &result {
+            Ok(response) => {
+                for entry in &response.entries {
+                    self.audit.record(
+                        "UploadArchive",
+                        &caller,
+                        Some(&entry.file_hash),
+                        entry.size,
+                        &result,
+                    );
+                }
             }
+            Err(_) => self
+                .audit
+                .record("UploadArchive", &caller, None, 0, &result),
         }
+        Ok(Response::new(result?))
+    }
+
+    async fn list_versions(
+        &self,
+        request: Request<ListVersionsRequest>,
+    ) -> Result<Response<ListVersionsResponse>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+
+        let Some(key) = request.into_inner().key else {
+            return Err(Status::invalid_argument("File key should be set!"));
+        };
+        self.check_rate(&caller, Some(&key.namespace))?;
+        Ok(Response::new(self.list_versions(&key)?))
+    }
+
+    async fn set_ref(&self, request: Request<SetRefRequest>) -> Result<Response<Ref>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        self.check_rate(&caller, None)?;
+        let request = request.into_inner();
+        let target = format!("ref:{}", request.name);
+
+        let result = self.set_ref(request);
+        self.audit
+            .record("SetRef", &caller, Some(&target), 0, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn get_ref(&self, request: Request<GetRefRequest>) -> Result<Response<Ref>, Status> {
+        self.check_available()?;
+        self.check_rate(&Caller::of(&request), None)?;
+
+        Ok(Response::new(self.get_ref(&request.into_inner().name)?))
+    }
+
+    async fn delete_ref(
+        &self,
+        request: Request<DeleteRefRequest>,
+    ) -> Result<Response<DeleteRefResponse>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        self.check_rate(&caller, None)?;
+        let request = request.into_inner();
+        let target = format!("ref:{}", request.name);
+
+        let result = self.delete_ref(&request.name, request.expected_hash.as_deref());
+        self.audit
+            .record("DeleteRef", &caller, Some(&target), 0, &result);
+        result?;
+        Ok(Response::new(DeleteRefResponse {}))
+    }
+
+    async fn list_refs(
+        &self,
+        request: Request<ListRefsRequest>,
+    ) -> Result<Response<ListRefsResponse>, Status> {
+        self.check_available()?;
+        self.check_rate(&Caller::of(&request), None)?;
+
+        let refs = self.list_refs(&request.into_inner())?;
+        Ok(Response::new(ListRefsResponse { refs }))
+    }
+
+    async fn update_metadata(
+        &self,
+        request: Request<UpdateMetadataRequest>,
+    ) -> Result<Response<FileInfo>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        self.check_rate(&caller, None)?;
+        let access = AccessNote::of(&request);
+        let request = request.into_inner();
+        let file_hash = request.file_hash.clone();
+
+        let result = self.update_metadata(request);
+        self.audit
+            .record("UpdateMetadata", &caller, Some(&file_hash), 0, &result);
+        note_info(&access, &file_hash, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn extend_ttl(
+        &self,
+        request: Request<ExtendTtlRequest>,
+    ) -> Result<Response<FileInfo>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        self.check_rate(&caller, None)?;
+        let access = AccessNote::of(&request);
+        let request = request.into_inner();
+        let file_hash = request.file_hash.clone();
+
+        let result = self.extend_ttl(request);
+        self.audit
+            .record("ExtendTtl", &caller, Some(&file_hash), 0, &result);
+        note_info(&access, &file_hash, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn set_retention(
+        &self,
+        request: Request<SetRetentionRequest>,
+    ) -> Result<Response<FileInfo>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        self.check_rate(&caller, None)?;
+        let access = AccessNote::of(&request);
+        let request = request.into_inner();
+        let file_hash = request.file_hash.clone();
+
+        let result = self.set_retention(request);
+        self.audit
+            .record("SetRetention", &caller, Some(&file_hash), 0, &result);
+        note_info(&access, &file_hash, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn watch_events(
+        &self,
+        request: Request<WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        let request = request.into_inner();
+        self.check_rate(&caller, request.namespace.as_deref())?;
+
+        Ok(Response::new(self.watch_events(request)?))
+    }
+
+    async fn list_files(
+        &self,
+        request: Request<ListFilesRequest>,
+    ) -> Result<Response<ListFilesResponse>, Status> {
+        self.check_available()?;
+        self.check_rate(&Caller::of(&request), None)?;
+
+        Ok(Response::new(self.list_files(request.into_inner())?))
     }
 
     async fn delete_file(
         &self,
         request: Request<DeleteFileRequest>,
     ) -> Result<Response<DeleteFileResponse>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        self.check_rate(&caller, None)?;
+        let access = AccessNote::of(&request);
         let request = request.into_inner();
-        match self.db.remove_item_by_hash(request.file_hash.clone()) {
-            Ok(item) => {
-                let path = PathBuf::from(item.file_path);
-                if !path.exists() {
-                    if !item.file_is_error {
-                        match self.db.update_last_read_state(item.id, true) {
-                            Ok(res) => {
-                                warn!("There is a problem with file \"{}\"", &res.file_path);
-                                return Err(Status::new(
-                                    tonic::Code::Internal,
-                                    format!("Could not find file!"),
-                                ));
-                            }
-                            Err(e) => {
-                                error!("Could not update error state in DB! Error: {}", e);
-                                return Err(Status::new(
-                                    tonic::Code::Internal,
-                                    format!("Internal service error!"),
-                                ));
-                            }
-                        }
-                    }
-                    return Err(Status::new(
-                        tonic::Code::NotFound,
-                        format!("File not found!"),
-                    ));
-                }
+        access.file(&request.file_hash, "");
 
-                match remove_file(&path).await {
-                    Ok(_) => {
-                        info!("Delete: {}", path.display());
-                        return Ok(Response::new(DeleteFileResponse {
-                            code: tonic::Code::Ok as i32,
-                            message: String::from("Ok"),
-                        }));
-                    }
-                    Err(_) => match self.db.update_last_read_state(item.id, true) {
-                        Ok(res) => {
-                            warn!("There is a problem with file \"{}\"", &res.file_path);
-                            return Err(Status::new(
-                                tonic::Code::Internal,
-                                format!("Internal service error!"),
-                            ));
-                        }
-                        Err(e) => {
-                            error!("Could not update error state in DB! Error: {}", e);
-                            return Err(Status::new(
-                                tonic::Code::Internal,
-                                format!("Internal service error!"),
-                            ));
-                        }
-                    },
+        let result = async {
+            if let Some(item) = self.db.get_file_by_hash(request.file_hash.clone()) {
+                access.file(&item.file_hash, &item.file_name);
+                let refs = self.referencing_refs(std::slice::from_ref(&item.file_hash))?;
+                if let Some(names) = refs.get(&item.file_hash) {
+                    warn!("File {} is still referenced", item.file_hash);
+                    return Err(referenced(names));
+                }
+                if let Some(status) = locked(&item) {
+                    warn!("File {} is locked", item.file_hash);
+                    return Err(status);
+                }
+                if !item.chunked {
+                    self.blob_path(&item)?;
                 }
             }
-            Err(_) => {
-                error!("Could not found record with hash: {}", request.file_hash);
-                return Err(Status::new(
-                    tonic::Code::Internal,
-                    format!("Record not found!"),
-                ));
+
+            match self.db.remove_item_by_hash(request.file_hash.clone()) {
+                Ok(Some(item)) => {
+                    self.remove_blob(&item).await?;
+                    Ok(Response::new(DeleteFileResponse {
+                        code: tonic::Code::Ok as i32,
+                        message: String::from("Ok"),
+                    }))
+                }
+                Ok(None) => {
+                    warn!(
+                        "File {} was locked or referenced meanwhile",
+                        request.file_hash
+                    );
+                    Err(kept())
+                }
+                Err(_) => {
+                    error!("Could not found record with hash: {}", request.file_hash);
+                    Err(Status::new(tonic::Code::Internal, "Record not found!"))
+                }
             }
         }
+        .await;
+        self.audit
+            .record("DeleteFile", &caller, Some(&request.file_hash), 0, &result);
+        result
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        request: Request<CreateMultipartUploadRequest>,
+    ) -> Result<Response<CreateMultipartUploadResponse>, Status> {
+        self.check_writable()?;
+        let caller = Caller::of(&request);
+
+        let result = async {
+            let header = request
+                .into_inner()
+                .header
+                .ok_or_else(|| Status::invalid_argument("Upload header didn't specified!"))?;
+            let namespace = header.key.as_ref().map(|key| key.namespace.as_str());
+            self.check_rate(&caller, namespace)?;
+            self.create_multipart(header).await
+        }
+        .await;
+        let target = result.as_ref().ok().map(|id| format!("upload:{}", id));
+        self.audit.record(
+            "CreateMultipartUpload",
+            &caller,
+            target.as_deref(),
+            0,
+            &result,
+        );
+
+        let upload_id = result?;
+        Ok(Response::new(CreateMultipartUploadResponse { upload_id }))
+    }
+
+    async fn upload_part(
+        &self,
+        request: Request<Streaming<UploadPartRequest>>,
+    ) -> Result<Response<UploadPartResponse>, Status> {
+        self.check_writable()?;
+        let caller = Caller::of(&request);
+
+        let result = self.upload_part(request.into_inner(), &caller).await;
+        let (part_hash, size) = match &result {
+            Ok(res) => (Some(res.part_hash.as_str()), res.size),
+            Err(_) => (None, 0),
+        };
+        self.audit
+            .record("UploadPart", &caller, part_hash, size, &result);
+        Ok(Response::new(result?))
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        request: Request<CompleteMultipartUploadRequest>,
+    ) -> Result<Response<UploadFileResponse>, Status> {
+        self.check_writable()?;
+        let caller = Caller::of(&request);
+        let access = AccessNote::of(&request);
+
+        let result = self.complete_multipart(request.into_inner(), &caller).await;
+        if let Ok(res) = &result {
+            access.file(&res.file_hash, &res.file_name);
+        }
+        let (file_hash, size) = match &result {
+            Ok(res) => (Some(res.file_hash.as_str()), res.file_size.unwrap_or(0)),
+            Err(_) => (None, 0),
+        };
+        self.audit.record(
+            "CompleteMultipartUpload",
+            &caller,
+            file_hash,
+            size as u64,
+            &result,
+        );
+
+        let res = result?;
+        Ok(Response::new(UploadFileResponse {
+            file_name: res.file_name,
+            file_hash: res.file_hash,
+            size: res.file_size.unwrap_or(0) as u64,
+            existing: false,
+            version: res.version.unwrap_or_default() as u32,
+        }))
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        request: Request<AbortMultipartUploadRequest>,
+    ) -> Result<Response<AbortMultipartUploadResponse>, Status> {
+        self.check_available()?;
+        let caller = Caller::of(&request);
+        self.check_rate(&caller, None)?;
+        let upload_id = request.into_inner().upload_id;
+        let target = format!("upload:{}", upload_id);
+
+        let result = self.abort_multipart(&upload_id).await;
+        self.audit
+            .record("AbortMultipartUpload", &caller, Some(&target), 0, &result);
+        result?;
+        Ok(Response::new(AbortMultipartUploadResponse {
+            code: tonic::Code::Ok as i32,
+            message: String::from("Ok"),
+        }))
     }
 }
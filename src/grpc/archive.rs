@@ -0,0 +1,308 @@
+use chrono::Utc;
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    path::PathBuf,
+};
+use tokio::{runtime::Handle, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+use tracing::{error, info, Span};
+use zip::{result::ZipError, write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use super::{batch_hashes, FileStorage};
+use crate::{
+    audit::Caller,
+    ratelimit::ClientLimit,
+    storage::{ArchiveFormat, FetchArchiveRequest, FetchArchiveResponse},
+};
+
+type ArchiveSender = mpsc::Sender<Result<FetchArchiveResponse, Status>>;
+
+/// A stored file to be added to an archive.
+struct ArchiveEntry {
+    file_hash: String,
+    name: String,
+    size: u64,
+    files: VecDeque<(PathBuf, u64)>,
+}
+
+impl FileStorage {
+    /// Streams the files of the given hashes as one archive, built while it's
+    /// sent. Each file is added once, under its stored name made unique.
+    pub(super) async fn fetch_archive(
+        &self,
+        request: FetchArchiveRequest,
+        caller: Caller,
+    ) -> Result<ReceiverStream<Result<FetchArchiveResponse, Status>>, Status> {
+        let format = ArchiveFormat::try_from(request.format)
+            .map_err(|_| Status::invalid_argument("Unknown archive format!"))?;
+        let hashes = batch_hashes(request.file_hashes)?;
+        let limit = self.rate_limits.client(&caller, None);
+        limit.check_request()?;
+        let found = self.find_files(&hashes)?;
+
+        let missing: Vec<&str> = hashes
+            .iter()
+            .filter(|hash| !found.contains_key(*hash))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(Status::not_found(format!(
+                "Could not found such hashes: {}",
+                missing.join(", ")
+            )));
+        }
+
+        let mut added = HashSet::new();
+        let mut names = HashSet::new();
+        let mut entries = Vec::with_capacity(found.len());
+        for hash in &hashes {
+            if !added.insert(hash) {
+                continue;
+            }
+
+            let item = &found[hash];
+            let (reader, size) = self.lazy_reader(item)?;
+            entries.push(ArchiveEntry {
+                file_hash: hash.clone(),
+                name: unique_name(&item.file_name, &mut names),
+                size,
+                files: reader.pending,
+            });
+        }
+
+        info!(
+            "Building {} archive of {} files",
+            format.as_str_name(),
+            entries.len()
+        );
+
+        // Recorded once the archive is sent, with how it went
+        let fetched: Vec<(String, u64)> = entries
+            .iter()
+            .map(|entry| (entry.file_hash.clone(), entry.size))
+            .collect();
+        let audit = self.audit.clone();
+        let chunk_size = self.chunk_size as usize;
+        let (tx, rx) = mpsc::channel(4);
+
+        let span = Span::current();
+        let runtime = Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
+            let writer = ChannelWriter {
+                tx: tx.clone(),
+                buffer: Vec::with_capacity(chunk_size),
+                chunk_size,
+                limit,
+                runtime,
+            };
+
+            let result = match write_archive(format, entries, writer).map_err(throttled) {
+                Ok(()) => Ok(()),
+                // Sent to the client already
+                Err(Ok(status)) => Err(status),
+                Err(Err(_)) if tx.is_closed() => Err(Status::cancelled("Fetch stream was closed")),
+                Err(Err(e)) => {
+                    error!("Failed to build archive: {}", e);
+                    let status = Status::internal("Failed to build archive");
+                    if let Err(err) = tx.blocking_send(Err(status.clone())) {
+                        error!("{}", err);
+                    }
+                    Err(status)
+                }
+            };
+
+            for (file_hash, size) in fetched {
+                let sent = if result.is_ok() { size } else { 0 };
+                audit.record("FetchArchive", &caller, Some(&file_hash), sent, &result);
+            }
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+}
+
+/// The `Status` a `ChannelWriter` failed with for being over the bandwidth,
+/// also when the zip writer wrapped it.
+fn throttled(e: io::Error) -> Result<Status, io::Error> {
+    let e = match e.downcast::<ZipError>() {
+        Ok(ZipError::Io(e)) => e,
+        Ok(e) => return Err(e.into()),
+        Err(e) => e,
+    };
+    e.downcast::<Status>()
+}
+
+fn write_archive(
+    format: ArchiveFormat,
+    entries: Vec<ArchiveEntry>,
+    writer: ChannelWriter,
+) -> io::Result<()> {
+    match format {
+        ArchiveFormat::Tar => write_tar(entries, writer)?.flush(),
+        ArchiveFormat::TarZstd => {
+            let encoder = zstd::Encoder::new(writer, 0)?;
+            write_tar(entries, encoder)?.finish()?.flush()
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new_stream(writer);
+            for entry in entries {
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .unix_permissions(0o644)
+                    .large_file(entry.size >= u32::MAX as u64);
+                zip.start_file(entry.name, options)?;
+                copy_entry(entry.files, entry.size, &mut zip)?;
+            }
+            zip.finish()?.into_inner().flush()
+        }
+    }
+}
+
+fn write_tar<W: Write>(entries: Vec<ArchiveEntry>, writer: W) -> io::Result<W> {
+    let mtime = Utc::now().timestamp() as u64;
+    let mut builder = tar::Builder::new(writer);
+
+    for entry in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(entry.size);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, &entry.name, EntryReader::new(entry.files))?;
+    }
+
+    builder.into_inner()
+}
+
+fn copy_entry<W: Write>(files: VecDeque<(PathBuf, u64)>, size: u64, out: &mut W) -> io::Result<()> {
+    let copied = io::copy(&mut EntryReader::new(files), out)?;
+    if copied != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Read {} bytes of {}", copied, size),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Makes a stored file name usable as an entry name and different from the
+/// names taken so far, `name.ext` becomes `name (1).ext` and so on.
+fn unique_name(file_name: &str, names: &mut HashSet<String>) -> String {
+    // Names stored before they were validated may contain path separators
+    let name = match file_name.replace(['/', '\\', '\0'], "_") {
+        name if name.is_empty() || name == "." || name == ".." => "_".to_owned(),
+        name => name,
+    };
+
+    if names.insert(name.clone()) {
+        return name;
+    }
+
+    let (stem, ext) = match name.rfind('.') {
+        Some(pos) if pos > 0 => name.split_at(pos),
+        _ => (name.as_str(), ""),
+    };
+    for n in 1.. {
+        let candidate = format!("{} ({}){}", stem, n, ext);
+        if names.insert(candidate.clone()) {
+            return candidate;
+        }
+    }
+
+    unreachable!()
+}
+
+/// Reads the data of an entry from its files, failing if one of them is
+/// shorter than recorded.
+struct EntryReader {
+    current: Option<io::Take<File>>,
+    pending: VecDeque<(PathBuf, u64)>,
+}
+
+impl EntryReader {
+    fn new(files: VecDeque<(PathBuf, u64)>) -> Self {
+        Self {
+            current: None,
+            pending: files,
+        }
+    }
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let file = match self.current.as_mut() {
+                Some(file) => file,
+                None => match self.pending.pop_front() {
+                    Some((path, len)) => self.current.insert(File::open(path)?.take(len)),
+                    None => return Ok(0),
+                },
+            };
+
+            let read = file.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            if file.limit() > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Stored file is shorter than recorded",
+                ));
+            }
+            self.current = None;
+        }
+    }
+}
+
+/// Sends everything written to it as archive chunks of `chunk_size` bytes.
+struct ChannelWriter {
+    tx: ArchiveSender,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+    limit: ClientLimit,
+    runtime: Handle,
+}
+
+impl ChannelWriter {
+    /// Fails with the `Status` sent to the client if it's over its bandwidth.
+    fn send(&mut self) -> io::Result<()> {
+        if let Err(status) = self
+            .runtime
+            .block_on(self.limit.throttle(self.buffer.len()))
+        {
+            let _ = self.tx.blocking_send(Err(status.clone()));
+            return Err(io::Error::other(status));
+        }
+
+        let response = FetchArchiveResponse {
+            chunk: mem::replace(&mut self.buffer, Vec::with_capacity(self.chunk_size)),
+        };
+        self.tx
+            .blocking_send(Ok(response))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Archive stream was closed"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == self.chunk_size {
+            self.send()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.send()
+    }
+}
@@ -0,0 +1,260 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+use tokio::fs::{create_dir_all, remove_file, rename};
+use tonic::Status;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::{
+    check_received, expires_at, normalize_tags, retain_until, tree::OUTBOARD_DIR, unique_rel_path,
+    BlobReader, FileStorage, PendingTree,
+};
+use crate::{
+    chunker::{Chunker, ChunkerConfig},
+    models::{NewChunk, NewStoreItem, StoreItem},
+    storage::UploadHeader,
+};
+
+/// Directory on each volume holding the chunks of chunked files.
+const CHUNKS_DIR: &str = ".chunks";
+
+pub(super) struct ChunkedUpload<'a> {
+    chunker: Chunker,
+    hasher: Sha256,
+    pub(super) written: u64,
+    chunk_ids: Vec<i32>,
+    new_chunks: usize,
+    pub(super) tree: PendingTree<'a>,
+}
+
+impl FileStorage {
+    /// Starts a chunked upload, its hash tree is kept in the `.outboard`
+    /// folder of a volume chosen by the placement policy.
+    pub(super) async fn start_chunked(
+        &self,
+        config: ChunkerConfig,
+        header: &UploadHeader,
+    ) -> Result<ChunkedUpload<'_>, Status> {
+        let vol = match self.volumes.place(&self.db, &header.file_name, 0) {
+            Some(vol) => vol,
+            None => {
+                error!("No storage volume available for writing!");
+                return Err(self.on_disk_full());
+            }
+        };
+
+        let rel_path = unique_rel_path(vol, |stamp| {
+            format!("{}/{}_{}.obao", OUTBOARD_DIR, stamp, header.file_name)
+        });
+
+        Ok(ChunkedUpload {
+            chunker: Chunker::new(config),
+            hasher: Sha256::new(),
+            written: 0,
+            chunk_ids: Vec::new(),
+            new_chunks: 0,
+            tree: self.open_tree(vol, rel_path).await?,
+        })
+    }
+
+    pub(super) async fn write_chunked(
+        &self,
+        upload: &mut ChunkedUpload<'_>,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        self.write_tree(&mut upload.tree, data).await?;
+        upload.hasher.update(data);
+        upload.written += data.len() as u64;
+
+        for chunk in upload.chunker.push(data) {
+            self.add_to_manifest(upload, &chunk).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores the last chunks and records the file with its chunk manifest.
+    pub(super) async fn finish_chunked(
+        &self,
+        upload: &mut ChunkedUpload<'_>,
+        header: UploadHeader,
+        announce: bool,
+    ) -> Result<StoreItem, Status> {
+        let file_hash = check_received(
+            upload.written,
+            &upload.hasher,
+            header.size,
+            &header.expected_hash,
+        )?;
+
+        for chunk in upload.chunker.finish() {
+            self.add_to_manifest(upload, &chunk).await?;
+        }
+        let blake3_hash = self.finish_tree(&mut upload.tree).await?;
+        // Keyed uploads are announced once they become a version
+        let announce = announce && header.key.is_none();
+
+        info!(
+            "Chunked upload of \"{}\": {} chunks, {} new",
+            header.file_name,
+            upload.chunk_ids.len(),
+            upload.new_chunks
+        );
+
+        self.db
+            .add_chunked_item(
+                &NewStoreItem {
+                    file_name: header.file_name,
+                    file_path: String::new(),
+                    file_hash,
+                    root_id: Some(upload.tree.volume.id),
+                    file_size: Some(upload.written as i64),
+                    content_type: Some(header.content_type).filter(|ct| !ct.is_empty()),
+                    metadata: serde_json::to_value(header.metadata).unwrap_or_default(),
+                    chunked: true,
+                    blake3_hash: Some(blake3_hash),
+                    outboard_path: Some(upload.tree.rel_path.clone()),
+                    expires_at: expires_at(header.expiration.as_ref()),
+                    retain_until: retain_until(header.retain_until),
+                    tags: normalize_tags(header.tags),
+                },
+                &upload.chunk_ids,
+                announce,
+            )
+            .map_err(|e| {
+                error!("Error during adding new item to DB! Error: {}", &e);
+                Status::new(tonic::Code::Internal, format!("{}", e))
+            })
+    }
+
+    async fn add_to_manifest(
+        &self,
+        upload: &mut ChunkedUpload<'_>,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        let chunk_hash = format!("{:x}", Sha256::digest(data));
+
+        let chunk_id = match self.db.touch_chunk(&chunk_hash) {
+            Some(chunk) => chunk.id,
+            None => {
+                upload.new_chunks += 1;
+                self.store_chunk(&chunk_hash, data).await?
+            }
+        };
+
+        upload.chunk_ids.push(chunk_id);
+        Ok(())
+    }
+
+    /// Writes a new chunk to `.chunks/<prefix>/<hash>` on a volume chosen by
+    /// the placement policy and registers it. Returns the chunk id.
+    #[instrument(name = "fs.chunk", skip_all, fields(chunk_hash = %chunk_hash))]
+    async fn store_chunk(&self, chunk_hash: &str, data: &[u8]) -> Result<i32, Status> {
+        let vol = match self.volumes.place(&self.db, chunk_hash, data.len() as u64) {
+            Some(vol) => vol,
+            None => {
+                error!("No storage volume available for writing!");
+                return Err(self.on_disk_full());
+            }
+        };
+
+        let rel_dir = format!("{}/{}", CHUNKS_DIR, &chunk_hash[..2]);
+        create_dir_all(vol.path.join(&rel_dir)).await.map_err(|e| {
+            error!("Failed to create directory for chunks: {}", &e);
+            self.volumes.report_io_error(&self.db, vol, &e, true);
+            Status::internal(format!("Failed to create directory for chunks: {}", e))
+        })?;
+
+        // Written under a unique name first, so concurrent uploads of the same
+        // chunk never see a partial file
+        let tmp_path = format!("{}/{}.{}.tmp", rel_dir, chunk_hash, Uuid::new_v4());
+        let mut pending = self.open_pending(vol, tmp_path).await?;
+
+        let written = async {
+            self.write_chunk(&mut pending, data).await?;
+            self.finish_pending(&mut pending, None, "").await
+        }
+        .await;
+        if let Err(status) = written {
+            pending.discard().await;
+            return Err(status);
+        }
+
+        let rel_path = format!("{}/{}", rel_dir, chunk_hash);
+        let path = vol.path.join(&rel_path);
+        if let Err(e) = rename(&pending.path, &path).await {
+            error!("Failed to move chunk in place: {}", &e);
+            self.volumes.report_io_error(&self.db, vol, &e, true);
+            pending.discard().await;
+            return Err(Status::internal(format!("Failed to store chunk: {}", e)));
+        }
+
+        let chunk = self
+            .db
+            .add_chunk(&NewChunk {
+                chunk_hash: chunk_hash.to_owned(),
+                chunk_size: data.len() as i64,
+                root_id: vol.id,
+                file_path: rel_path.clone(),
+            })
+            .map_err(|e| {
+                error!("Error during adding chunk to DB! Error: {}", &e);
+                Status::internal(format!("{}", e))
+            })?;
+
+        // Another upload registered the same chunk on a different volume
+        if chunk.root_id != vol.id || chunk.file_path != rel_path {
+            if let Err(e) = remove_file(&path).await {
+                warn!(
+                    "Couldn't remove duplicate chunk \"{}\": {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+
+        Ok(chunk.id)
+    }
+
+    /// Prepares reading a chunked item from its manifest, refusing items with
+    /// chunks on an offline or unknown volume.
+    pub(super) fn open_chunked(&self, item: &StoreItem) -> Result<BlobReader, Status> {
+        let chunks = self.db.get_file_chunks(item.id).map_err(|e| {
+            error!("Error during loading chunk manifest from DB! Error: {}", &e);
+            Status::internal("Internal service error!")
+        })?;
+
+        let mut checked = HashSet::new();
+        let mut pending = VecDeque::with_capacity(chunks.len());
+        for chunk in chunks {
+            let vol = self.volumes.get(chunk.root_id).ok_or_else(|| {
+                error!(
+                    "Volume {} of chunk {} is not configured!",
+                    chunk.root_id, chunk.chunk_hash
+                );
+                Status::unavailable("Storage volume of the file is not available!")
+            })?;
+
+            if checked.insert(vol.id) && !self.volumes.check_reachable(&self.db, vol) {
+                warn!("Volume '{}' is offline", vol.name);
+                return Err(Status::unavailable(format!(
+                    "Storage volume '{}' is offline!",
+                    vol.name
+                )));
+            }
+
+            pending.push_back((vol.path.join(&chunk.file_path), chunk.chunk_size as u64));
+        }
+
+        info!(
+            "Reading chunked file \"{}\" ({} chunks)",
+            item.file_name,
+            pending.len()
+        );
+
+        Ok(BlobReader {
+            current: None,
+            pending,
+        })
+    }
+}
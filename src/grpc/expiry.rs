@@ -0,0 +1,228 @@
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use dotenvy::dotenv;
+use std::{env, sync::Arc, time::Duration};
+use tokio::time::MissedTickBehavior;
+use tonic::Status;
+use tracing::{error, info, warn};
+
+use super::{file_info, FileStorage};
+use crate::{
+    audit::Caller,
+    storage::{expiration::At, Expiration, ExtendTtlRequest, FileInfo, ServiceMode, UploadHeader},
+};
+
+/// Longest TTL accepted, about a hundred years.
+const MAX_TTL_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// How often and in which batches expired files are deleted.
+#[derive(Clone, Copy, Debug)]
+struct ReaperConfig {
+    interval: Duration,
+    batch_size: i64,
+}
+
+impl ReaperConfig {
+    /// Reads `EXPIRY_REAP_INTERVAL_SECS` (0 disables the reaper) and
+    /// `EXPIRY_REAP_BATCH_SIZE`.
+    fn from_env() -> Option<Self> {
+        dotenv().ok();
+
+        let interval: u64 = env::var("EXPIRY_REAP_INTERVAL_SECS")
+            .unwrap_or("60".to_owned())
+            .parse()
+            .unwrap_or_else(|_| {
+                error!(
+                    "'EXPIRY_REAP_INTERVAL_SECS' - should be an integer value in range: [0;{}]",
+                    u64::MAX
+                );
+                panic!()
+            });
+
+        let batch_size: i64 = env::var("EXPIRY_REAP_BATCH_SIZE")
+            .unwrap_or("500".to_owned())
+            .parse()
+            .ok()
+            .filter(|size| *size >= 1)
+            .unwrap_or_else(|| {
+                error!(
+                    "'EXPIRY_REAP_BATCH_SIZE' - should be an integer value in range: [1;{}]",
+                    i64::MAX
+                );
+                panic!()
+            });
+
+        (interval > 0).then(|| Self {
+            interval: Duration::from_secs(interval),
+            batch_size,
+        })
+    }
+}
+
+impl FileStorage {
+    /// Starts deleting expired files in the background.
+    pub fn spawn_reaper(self: Arc<Self>) {
+        let Some(config) = ReaperConfig::from_env() else {
+            info!("Reaper of expired files is disabled");
+            return;
+        };
+
+        info!(
+            "Expired files are deleted every {}s, {} at a time",
+            config.interval.as_secs(),
+            config.batch_size
+        );
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(config.interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                self.reap_expired(config.batch_size).await;
+            }
+        });
+    }
+
+    /// Deletes the expired files no ref points at, in batches. Files on an
+    /// unavailable volume are left for a later run.
+    async fn reap_expired(&self, batch_size: i64) {
+        if self.mode.read().unwrap().mode == ServiceMode::Maintenance {
+            return;
+        }
+
+        let mut after_id = 0;
+        let mut removed_count = 0;
+        loop {
+            let batch = match self.db.get_expired(after_id, batch_size) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    error!("Error during loading expired files from DB! Error: {}", &e);
+                    return;
+                }
+            };
+            let Some(last) = batch.last() else {
+                break;
+            };
+            after_id = last.id;
+
+            let ids: Vec<i32> = batch
+                .iter()
+                .filter(|item| item.chunked || self.blob_path(item).is_ok())
+                .map(|item| item.id)
+                .collect();
+            let removed = match self.db.remove_expired(&ids) {
+                Ok(removed) => removed,
+                Err(e) => {
+                    error!("Error during removing expired files from DB! Error: {}", &e);
+                    return;
+                }
+            };
+
+            for item in removed {
+                let result = self.remove_blob(&item).await;
+                match &result {
+                    Ok(_) => removed_count += 1,
+                    Err(status) => warn!(
+                        "Couldn't remove expired file {}: {}",
+                        item.file_hash,
+                        status.message()
+                    ),
+                }
+                self.audit.record(
+                    "Expire",
+                    &Caller::system(),
+                    Some(&item.file_hash),
+                    0,
+                    &result,
+                );
+            }
+
+            if (batch.len() as i64) < batch_size {
+                break;
+            }
+        }
+
+        if removed_count > 0 {
+            info!("Removed {} expired files", removed_count);
+        }
+    }
+
+    /// Sets a new expiration on the stored file with the given hash.
+    pub(super) fn extend_ttl(&self, request: ExtendTtlRequest) -> Result<FileInfo, Status> {
+        let expiration = expiration_time(request.expiration.as_ref(), Utc::now())?;
+        let file_hash = request.file_hash.to_ascii_lowercase();
+
+        let updated = self
+            .db
+            .set_expiration(&file_hash, expiration)
+            .map_err(|e| {
+                error!("Error during updating expiration in DB! Error: {}", &e);
+                Status::internal("Internal service error!")
+            })?;
+
+        match updated.first() {
+            Some(item) => {
+                match expiration {
+                    Some(at) => info!("{} now expires at {}", file_hash, at),
+                    None => info!("{} no longer expires", file_hash),
+                }
+                Ok(file_info(item, item.file_size.unwrap_or(0) as u64))
+            }
+            None => Err(Status::not_found("Could not found such hash!")),
+        }
+    }
+}
+
+/// Turns a TTL in an upload header into an absolute time, so it counts from
+/// the start of the upload.
+pub(super) fn resolve_expiration(header: &mut UploadHeader) -> Result<(), Status> {
+    if let Some(at) = expiration_time(header.expiration.as_ref(), Utc::now())? {
+        header.expiration = Some(Expiration {
+            at: Some(At::ExpiresAt(at.and_utc().timestamp())),
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns when a file uploaded with this expiration expires. Unlike
+/// `expiration_time` the time may have passed already, if the upload took
+/// longer than its TTL.
+pub(super) fn expires_at(expiration: Option<&Expiration>) -> Option<NaiveDateTime> {
+    let at = match expiration?.at? {
+        At::ExpiresAt(secs) => DateTime::from_timestamp(secs, 0)?,
+        At::TtlSeconds(ttl) => Utc::now() + TimeDelta::seconds(ttl.min(MAX_TTL_SECS) as i64),
+    };
+
+    Some(at.naive_utc())
+}
+
+/// Checks an expiration and returns it as a UTC time, a TTL counts from
+/// `start`.
+pub(super) fn expiration_time(
+    expiration: Option<&Expiration>,
+    start: DateTime<Utc>,
+) -> Result<Option<NaiveDateTime>, Status> {
+    let Some(expiration) = expiration else {
+        return Ok(None);
+    };
+
+    let at = match expiration.at {
+        Some(At::ExpiresAt(secs)) => DateTime::from_timestamp(secs, 0)
+            .filter(|at| *at > start)
+            .ok_or_else(|| Status::invalid_argument("Expiration should be in the future!"))?,
+        Some(At::TtlSeconds(ttl)) if ttl == 0 || ttl > MAX_TTL_SECS => {
+            return Err(Status::invalid_argument(format!(
+                "TTL should be in range: [1;{}] seconds!",
+                MAX_TTL_SECS
+            )))
+        }
+        Some(At::TtlSeconds(ttl)) => start + TimeDelta::seconds(ttl as i64),
+        None => {
+            return Err(Status::invalid_argument(
+                "Expiration should have a time or a TTL!",
+            ))
+        }
+    };
+
+    Ok(Some(at.naive_utc()))
+}
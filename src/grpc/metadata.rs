@@ -0,0 +1,151 @@
+use tonic::Status;
+use tracing::{error, info, warn};
+
+use super::{file_info, FileStorage};
+use crate::storage::{FileInfo, ListFilesRequest, ListFilesResponse, UpdateMetadataRequest};
+
+/// Tags a file may have at most.
+const MAX_TAGS: usize = 64;
+
+/// Longest tag accepted, in bytes.
+const MAX_TAG_LEN: usize = 256;
+
+/// Files returned by one `ListFiles` call at most.
+const MAX_LIST_FILES: u32 = 1000;
+
+impl FileStorage {
+    /// Replaces the metadata and tags of a file, unless it was updated since
+    /// the generation the request is based on.
+    pub(super) fn update_metadata(
+        &self,
+        request: UpdateMetadataRequest,
+    ) -> Result<FileInfo, Status> {
+        validate_tags(&request.tags)?;
+        if request.generation == 0 {
+            return Err(Status::invalid_argument(
+                "Generation of the file should be set!",
+            ));
+        }
+
+        let file_hash = request.file_hash.to_ascii_lowercase();
+        let Some(item) = self.db.get_file_by_hash(file_hash.clone()) else {
+            return Err(Status::not_found("Could not found such hash!"));
+        };
+
+        let updated = self.db.update_metadata(
+            item.id,
+            request.generation as i64,
+            serde_json::to_value(request.metadata).unwrap_or_default(),
+            &normalize_tags(request.tags),
+        );
+        match updated {
+            Ok(Some(item)) => {
+                info!(
+                    "Metadata of {} updated, generation {}",
+                    item.file_hash, item.generation
+                );
+                Ok(file_info(&item, item.file_size.unwrap_or(0) as u64))
+            }
+            Ok(None) => {
+                warn!("Metadata of {} changed meanwhile", file_hash);
+                let current = self
+                    .db
+                    .get_file_by_hash(file_hash)
+                    .map(|item| item.generation)
+                    .unwrap_or_default();
+                Err(Status::aborted(format!(
+                    "File was updated meanwhile, its generation is {}!",
+                    current
+                )))
+            }
+            Err(diesel::result::Error::NotFound) => {
+                Err(Status::not_found("Could not found such hash!"))
+            }
+            Err(e) => {
+                error!("Error during updating metadata in DB! Error: {}", &e);
+                Err(Status::internal("Internal service error!"))
+            }
+        }
+    }
+
+    /// Lists files by tags and metadata, a page at a time.
+    pub(super) fn list_files(
+        &self,
+        request: ListFilesRequest,
+    ) -> Result<ListFilesResponse, Status> {
+        let limit = match request.limit {
+            0 => MAX_LIST_FILES,
+            limit => limit.min(MAX_LIST_FILES),
+        };
+        let after_id = match request.page_token.as_str() {
+            "" => 0,
+            token => token
+                .parse()
+                .map_err(|_| Status::invalid_argument("Invalid page token!"))?,
+        };
+        let metadata_key = Some(request.metadata_key.as_str()).filter(|key| !key.is_empty());
+        if metadata_key.is_none() && request.metadata_value.is_some() {
+            return Err(Status::invalid_argument("Metadata value requires a key!"));
+        }
+
+        let items = self
+            .db
+            .list_files(
+                &request.tags,
+                metadata_key,
+                request.metadata_value.as_deref(),
+                after_id,
+                limit as i64,
+            )
+            .map_err(|e| {
+                error!("Error during listing files from DB! Error: {}", &e);
+                Status::internal("Internal service error!")
+            })?;
+
+        let next_page_token = match items.last() {
+            Some(last) if items.len() == limit as usize => last.id.to_string(),
+            _ => String::new(),
+        };
+
+        Ok(ListFilesResponse {
+            files: items
+                .iter()
+                .map(|item| file_info(item, item.file_size.unwrap_or(0) as u64))
+                .collect(),
+            next_page_token,
+        })
+    }
+}
+
+/// Drops repeated tags, keeping the first occurrence.
+pub(super) fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut unique = Vec::with_capacity(tags.len());
+    for tag in tags {
+        if !unique.contains(&tag) {
+            unique.push(tag);
+        }
+    }
+
+    unique
+}
+
+pub(super) fn validate_tags(tags: &[String]) -> Result<(), Status> {
+    if tags.len() > MAX_TAGS {
+        return Err(Status::invalid_argument(format!(
+            "A file should not have more than {} tags!",
+            MAX_TAGS
+        )));
+    }
+
+    for tag in tags {
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN || tag.chars().any(char::is_control) {
+            warn!("Rejected tag: {:?}", tag);
+            return Err(Status::invalid_argument(format!(
+                "Tags should have 1 to {} bytes without control characters!",
+                MAX_TAG_LEN
+            )));
+        }
+    }
+
+    Ok(())
+}
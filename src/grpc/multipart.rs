@@ -0,0 +1,463 @@
+use std::{collections::HashMap, path::Path};
+use tokio::{
+    fs::{create_dir_all, remove_dir_all, remove_file, File},
+    io::AsyncReadExt,
+};
+use tonic::{Status, Streaming};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::{
+    expires_at, normalize_tags, resolve_expiration, retain_until, unique_rel_path, validate_hash,
+    validate_header, versioned_key, FileStorage, PendingUpload,
+};
+use crate::{
+    audit::Caller,
+    models::{
+        MultipartUpload, NewMultipartPart, NewMultipartUpload, NewStoreItem, NewVersionedKey,
+        StoreItem,
+    },
+    ratelimit::ClientLimit,
+    storage::{
+        upload_part_request::Data, CompleteMultipartUploadRequest, UploadHeader, UploadPartHeader,
+        UploadPartRequest, UploadPartResponse,
+    },
+    volumes::Volume,
+};
+
+/// Directory on each volume holding the parts of unfinished uploads.
+const MULTIPART_DIR: &str = ".multipart";
+
+/// Highest part number accepted, part numbers start at 1.
+const MAX_PART_NUMBER: u32 = 10_000;
+
+impl FileStorage {
+    /// Registers a new multipart upload and picks the volume its parts and the
+    /// assembled file will be written to. Returns the upload id.
+    pub(super) async fn create_multipart(
+        &self,
+        mut header: UploadHeader,
+    ) -> Result<String, Status> {
+        validate_header(&header)?;
+        resolve_expiration(&mut header)?;
+        self.resolve_retention(&mut header)?;
+
+        let vol = match self
+            .volumes
+            .place(&self.db, &header.file_name, header.size.unwrap_or(0))
+        {
+            Some(vol) => vol,
+            None => {
+                error!("No storage volume available for writing!");
+                return Err(self.on_disk_full());
+            }
+        };
+
+        let upload_id = Uuid::new_v4().to_string();
+        let parts_dir = vol.path.join(MULTIPART_DIR).join(&upload_id);
+        create_dir_all(&parts_dir).await.map_err(|e| {
+            error!("Failed to create directory for parts: {}", &e);
+            self.volumes.report_io_error(&self.db, vol, &e, true);
+            Status::internal(format!("Failed to create directory for parts: {}", e))
+        })?;
+
+        let key = versioned_key(&header);
+        let result = self.db.create_multipart_upload(&NewMultipartUpload {
+            upload_id: upload_id.clone(),
+            file_name: header.file_name,
+            file_size: header.size.map(|size| size as i64),
+            content_type: Some(header.content_type).filter(|ct| !ct.is_empty()),
+            metadata: serde_json::to_value(header.metadata).unwrap_or_default(),
+            expected_hash: Some(header.expected_hash).filter(|hash| !hash.is_empty()),
+            root_id: vol.id,
+            namespace: key.as_ref().map(|key| key.namespace.clone()),
+            file_key: key.as_ref().map(|key| key.file_key.clone()),
+            keep_versions: key.and_then(|key| key.keep_versions),
+            expires_at: expires_at(header.expiration.as_ref()),
+            retain_until: retain_until(header.retain_until),
+            tags: normalize_tags(header.tags),
+        });
+
+        if let Err(e) = result {
+            error!("Error during adding multipart upload to DB! Error: {}", &e);
+            remove_parts_dir(&parts_dir).await;
+            return Err(Status::internal(format!("{}", e)));
+        }
+
+        info!("Multipart upload {} started on '{}'", upload_id, vol.name);
+        Ok(upload_id)
+    }
+
+    pub(super) async fn upload_part(
+        &self,
+        mut stream: Streaming<UploadPartRequest>,
+        caller: &Caller,
+    ) -> Result<UploadPartResponse, Status> {
+        let header = match stream.message().await? {
+            Some(UploadPartRequest {
+                data: Some(Data::Header(header)),
+            }) => header,
+            _ => {
+                warn!("Part upload didn't start with a header!");
+                return Err(Status::invalid_argument(
+                    "Part header should be sent before chunks!",
+                ));
+            }
+        };
+
+        if header.part_number < 1 || header.part_number > MAX_PART_NUMBER {
+            return Err(Status::invalid_argument(format!(
+                "Part number should be in range: [1;{}]",
+                MAX_PART_NUMBER
+            )));
+        }
+        validate_hash(&header.expected_hash)?;
+
+        let upload = self.find_multipart(&header.upload_id)?;
+        let limit = self.rate_limits.client(caller, upload.namespace.as_deref());
+        limit.check_request()?;
+        let vol = self.multipart_volume(&upload)?;
+
+        // Each attempt at a part gets its own file, so a concurrent one for
+        // the same part number can't truncate it or remove it as replaced
+        let rel_path = format!(
+            "{}/{}/{}_{}.part",
+            MULTIPART_DIR,
+            upload.upload_id,
+            header.part_number,
+            Uuid::new_v4().simple()
+        );
+        let mut pending = self.open_pending(vol, rel_path).await?;
+
+        match self
+            .receive_part(&mut stream, &header, &upload, &limit, &mut pending)
+            .await
+        {
+            Ok(part_hash) => Ok(UploadPartResponse {
+                upload_id: upload.upload_id,
+                part_number: header.part_number,
+                part_hash,
+                size: pending.written,
+            }),
+            Err(status) => {
+                pending.discard().await;
+                Err(status)
+            }
+        }
+    }
+
+    async fn receive_part(
+        &self,
+        stream: &mut Streaming<UploadPartRequest>,
+        header: &UploadPartHeader,
+        upload: &MultipartUpload,
+        limit: &ClientLimit,
+        pending: &mut PendingUpload<'_>,
+    ) -> Result<String, Status> {
+        while let Some(message) = stream.message().await? {
+            match message.data {
+                Some(Data::Header(_)) => {
+                    warn!("Part header was sent twice!");
+                    return Err(Status::invalid_argument("Part header was already sent!"));
+                }
+                Some(Data::Chunk(chunk_data)) => {
+                    if let Some(size) = header.size {
+                        if pending.written + chunk_data.len() as u64 > size {
+                            warn!("Part exceeds its declared size of {} bytes", size);
+                            return Err(Status::invalid_argument(format!(
+                                "Part exceeds its declared size of {} bytes!",
+                                size
+                            )));
+                        }
+                    }
+
+                    limit.throttle(chunk_data.len()).await?;
+                    self.write_chunk(pending, &chunk_data).await?;
+                }
+                None => {}
+            }
+        }
+
+        let part_hash = self
+            .finish_pending(pending, header.size, &header.expected_hash)
+            .await?;
+
+        let replaced = self
+            .db
+            .upsert_multipart_part(&NewMultipartPart {
+                multipart_id: upload.id,
+                part_number: header.part_number as i32,
+                part_hash: part_hash.clone(),
+                part_size: pending.written as i64,
+                file_path: pending.rel_path.clone(),
+            })
+            .map_err(|e| {
+                error!("Error during adding part to DB! Error: {}", &e);
+                match e {
+                    diesel::result::Error::DatabaseError(
+                        diesel::result::DatabaseErrorKind::ForeignKeyViolation,
+                        _,
+                    ) => Status::not_found("Multipart upload not found!"),
+                    e => Status::internal(format!("{}", e)),
+                }
+            })?;
+
+        if let Some(old) = replaced {
+            let old_path = pending.volume.path.join(&old.file_path);
+            if let Err(e) = remove_file(&old_path).await {
+                warn!(
+                    "Couldn't remove replaced part \"{}\": {}",
+                    old_path.display(),
+                    e
+                );
+            }
+        }
+
+        info!(
+            "Part {} of multipart upload {} received ({} bytes)",
+            header.part_number, upload.upload_id, pending.written
+        );
+        Ok(part_hash)
+    }
+
+    /// Concatenates the listed parts into a new file and turns the upload into
+    /// a regular store item.
+    pub(super) async fn complete_multipart(
+        &self,
+        request: CompleteMultipartUploadRequest,
+        caller: &Caller,
+    ) -> Result<StoreItem, Status> {
+        let upload = self.find_multipart(&request.upload_id)?;
+        self.rate_limits
+            .client(caller, upload.namespace.as_deref())
+            .check_request()?;
+
+        if request.parts.is_empty() {
+            return Err(Status::invalid_argument("At least one part is required!"));
+        }
+        if request
+            .parts
+            .windows(2)
+            .any(|pair| pair[0].part_number >= pair[1].part_number)
+        {
+            return Err(Status::invalid_argument(
+                "Parts should be listed in ascending part number order!",
+            ));
+        }
+
+        let mut stored: HashMap<i32, _> = self
+            .db
+            .get_multipart_parts(upload.id)
+            .map_err(|e| {
+                error!("Error during loading parts from DB! Error: {}", &e);
+                Status::internal("Internal service error!")
+            })?
+            .into_iter()
+            .map(|part| (part.part_number, part))
+            .collect();
+
+        let mut parts = Vec::with_capacity(request.parts.len());
+        for listed in &request.parts {
+            let part = match stored.remove(&(listed.part_number as i32)) {
+                Some(part) if part.part_hash.eq_ignore_ascii_case(&listed.part_hash) => part,
+                Some(_) => {
+                    return Err(Status::invalid_argument(format!(
+                        "Hash of part {} doesn't match the uploaded part!",
+                        listed.part_number
+                    )))
+                }
+                None => {
+                    return Err(Status::invalid_argument(format!(
+                        "Part {} was not uploaded!",
+                        listed.part_number
+                    )))
+                }
+            };
+            parts.push(part);
+        }
+
+        let total: i64 = parts.iter().map(|part| part.part_size).sum();
+        if let Some(size) = upload.file_size {
+            if total != size {
+                return Err(Status::invalid_argument(format!(
+                    "Parts contain {} bytes, but {} were declared!",
+                    total, size
+                )));
+            }
+        }
+
+        let vol = self.multipart_volume(&upload)?;
+        let rel_path = unique_rel_path(vol, |stamp| format!("{}_{}", stamp, upload.file_name));
+        let mut pending = self.open_pending(vol, rel_path).await?;
+        self.attach_tree(&mut pending).await?;
+
+        let assembled = async {
+            for part in &parts {
+                self.append_part(&mut pending, &vol.path.join(&part.file_path))
+                    .await?;
+            }
+
+            let file_hash = self
+                .finish_pending(
+                    &mut pending,
+                    upload.file_size.map(|size| size as u64),
+                    upload.expected_hash.as_deref().unwrap_or_default(),
+                )
+                .await?;
+
+            self.db
+                .complete_multipart_upload(
+                    upload.id,
+                    &NewStoreItem {
+                        file_name: upload.file_name.clone(),
+                        file_path: pending.rel_path.clone(),
+                        file_hash,
+                        root_id: Some(vol.id),
+                        file_size: Some(pending.written as i64),
+                        content_type: upload.content_type.clone(),
+                        metadata: upload.metadata.clone(),
+                        chunked: false,
+                        blake3_hash: pending
+                            .tree
+                            .as_ref()
+                            .and_then(|tree| tree.root_hash.clone()),
+                        outboard_path: pending.tree.as_ref().map(|tree| tree.rel_path.clone()),
+                        expires_at: upload.expires_at,
+                        retain_until: upload.retain_until,
+                        tags: upload.tags.clone(),
+                    },
+                    upload.file_key.is_none(),
+                )
+                .map_err(|e| match e {
+                    diesel::result::Error::NotFound => {
+                        Status::not_found("Multipart upload not found!")
+                    }
+                    e => {
+                        error!("Error during adding new item to DB! Error: {}", &e);
+                        Status::internal(format!("{}", e))
+                    }
+                })
+        }
+        .await;
+
+        match &assembled {
+            Ok(item) => {
+                info!(
+                    "Multipart upload {} completed from {} parts: {}",
+                    upload.upload_id,
+                    parts.len(),
+                    item.file_hash
+                );
+                remove_parts_dir(&vol.path.join(MULTIPART_DIR).join(&upload.upload_id)).await;
+            }
+            Err(_) => pending.discard().await,
+        }
+
+        let item = match upload.file_key {
+            Some(file_key) => {
+                let key = NewVersionedKey {
+                    namespace: upload.namespace.unwrap_or_default(),
+                    file_key,
+                    latest_version: 1,
+                    keep_versions: upload.keep_versions,
+                };
+                self.record_version(assembled?, key).await?
+            }
+            None => assembled?,
+        };
+        Ok(item)
+    }
+
+    /// Forgets a multipart upload and removes its parts.
+    pub(super) async fn abort_multipart(&self, upload_id: &str) -> Result<(), Status> {
+        let upload = self.find_multipart(upload_id)?;
+
+        self.db
+            .remove_multipart_upload(upload.id)
+            .map_err(|e| match e {
+                diesel::result::Error::NotFound => Status::not_found("Multipart upload not found!"),
+                e => {
+                    error!(
+                        "Error during removing multipart upload from DB! Error: {}",
+                        &e
+                    );
+                    Status::internal("Internal service error!")
+                }
+            })?;
+
+        if let Some(vol) = self.volumes.get(upload.root_id) {
+            remove_parts_dir(&vol.path.join(MULTIPART_DIR).join(&upload.upload_id)).await;
+        }
+
+        info!("Multipart upload {} aborted", upload.upload_id);
+        Ok(())
+    }
+
+    fn find_multipart(&self, upload_id: &str) -> Result<MultipartUpload, Status> {
+        self.db.get_multipart_upload(upload_id).ok_or_else(|| {
+            warn!("Could not found multipart upload: {}", upload_id);
+            Status::not_found("Multipart upload not found!")
+        })
+    }
+
+    /// Returns the volume chosen for an upload, which has to be reachable.
+    fn multipart_volume(&self, upload: &MultipartUpload) -> Result<&Volume, Status> {
+        let vol = self.volumes.get(upload.root_id).ok_or_else(|| {
+            error!(
+                "Volume {} of multipart upload {} is not configured!",
+                upload.root_id, upload.upload_id
+            );
+            Status::unavailable("Storage volume of the upload is not available!")
+        })?;
+
+        if !self.volumes.check_reachable(&self.db, vol) {
+            warn!("Volume '{}' is offline", vol.name);
+            return Err(Status::unavailable(format!(
+                "Storage volume '{}' is offline!",
+                vol.name
+            )));
+        }
+
+        Ok(vol)
+    }
+
+    #[instrument(name = "fs.append", skip_all, fields(path = %path.display()))]
+    async fn append_part(
+        &self,
+        pending: &mut PendingUpload<'_>,
+        path: &Path,
+    ) -> Result<(), Status> {
+        let mut part = File::open(path).await.map_err(|e| {
+            error!("Failed to open part \"{}\": {}", path.display(), &e);
+            self.volumes
+                .report_io_error(&self.db, pending.volume, &e, false);
+            Status::internal("Failed to read uploaded part")
+        })?;
+
+        let mut buffer = vec![0; self.chunk_size as usize];
+        loop {
+            let bytes_read = part.read(&mut buffer).await.map_err(|e| {
+                error!("Failed to read part \"{}\": {}", path.display(), &e);
+                self.volumes
+                    .report_io_error(&self.db, pending.volume, &e, false);
+                Status::internal("Failed to read uploaded part")
+            })?;
+
+            if bytes_read == 0 {
+                return Ok(());
+            }
+
+            self.write_chunk(pending, &buffer[..bytes_read]).await?;
+        }
+    }
+}
+
+async fn remove_parts_dir(path: &Path) {
+    if let Err(e) = remove_dir_all(path).await {
+        warn!(
+            "Couldn't remove parts directory \"{}\": {}",
+            path.display(),
+            e
+        );
+    }
+}
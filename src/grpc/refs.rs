@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use tonic::Status;
+use tracing::{error, info, warn};
+
+use super::{validate_hash, FileStorage};
+use crate::{
+    models::{FileRef, NewFileRef},
+    storage::{ListRefsRequest, Ref, SetRefRequest},
+};
+
+/// Longest ref name accepted, in bytes.
+const MAX_REF_LEN: usize = 1024;
+
+/// Refs returned by one `ListRefs` call at most.
+const MAX_LIST_REFS: u32 = 1000;
+
+impl FileStorage {
+    /// Points a ref at a stored file, atomically checking its current target
+    /// if the request has an expected hash.
+    pub(super) fn set_ref(&self, request: SetRefRequest) -> Result<Ref, Status> {
+        validate_ref_name(&request.name)?;
+        if request.file_hash.is_empty() {
+            return Err(Status::invalid_argument("File hash should not be empty!"));
+        }
+        let file_hash = ref_target(&request.file_hash)?;
+        let expected = request
+            .expected_hash
+            .as_deref()
+            .map(ref_target)
+            .transpose()?;
+
+        let new_ref = NewFileRef {
+            name: request.name,
+            file_hash,
+        };
+        match self.db.set_ref(&new_ref, expected.as_deref()) {
+            Ok(Some(stored)) => {
+                info!("Ref \"{}\" -> {}", stored.name, stored.file_hash);
+                Ok(ref_message(stored))
+            }
+            Ok(None) => {
+                warn!("Ref \"{}\" was not set, it changed meanwhile", new_ref.name);
+                Err(self.ref_conflict(&new_ref.name))
+            }
+            Err(diesel::result::Error::NotFound) => Err(Status::not_found(format!(
+                "Could not found such hash: {}",
+                new_ref.file_hash
+            ))),
+            Err(e) => {
+                error!("Error during setting ref in DB! Error: {}", &e);
+                Err(Status::internal("Internal service error!"))
+            }
+        }
+    }
+
+    pub(super) fn get_ref(&self, name: &str) -> Result<Ref, Status> {
+        validate_ref_name(name)?;
+
+        self.db
+            .get_ref(name)
+            .map(ref_message)
+            .ok_or_else(|| Status::not_found(format!("Could not found ref \"{}\"!", name)))
+    }
+
+    pub(super) fn delete_ref(&self, name: &str, expected: Option<&str>) -> Result<(), Status> {
+        validate_ref_name(name)?;
+        let expected = expected.map(ref_target).transpose()?;
+
+        match self.db.remove_ref(name, expected.as_deref()) {
+            Ok(Some(removed)) => {
+                info!("Ref \"{}\" removed ({})", removed.name, removed.file_hash);
+                Ok(())
+            }
+            Ok(None) if expected.is_some() && self.db.get_ref(name).is_some() => {
+                warn!("Ref \"{}\" was not removed, it changed meanwhile", name);
+                Err(self.ref_conflict(name))
+            }
+            Ok(None) => Err(Status::not_found(format!(
+                "Could not found ref \"{}\"!",
+                name
+            ))),
+            Err(e) => {
+                error!("Error during removing ref from DB! Error: {}", &e);
+                Err(Status::internal("Internal service error!"))
+            }
+        }
+    }
+
+    pub(super) fn list_refs(&self, request: &ListRefsRequest) -> Result<Vec<Ref>, Status> {
+        let limit = match request.limit {
+            0 => MAX_LIST_REFS,
+            limit => limit.min(MAX_LIST_REFS),
+        };
+
+        self.db
+            .list_refs(&request.prefix, &request.start_after, limit as i64)
+            .map(|refs| refs.into_iter().map(ref_message).collect())
+            .map_err(|e| {
+                error!("Error during loading refs from DB! Error: {}", &e);
+                Status::internal("Internal service error!")
+            })
+    }
+
+    /// Returns the hash a ref points at.
+    pub(super) fn resolve_ref(&self, name: &str) -> Result<String, Status> {
+        Ok(self.get_ref(name)?.file_hash)
+    }
+
+    /// Returns the names of the refs pointing at any of the given hashes, per
+    /// hash. Referenced files aren't deleted.
+    pub(super) fn referencing_refs(
+        &self,
+        hashes: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, Status> {
+        let found = self.db.get_refs_to(hashes).map_err(|e| {
+            error!("Error during loading refs from DB! Error: {}", &e);
+            Status::internal("Internal service error!")
+        })?;
+
+        let mut referenced: HashMap<String, Vec<String>> = HashMap::new();
+        for stored in found {
+            referenced
+                .entry(stored.file_hash)
+                .or_default()
+                .push(stored.name);
+        }
+
+        Ok(referenced)
+    }
+
+    fn ref_conflict(&self, name: &str) -> Status {
+        match self.db.get_ref(name) {
+            Some(current) => {
+                Status::aborted(format!("Ref \"{}\" points at {}!", name, current.file_hash))
+            }
+            None => Status::aborted(format!("Ref \"{}\" doesn't exist!", name)),
+        }
+    }
+}
+
+/// The error for deleting a file that refs point at.
+pub(super) fn referenced(names: &[String]) -> Status {
+    Status::failed_precondition(format!("File is referenced by: {}", names.join(", ")))
+}
+
+fn ref_message(stored: FileRef) -> Ref {
+    Ref {
+        name: stored.name,
+        file_hash: stored.file_hash,
+        updated_at: stored.updated_at.and_utc().timestamp(),
+    }
+}
+
+/// Checks a hash a ref points (or is expected to point) at, lowercased.
+fn ref_target(hash: &str) -> Result<String, Status> {
+    validate_hash(hash)?;
+    Ok(hash.to_ascii_lowercase())
+}
+
+/// Accepts `/` separated names like `releases/app/latest`, without empty,
+/// `.` or `..` segments, whitespace or control characters.
+fn validate_ref_name(name: &str) -> Result<(), Status> {
+    if name.is_empty() {
+        return Err(Status::invalid_argument("Ref name should not be empty!"));
+    }
+
+    if name.len() > MAX_REF_LEN {
+        return Err(Status::invalid_argument(format!(
+            "Ref name should not exceed {} bytes!",
+            MAX_REF_LEN
+        )));
+    }
+
+    let valid = !name.chars().any(|c| c.is_whitespace() || c.is_control())
+        && name
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    if !valid {
+        warn!("Rejected ref name: {:?}", name);
+        return Err(Status::invalid_argument(format!(
+            "Invalid ref name \"{}\"!",
+            name
+        )));
+    }
+
+    Ok(())
+}
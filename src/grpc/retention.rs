@@ -0,0 +1,281 @@
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use dotenvy::dotenv;
+use serde_json::json;
+use std::env;
+use tonic::Status;
+use tracing::{error, info, warn};
+
+use super::{file_info, validate_hash, versions::MAX_KEY_LEN, FileStorage};
+use crate::{
+    models::{NewGovernanceAudit, StoreItem},
+    storage::{
+        FileInfo, NamespaceRetention, OverrideRetentionRequest, SetLegalHoldRequest,
+        SetNamespaceRetentionRequest, SetRetentionRequest, UploadHeader,
+    },
+};
+
+/// Longest retention accepted, about a hundred years.
+const MAX_RETENTION_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Whether an admin may override retention locks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) enum RetentionMode {
+    /// Admins may shorten or remove retention, each change is audited
+    Governance,
+    /// Nobody can shorten retention
+    Compliance,
+}
+
+impl RetentionMode {
+    /// Reads `RETENTION_MODE`, `governance` by default.
+    pub(super) fn from_env() -> Self {
+        dotenv().ok();
+
+        match env::var("RETENTION_MODE")
+            .unwrap_or("governance".to_owned())
+            .as_str()
+        {
+            "governance" => Self::Governance,
+            "compliance" => Self::Compliance,
+            _ => {
+                error!("'RETENTION_MODE' - should be 'governance' or 'compliance'");
+                panic!()
+            }
+        }
+    }
+}
+
+impl FileStorage {
+    /// Sets the retention of an upload to the later of the requested one and
+    /// the default of its namespace, counted from the start of the upload.
+    pub(super) fn resolve_retention(&self, header: &mut UploadHeader) -> Result<(), Status> {
+        let start = Utc::now();
+        let requested = match header.retain_until {
+            0 => None,
+            secs => Some(retention_time(secs, start)?),
+        };
+        let namespace = header
+            .key
+            .as_ref()
+            .map(|key| key.namespace.as_str())
+            .unwrap_or_default();
+        let default = self
+            .db
+            .get_namespace_retention(namespace)
+            .map(|secs| (start + TimeDelta::seconds(secs)).naive_utc());
+
+        header.retain_until = requested
+            .max(default)
+            .map(|at| at.and_utc().timestamp())
+            .unwrap_or_default();
+        Ok(())
+    }
+
+    /// Extends the retention of the stored file with the given hash.
+    pub(super) fn set_retention(&self, request: SetRetentionRequest) -> Result<FileInfo, Status> {
+        let file_hash = file_hash(&request.file_hash)?;
+        let until = retention_time(request.retain_until, Utc::now())?;
+
+        match self.db.extend_retention(&file_hash, until) {
+            Ok(Some(updated)) => {
+                info!("{} retained until {}", file_hash, until);
+                Ok(first_info(&updated))
+            }
+            Ok(None) => Err(Status::failed_precondition(
+                "Retention can only be extended!",
+            )),
+            Err(diesel::result::Error::NotFound) => {
+                Err(Status::not_found("Could not found such hash!"))
+            }
+            Err(e) => {
+                error!("Error during updating retention in DB! Error: {}", &e);
+                Err(Status::internal("Internal service error!"))
+            }
+        }
+    }
+
+    /// Places or releases a legal hold on the stored file with the given hash.
+    pub fn set_legal_hold(&self, request: SetLegalHoldRequest) -> Result<FileInfo, Status> {
+        let file_hash = file_hash(&request.file_hash)?;
+        let audit = NewGovernanceAudit {
+            action: if request.hold {
+                "legal_hold".to_owned()
+            } else {
+                "release_legal_hold".to_owned()
+            },
+            target: file_hash.clone(),
+            reason: audit_reason(request.reason)?,
+            details: json!({ "hold": request.hold }),
+        };
+
+        let updated = self
+            .db
+            .set_legal_hold(&file_hash, request.hold, audit)
+            .map_err(|e| governance_error(e, "legal hold"))?;
+
+        warn!(
+            "Legal hold of {} {}",
+            file_hash,
+            if request.hold { "placed" } else { "released" }
+        );
+        Ok(first_info(&updated))
+    }
+
+    /// Sets the retention of the stored file with the given hash to anything,
+    /// in governance mode only.
+    pub fn override_retention(
+        &self,
+        request: OverrideRetentionRequest,
+    ) -> Result<FileInfo, Status> {
+        if self.retention_mode == RetentionMode::Compliance {
+            return Err(Status::failed_precondition(
+                "Retention can't be overridden in compliance mode!",
+            ));
+        }
+
+        let file_hash = file_hash(&request.file_hash)?;
+        let until = match request.retain_until {
+            0 => None,
+            secs => Some(
+                DateTime::from_timestamp(secs, 0)
+                    .ok_or_else(|| Status::invalid_argument("Invalid retention time!"))?
+                    .naive_utc(),
+            ),
+        };
+        let audit = NewGovernanceAudit {
+            action: "override_retention".to_owned(),
+            target: file_hash.clone(),
+            reason: audit_reason(request.reason)?,
+            details: json!({ "retainUntil": request.retain_until }),
+        };
+
+        let updated = self
+            .db
+            .override_retention(&file_hash, until, audit)
+            .map_err(|e| governance_error(e, "retention"))?;
+
+        match until {
+            Some(at) => warn!("Retention of {} overridden, now until {}", file_hash, at),
+            None => warn!("Retention of {} removed", file_hash),
+        }
+        Ok(first_info(&updated))
+    }
+
+    /// Sets the default retention of the files uploaded to a namespace.
+    pub fn set_namespace_retention(
+        &self,
+        request: SetNamespaceRetentionRequest,
+    ) -> Result<NamespaceRetention, Status> {
+        if request.namespace.len() > MAX_KEY_LEN || request.namespace.contains('\0') {
+            return Err(Status::invalid_argument(format!(
+                "Namespace should have up to {} bytes without NUL characters!",
+                MAX_KEY_LEN
+            )));
+        }
+        if request.retention_seconds > MAX_RETENTION_SECS {
+            return Err(Status::invalid_argument(format!(
+                "Retention should not exceed {} seconds!",
+                MAX_RETENTION_SECS
+            )));
+        }
+
+        let retention = Some(request.retention_seconds as i64).filter(|secs| *secs > 0);
+        let audit = NewGovernanceAudit {
+            action: "namespace_retention".to_owned(),
+            target: request.namespace.clone(),
+            reason: audit_reason(request.reason)?,
+            details: json!({ "retentionSeconds": request.retention_seconds }),
+        };
+
+        self.db
+            .set_namespace_retention(&request.namespace, retention, audit)
+            .map_err(|e| {
+                error!(
+                    "Error during updating namespace retention in DB! Error: {}",
+                    &e
+                );
+                Status::internal("Internal service error!")
+            })?;
+
+        warn!(
+            "Default retention of namespace \"{}\" set to {}s",
+            request.namespace, request.retention_seconds
+        );
+        Ok(NamespaceRetention {
+            namespace: request.namespace,
+            retention_seconds: request.retention_seconds,
+        })
+    }
+}
+
+/// The error for deleting a stored file that is locked, if it is.
+pub(super) fn locked(item: &StoreItem) -> Option<Status> {
+    if item.legal_hold {
+        return Some(Status::failed_precondition("File is on legal hold!"));
+    }
+
+    item.retain_until
+        .filter(|until| *until > Utc::now().naive_utc())
+        .map(|until| {
+            Status::failed_precondition(format!(
+                "File is retained until {}!",
+                until.and_utc().timestamp()
+            ))
+        })
+}
+
+/// Converts the retention time of an upload, in unix seconds.
+pub(super) fn retain_until(secs: i64) -> Option<NaiveDateTime> {
+    DateTime::from_timestamp(secs, 0)
+        .filter(|_| secs != 0)
+        .map(|at| at.naive_utc())
+}
+
+/// Checks a requested retention time, in unix seconds.
+fn retention_time(secs: i64, start: DateTime<Utc>) -> Result<NaiveDateTime, Status> {
+    let at = DateTime::from_timestamp(secs, 0)
+        .filter(|at| *at > start)
+        .ok_or_else(|| Status::invalid_argument("Retention should end in the future!"))?;
+
+    if at > start + TimeDelta::seconds(MAX_RETENTION_SECS as i64) {
+        return Err(Status::invalid_argument(format!(
+            "Retention should not exceed {} seconds!",
+            MAX_RETENTION_SECS
+        )));
+    }
+
+    Ok(at.naive_utc())
+}
+
+fn file_hash(hash: &str) -> Result<String, Status> {
+    if hash.is_empty() {
+        return Err(Status::invalid_argument("File hash should not be empty!"));
+    }
+    validate_hash(hash)?;
+
+    Ok(hash.to_ascii_lowercase())
+}
+
+fn audit_reason(reason: String) -> Result<String, Status> {
+    match reason.trim() {
+        "" => Err(Status::invalid_argument(
+            "A reason is required for the audit record!",
+        )),
+        _ => Ok(reason),
+    }
+}
+
+fn governance_error(e: diesel::result::Error, what: &str) -> Status {
+    match e {
+        diesel::result::Error::NotFound => Status::not_found("Could not found such hash!"),
+        e => {
+            error!("Error during updating {} in DB! Error: {}", what, &e);
+            Status::internal("Internal service error!")
+        }
+    }
+}
+
+fn first_info(items: &[StoreItem]) -> FileInfo {
+    let item = &items[0];
+    file_info(item, item.file_size.unwrap_or(0) as u64)
+}
@@ -0,0 +1,200 @@
+use blake3::{hazmat::ChainingValue, Hash};
+use std::{io::SeekFrom, path::PathBuf};
+use tokio::{
+    fs::{create_dir_all, remove_file, File},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tonic::Status;
+use tracing::{error, info, warn};
+
+use super::FileStorage;
+use crate::{
+    bao::{ProofStep, TreeBuilder, NODE_SIZE},
+    models::StoreItem,
+    volumes::Volume,
+};
+
+/// Directory on each volume holding the hash trees of chunked files.
+pub(super) const OUTBOARD_DIR: &str = ".outboard";
+
+/// The hash tree of an upload in progress, written to an outboard file.
+pub(super) struct PendingTree<'a> {
+    pub(super) volume: &'a Volume,
+    path: PathBuf,
+    pub(super) rel_path: String,
+    file: File,
+    builder: Option<TreeBuilder>,
+    buffer: Vec<u8>,
+    pub(super) root_hash: Option<String>,
+}
+
+impl PendingTree<'_> {
+    /// Removes the outboard file of a failed upload.
+    pub(super) async fn discard(self) {
+        drop(self.file);
+        if let Err(e) = remove_file(&self.path).await {
+            warn!(
+                "Couldn't remove incomplete hash tree \"{}\": {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl FileStorage {
+    /// Creates the outboard file at `rel_path` on the given volume.
+    pub(super) async fn open_tree<'a>(
+        &self,
+        vol: &'a Volume,
+        rel_path: String,
+    ) -> Result<PendingTree<'a>, Status> {
+        let path = vol.path.join(&rel_path);
+
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).await.map_err(|e| {
+                error!("Failed to create directory for hash trees: {}", &e);
+                self.volumes.report_io_error(&self.db, vol, &e, true);
+                Status::internal(format!("Failed to create hash tree: {}", e))
+            })?;
+        }
+
+        let file = File::create(&path).await.map_err(|e| {
+            error!("Failed to create hash tree: {}", &e);
+            self.volumes.report_io_error(&self.db, vol, &e, true);
+            Status::internal(format!("Failed to create hash tree: {}", e))
+        })?;
+
+        Ok(PendingTree {
+            volume: vol,
+            path,
+            rel_path,
+            file,
+            builder: Some(TreeBuilder::new()),
+            buffer: Vec::new(),
+            root_hash: None,
+        })
+    }
+
+    pub(super) async fn write_tree(
+        &self,
+        tree: &mut PendingTree<'_>,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        if let Some(builder) = tree.builder.as_mut() {
+            builder.update(data, &mut tree.buffer);
+        }
+        self.flush_tree(tree).await
+    }
+
+    /// Writes the last parent nodes and returns the BLAKE3 root hash.
+    pub(super) async fn finish_tree(&self, tree: &mut PendingTree<'_>) -> Result<String, Status> {
+        if let Some(builder) = tree.builder.take() {
+            let root_hash = builder.finalize(&mut tree.buffer);
+            tree.root_hash = Some(root_hash.to_hex().to_string());
+        }
+        self.flush_tree(tree).await?;
+
+        tree.file.sync_all().await.map_err(|e| {
+            error!("Failed to sync hash tree: {}", &e);
+            self.volumes
+                .report_io_error(&self.db, tree.volume, &e, true);
+            Status::internal(format!("Failed to sync hash tree: {}", e))
+        })?;
+
+        Ok(tree.root_hash.clone().unwrap_or_default())
+    }
+
+    async fn flush_tree(&self, tree: &mut PendingTree<'_>) -> Result<(), Status> {
+        if tree.buffer.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(e) = tree.file.write_all(&tree.buffer).await {
+            error!("Failed to write hash tree: {}", &e);
+            if e.kind() == std::io::ErrorKind::StorageFull {
+                return Err(self.on_disk_full());
+            }
+
+            self.volumes
+                .report_io_error(&self.db, tree.volume, &e, true);
+            return Err(Status::internal(format!(
+                "Failed to write hash tree: {}",
+                e
+            )));
+        }
+
+        tree.buffer.clear();
+        Ok(())
+    }
+
+    /// Resolves the outboard file of a stored item, if it has one.
+    pub(super) fn outboard_path(&self, item: &StoreItem) -> Option<PathBuf> {
+        let rel_path = item.outboard_path.as_ref()?;
+
+        match item.root_id {
+            Some(root) => Some(self.volumes.get(root)?.path.join(rel_path)),
+            None => Some(PathBuf::from(rel_path)),
+        }
+    }
+
+    /// Opens the outboard file of a stored item together with its root hash,
+    /// or returns `None` for items stored without a hash tree.
+    pub(super) async fn open_outboard(
+        &self,
+        item: &StoreItem,
+    ) -> Result<Option<(File, Hash)>, Status> {
+        let (Some(path), Some(root_hash)) = (self.outboard_path(item), &item.blake3_hash) else {
+            return Ok(None);
+        };
+
+        let root_hash = Hash::from_hex(root_hash).map_err(|e| {
+            error!("Invalid BLAKE3 hash of \"{}\": {}", item.file_name, e);
+            Status::internal("Internal service error!")
+        })?;
+
+        let file = File::open(&path).await.map_err(|e| {
+            error!("Failed to open hash tree \"{}\": {}", path.display(), &e);
+            Status::internal("Failed to open hash tree")
+        })?;
+
+        Ok(Some((file, root_hash)))
+    }
+
+    /// Removes the outboard file of a deleted item.
+    pub(super) async fn remove_outboard(&self, item: &StoreItem) {
+        if let Some(path) = self.outboard_path(item) {
+            match remove_file(&path).await {
+                Ok(_) => info!("Delete: {}", path.display()),
+                Err(e) => warn!("Couldn't remove hash tree \"{}\": {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// Reads the sibling hashes named by `steps` from an outboard file.
+pub(super) async fn read_proof(
+    outboard: &mut File,
+    steps: &[ProofStep],
+) -> std::io::Result<Vec<(ChainingValue, bool)>> {
+    let mut proof = Vec::with_capacity(steps.len());
+    let mut node = [0; NODE_SIZE as usize];
+
+    for step in steps {
+        outboard
+            .seek(SeekFrom::Start(step.node * NODE_SIZE))
+            .await?;
+        outboard.read_exact(&mut node).await?;
+
+        let half = if step.sibling_is_left {
+            &node[..32]
+        } else {
+            &node[32..]
+        };
+        let mut sibling = ChainingValue::default();
+        sibling.copy_from_slice(half);
+        proof.push((sibling, step.sibling_is_left));
+    }
+
+    Ok(proof)
+}
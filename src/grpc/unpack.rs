@@ -0,0 +1,634 @@
+use dotenvy::dotenv;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    env,
+    io::{self, Read},
+    rc::Rc,
+};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tonic::{Status, Streaming};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use zip::ZipArchive;
+
+use super::{validate_header, FileStorage, PendingUpload, Upload};
+use crate::{
+    audit::Caller,
+    models::StoreItem,
+    ratelimit::ClientLimit,
+    storage::{
+        upload_archive_request::Data, ArchiveEntryResult, ArchiveFormat, UploadArchiveRequest,
+        UploadArchiveResponse, UploadHeader,
+    },
+};
+
+/// Directory on each volume holding zip uploads until they are unpacked.
+const ARCHIVES_DIR: &str = ".archives";
+
+/// Unpacked bytes after which the compression ratio of an archive is checked.
+const RATIO_CHECK_MIN: u64 = 1024 * 1024;
+
+/// Limits on unpacked archives, against archive bombs.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct ArchiveLimits {
+    pub(super) max_entries: u64,
+    pub(super) max_bytes: u64,
+    pub(super) max_ratio: u64,
+}
+
+impl ArchiveLimits {
+    /// Reads `ARCHIVE_MAX_ENTRIES`, `ARCHIVE_MAX_BYTES` and `ARCHIVE_MAX_RATIO`.
+    pub(super) fn from_env() -> Self {
+        dotenv().ok();
+
+        Self {
+            max_entries: parse_limit("ARCHIVE_MAX_ENTRIES", 10_000),
+            max_bytes: parse_limit("ARCHIVE_MAX_BYTES", 10 * 1024 * 1024 * 1024),
+            max_ratio: parse_limit("ARCHIVE_MAX_RATIO", 100),
+        }
+    }
+}
+
+/// What the unpacker found in an archive, in archive order.
+enum Unpacked {
+    Entry { path: String, size: Option<u64> },
+    Data(Vec<u8>),
+    End,
+    Skipped(String),
+}
+
+impl FileStorage {
+    /// Unpacks an uploaded archive and stores each regular file in it as its
+    /// own item. Either all entries are stored or none.
+    pub(super) async fn upload_archive(
+        &self,
+        mut stream: Streaming<UploadArchiveRequest>,
+        caller: &Caller,
+    ) -> Result<UploadArchiveResponse, Status> {
+        let format = match stream.message().await? {
+            Some(UploadArchiveRequest {
+                data: Some(Data::Header(header)),
+            }) => ArchiveFormat::try_from(header.format)
+                .map_err(|_| Status::invalid_argument("Unknown archive format!"))?,
+            _ => {
+                warn!("Archive upload didn't start with a header!");
+                return Err(Status::invalid_argument(
+                    "Archive header should be sent before chunks!",
+                ));
+            }
+        };
+        let limit = self.rate_limits.client(caller, None);
+        limit.check_request()?;
+
+        let limits = self.archive_limits;
+        let chunk_size = self.chunk_size as usize;
+        let (events_tx, mut events) = mpsc::channel(16);
+
+        let mut spool = None;
+        let unpacker = match format {
+            ArchiveFormat::Zip => {
+                // The directory of a zip file is at its end, so it's read once
+                // the whole upload is on disk
+                let pending = self
+                    .spool_archive(&mut stream, limits.max_bytes, &limit)
+                    .await?;
+                let path = pending.path.clone();
+                spool = Some(pending);
+
+                tokio::task::spawn_blocking(move || {
+                    let file = std::fs::File::open(path).map_err(invalid_archive)?;
+                    Unpacker::new(limits, chunk_size, events_tx).unpack_zip(file)
+                })
+            }
+            ArchiveFormat::Tar | ArchiveFormat::TarZstd => {
+                let (data_tx, data_rx) = mpsc::channel(16);
+                tokio::spawn(forward_chunks(stream, limit, data_tx));
+
+                tokio::task::spawn_blocking(move || {
+                    let mut unpacker = Unpacker::new(limits, chunk_size, events_tx);
+                    let reader = ChannelReader {
+                        rx: data_rx,
+                        buffer: Vec::new(),
+                        pos: 0,
+                        received: unpacker.packed.clone(),
+                    };
+
+                    match format {
+                        ArchiveFormat::TarZstd => {
+                            let decoder = zstd::Decoder::new(reader).map_err(invalid_archive)?;
+                            unpacker.unpack_tar(decoder)
+                        }
+                        _ => unpacker.unpack_tar(reader),
+                    }
+                })
+            }
+        };
+
+        let mut stored = Vec::new();
+        let mut skipped = Vec::new();
+        let received = self
+            .store_entries(&mut events, &mut stored, &mut skipped)
+            .await;
+        drop(events);
+
+        let unpacked = unpacker.await.unwrap_or_else(|e| {
+            error!("Archive unpacker failed: {}", e);
+            Err(Status::internal("Internal service error!"))
+        });
+
+        if let Some(pending) = spool {
+            pending.discard().await;
+        }
+
+        // A failed store stops the unpacker, its error is the one to report
+        // The entries are announced together once all of them are stored
+        let announced = received.and(unpacked).and_then(|_| {
+            let items: Vec<StoreItem> = stored.iter().map(|(_, item)| item.clone()).collect();
+            self.db.announce_uploads(&items).map_err(|e| {
+                error!("Error during recording unpacked files! Error: {}", e);
+                Status::internal("Internal service error!")
+            })
+        });
+        if let Err(status) = announced {
+            self.roll_back_entries(stored).await;
+            return Err(status);
+        }
+
+        info!(
+            "Archive unpacked: {} files stored, {} entries skipped",
+            stored.len(),
+            skipped.len()
+        );
+
+        Ok(UploadArchiveResponse {
+            entries: stored
+                .into_iter()
+                .map(|(path, item)| ArchiveEntryResult {
+                    path,
+                    file_hash: item.file_hash,
+                    size: item.file_size.unwrap_or(0) as u64,
+                })
+                .collect(),
+            skipped,
+        })
+    }
+
+    /// Writes an uploaded zip file to the `.archives` folder of a volume.
+    async fn spool_archive(
+        &self,
+        stream: &mut Streaming<UploadArchiveRequest>,
+        max_bytes: u64,
+        limit: &ClientLimit,
+    ) -> Result<PendingUpload<'_>, Status> {
+        let vol = match self.volumes.place(&self.db, ARCHIVES_DIR, 0) {
+            Some(vol) => vol,
+            None => {
+                error!("No storage volume available for writing!");
+                return Err(self.on_disk_full());
+            }
+        };
+
+        tokio::fs::create_dir_all(vol.path.join(ARCHIVES_DIR))
+            .await
+            .map_err(|e| {
+                error!("Failed to create directory for archives: {}", &e);
+                self.volumes.report_io_error(&self.db, vol, &e, true);
+                Status::internal(format!("Failed to create directory for archives: {}", e))
+            })?;
+
+        let rel_path = format!("{}/{}.zip", ARCHIVES_DIR, Uuid::new_v4());
+        let mut pending = self.open_pending(vol, rel_path).await?;
+
+        let spooled = async {
+            while let Some(message) = stream.message().await? {
+                match message.data {
+                    Some(Data::Header(_)) => {
+                        warn!("Archive header was sent twice!");
+                        return Err(Status::invalid_argument("Archive header was already sent!"));
+                    }
+                    Some(Data::Chunk(chunk_data)) => {
+                        if pending.written + chunk_data.len() as u64 > max_bytes {
+                            return Err(too_large(max_bytes));
+                        }
+                        limit.throttle(chunk_data.len()).await?;
+                        self.write_chunk(&mut pending, &chunk_data).await?;
+                    }
+                    None => {}
+                }
+            }
+
+            pending.file.flush().await.map_err(|e| {
+                error!("Failed to write archive: {}", &e);
+                Status::internal(format!("Failed to write archive: {}", e))
+            })
+        }
+        .await;
+
+        match spooled {
+            Ok(()) => Ok(pending),
+            Err(status) => {
+                pending.discard().await;
+                Err(status)
+            }
+        }
+    }
+
+    /// Stores the entries reported by the unpacker until it's done. The
+    /// stored items are collected in `stored` even if storing fails.
+    async fn store_entries(
+        &self,
+        events: &mut mpsc::Receiver<Unpacked>,
+        stored: &mut Vec<(String, StoreItem)>,
+        skipped: &mut Vec<String>,
+    ) -> Result<(), Status> {
+        let mut current: Option<(String, UploadHeader, Upload)> = None;
+
+        let result = async {
+            while let Some(event) = events.recv().await {
+                match event {
+                    Unpacked::Entry { path, size } => {
+                        let mut header = UploadHeader {
+                            file_name: path.rsplit('/').next().unwrap_or_default().to_owned(),
+                            size,
+                            content_type: String::new(),
+                            metadata: HashMap::from([("archive_path".to_owned(), path.clone())]),
+                            expected_hash: String::new(),
+                            skip_if_exists: false,
+                            key: None,
+                            keep_versions: None,
+                            tags: Vec::new(),
+                            expiration: None,
+                            retain_until: 0,
+                        };
+                        validate_header(&header)?;
+                        self.resolve_retention(&mut header)?;
+
+                        let upload = self.start_upload(&header).await?;
+                        current = Some((path, header, upload));
+                    }
+                    Unpacked::Data(data) => {
+                        let Some((path, header, upload)) = current.as_mut() else {
+                            continue;
+                        };
+
+                        if let Some(size) = header.size {
+                            if upload.written() + data.len() as u64 > size {
+                                return Err(Status::invalid_argument(format!(
+                                    "Archive entry \"{}\" exceeds its declared size of {} bytes!",
+                                    path, size
+                                )));
+                            }
+                        }
+                        self.write_upload(upload, &data).await?;
+                    }
+                    Unpacked::End => {
+                        let Some((path, header, mut upload)) = current.take() else {
+                            continue;
+                        };
+
+                        match self.finish_upload(&mut upload, header, false).await {
+                            Ok(item) => stored.push((path, item)),
+                            Err(status) => {
+                                upload.discard().await;
+                                return Err(status);
+                            }
+                        }
+                    }
+                    Unpacked::Skipped(path) => skipped.push(path),
+                }
+            }
+
+            Ok(())
+        }
+        .await;
+
+        // Left open when the unpacker or the store failed within an entry
+        if let Some((_, _, upload)) = current {
+            upload.discard().await;
+        }
+
+        result
+    }
+
+    /// Removes the entries already stored from a failed archive upload. They
+    /// were never announced, so neither is their removal.
+    async fn roll_back_entries(&self, stored: Vec<(String, StoreItem)>) {
+        if stored.is_empty() {
+            return;
+        }
+
+        let ids: Vec<i32> = stored.iter().map(|(_, item)| item.id).collect();
+        match self.db.remove_items(&ids, false) {
+            Ok(removed) => {
+                for item in removed {
+                    if let Err(status) = self.remove_blob(&item).await {
+                        warn!(
+                            "Couldn't remove unpacked file {}: {}",
+                            item.file_hash,
+                            status.message()
+                        );
+                    }
+                }
+            }
+            Err(e) => error!("Error during removing unpacked files from DB! Error: {}", e),
+        }
+    }
+}
+
+/// Feeds the chunks of an archive upload to the unpacker.
+async fn forward_chunks(
+    mut stream: Streaming<UploadArchiveRequest>,
+    limit: ClientLimit,
+    tx: mpsc::Sender<Result<Vec<u8>, Status>>,
+) {
+    loop {
+        let data = match stream.message().await {
+            Ok(Some(UploadArchiveRequest {
+                data: Some(Data::Chunk(chunk_data)),
+            })) => limit.throttle(chunk_data.len()).await.map(|()| chunk_data),
+            Ok(Some(UploadArchiveRequest {
+                data: Some(Data::Header(_)),
+            })) => Err(Status::invalid_argument("Archive header was already sent!")),
+            Ok(Some(_)) => continue,
+            Ok(None) => return,
+            Err(status) => Err(status),
+        };
+
+        let failed = data.is_err();
+        // Fails once the unpacker stopped reading
+        if tx.send(data).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Walks the entries of an archive on a blocking thread and reports them to
+/// the async side, enforcing the archive limits on the actual data.
+struct Unpacker {
+    limits: ArchiveLimits,
+    chunk_size: usize,
+    events: mpsc::Sender<Unpacked>,
+    entries: u64,
+    unpacked: u64,
+    /// Archive bytes consumed so far
+    packed: Rc<Cell<u64>>,
+}
+
+impl Unpacker {
+    fn new(limits: ArchiveLimits, chunk_size: usize, events: mpsc::Sender<Unpacked>) -> Self {
+        Self {
+            limits,
+            chunk_size,
+            events,
+            entries: 0,
+            unpacked: 0,
+            packed: Rc::new(Cell::new(0)),
+        }
+    }
+
+    fn unpack_tar<R: Read>(&mut self, reader: R) -> Result<(), Status> {
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries().map_err(invalid_archive)? {
+            let entry = entry.map_err(invalid_archive)?;
+            let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let kind = entry.header().entry_type();
+
+            if kind.is_dir() || kind.is_pax_global_extensions() {
+                continue;
+            }
+            if !kind.is_file() && !kind.is_contiguous() {
+                self.send(Unpacked::Skipped(path))?;
+                continue;
+            }
+
+            let size = entry.header().size().ok();
+            self.unpack_entry(&path, size, entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn unpack_zip(&mut self, file: std::fs::File) -> Result<(), Status> {
+        let mut archive = ZipArchive::new(file).map_err(|e| invalid_archive(e.into()))?;
+        if archive.len() as u64 > self.limits.max_entries {
+            return Err(too_many_entries(self.limits.max_entries));
+        }
+
+        for index in 0..archive.len() {
+            let entry = archive
+                .by_index(index)
+                .map_err(|e| invalid_archive(e.into()))?;
+            let path = entry.name().to_owned();
+
+            if entry.is_dir() {
+                continue;
+            }
+            if !entry.is_file() {
+                self.send(Unpacked::Skipped(path))?;
+                continue;
+            }
+
+            self.packed.set(self.packed.get() + entry.compressed_size());
+            let size = entry.size();
+            self.unpack_entry(&path, Some(size), entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn unpack_entry<R: Read>(
+        &mut self,
+        raw_path: &str,
+        size: Option<u64>,
+        mut reader: R,
+    ) -> Result<(), Status> {
+        let path = entry_path(raw_path)?;
+
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            return Err(too_many_entries(self.limits.max_entries));
+        }
+
+        self.send(Unpacked::Entry { path, size })?;
+
+        loop {
+            let mut data = Vec::with_capacity(self.chunk_size);
+            let read = reader
+                .by_ref()
+                .take(self.chunk_size as u64)
+                .read_to_end(&mut data)
+                .map_err(invalid_archive)?;
+            if read == 0 {
+                break;
+            }
+
+            self.unpacked += read as u64;
+            if self.unpacked > self.limits.max_bytes {
+                return Err(too_large(self.limits.max_bytes));
+            }
+            if self.unpacked > RATIO_CHECK_MIN
+                && self.unpacked > self.packed.get().saturating_mul(self.limits.max_ratio)
+            {
+                warn!(
+                    "Archive unpacks to {} bytes from {}, rejected",
+                    self.unpacked,
+                    self.packed.get()
+                );
+                return Err(Status::invalid_argument(format!(
+                    "Archive compression ratio exceeds {}!",
+                    self.limits.max_ratio
+                )));
+            }
+
+            self.send(Unpacked::Data(data))?;
+        }
+
+        self.send(Unpacked::End)
+    }
+
+    fn send(&self, event: Unpacked) -> Result<(), Status> {
+        self.events
+            .blocking_send(event)
+            .map_err(|_| Status::cancelled("Archive upload was aborted"))
+    }
+}
+
+/// Reads the chunks of an archive upload as they arrive.
+struct ChannelReader {
+    rx: mpsc::Receiver<Result<Vec<u8>, Status>>,
+    buffer: Vec<u8>,
+    pos: usize,
+    received: Rc<Cell<u64>>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buffer.len() {
+            match self.rx.blocking_recv() {
+                Some(Ok(chunk)) => {
+                    self.received.set(self.received.get() + chunk.len() as u64);
+                    self.buffer = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(status)) => return Err(io::Error::other(status.message().to_owned())),
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Normalizes the path of an archive entry, refusing absolute paths and paths
+/// leaving the archive.
+fn entry_path(raw_path: &str) -> Result<String, Status> {
+    let path = raw_path.replace('\\', "/");
+    let absolute = path.starts_with('/') || path.as_bytes().get(1) == Some(&b':');
+
+    let parts: Vec<&str> = path
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+
+    if absolute
+        || parts.is_empty()
+        || parts
+            .iter()
+            .any(|part| *part == ".." || part.contains('\0'))
+    {
+        warn!("Rejected archive entry path: {:?}", raw_path);
+        return Err(Status::invalid_argument(format!(
+            "Archive entry path {:?} is not allowed!",
+            raw_path
+        )));
+    }
+
+    Ok(parts.join("/"))
+}
+
+fn invalid_archive(err: io::Error) -> Status {
+    warn!("Invalid archive: {}", err);
+    Status::invalid_argument(format!("Invalid archive: {}", err))
+}
+
+fn too_large(max_bytes: u64) -> Status {
+    Status::invalid_argument(format!("Archive exceeds {} bytes!", max_bytes))
+}
+
+fn too_many_entries(max_entries: u64) -> Status {
+    Status::invalid_argument(format!("Archive has more than {} entries!", max_entries))
+}
+
+fn parse_limit(name: &str, default: u64) -> u64 {
+    let limit: u64 = env::var(name)
+        .unwrap_or(default.to_string())
+        .parse()
+        .unwrap_or_else(|_| {
+            error!(
+                "'{}' - should be an integer value in range: [1;{}]",
+                name,
+                u64::MAX
+            );
+            panic!()
+        });
+
+    if limit < 1 {
+        error!(
+            "'{}' - should be an integer value in range: [1;{}]",
+            name,
+            u64::MAX
+        );
+        panic!();
+    }
+
+    limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_paths_are_normalized() {
+        assert_eq!(entry_path("a/b.txt").unwrap(), "a/b.txt");
+        assert_eq!(entry_path("./a//b/./c").unwrap(), "a/b/c");
+        assert_eq!(entry_path("dir\\sub\\file").unwrap(), "dir/sub/file");
+        assert_eq!(entry_path("a/b/").unwrap(), "a/b");
+        assert_eq!(entry_path("..file").unwrap(), "..file");
+    }
+
+    #[test]
+    fn entry_paths_leaving_the_archive_are_rejected() {
+        for path in ["..", "../x", "a/../../x", "a/..", "a\\..\\..\\x", "./../x"] {
+            let status = entry_path(path).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn absolute_entry_paths_are_rejected() {
+        for path in [
+            "/etc/passwd",
+            "//server/share/x",
+            "\\\\server\\share\\x",
+            "\\x",
+            "C:\\Windows\\x",
+            "c:/x",
+            "C:x",
+        ] {
+            assert!(entry_path(path).is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn empty_or_nul_entry_paths_are_rejected() {
+        for path in ["", ".", "./", "//", "a\0b"] {
+            assert!(entry_path(path).is_err(), "{path:?}");
+        }
+    }
+}
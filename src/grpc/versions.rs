@@ -0,0 +1,179 @@
+use tonic::Status;
+use tracing::{error, info, warn};
+
+use super::{file_info, FileStorage};
+use crate::{
+    audit::Caller,
+    models::{NewVersionedKey, StoreItem},
+    storage::{FileKey, FileVersion, ListVersionsResponse, UploadHeader},
+};
+
+/// Longest namespace or key accepted, in bytes.
+pub(super) const MAX_KEY_LEN: usize = 1024;
+
+impl FileStorage {
+    /// Makes a recorded upload the next version of its key and removes the
+    /// versions past the key's retention. The upload is removed again if it
+    /// can't be versioned.
+    pub(super) async fn record_version(
+        &self,
+        item: StoreItem,
+        key: NewVersionedKey,
+    ) -> Result<StoreItem, Status> {
+        match self.db.add_version(item.id, &key, self.keep_versions) {
+            Ok((item, expired)) => {
+                info!(
+                    "Stored version {} of \"{}\" in namespace \"{}\"",
+                    item.version.unwrap_or_default(),
+                    key.file_key,
+                    key.namespace
+                );
+
+                for old in expired {
+                    info!(
+                        "Version {} of \"{}\" expired",
+                        old.version.unwrap_or_default(),
+                        key.file_key
+                    );
+                    let removed = self.remove_blob(&old).await;
+                    if let Err(status) = &removed {
+                        warn!(
+                            "Couldn't remove expired version {}: {}",
+                            old.file_hash,
+                            status.message()
+                        );
+                    }
+                    self.audit.record(
+                        "PruneVersion",
+                        &Caller::system(),
+                        Some(&old.file_hash),
+                        0,
+                        &removed,
+                    );
+                }
+
+                Ok(item)
+            }
+            Err(e) => {
+                error!("Error during adding file version to DB! Error: {}", &e);
+                match self.db.remove_items(&[item.id], false) {
+                    Ok(removed) => {
+                        for item in removed {
+                            if let Err(status) = self.remove_blob(&item).await {
+                                warn!(
+                                    "Couldn't remove unversioned file {}: {}",
+                                    item.file_hash,
+                                    status.message()
+                                );
+                            }
+                        }
+                    }
+                    Err(e) => error!("Could not remove unversioned file! Error: {}", e),
+                }
+                Err(Status::internal("Internal service error!"))
+            }
+        }
+    }
+
+    /// Returns the given version of a key, or its latest one.
+    pub(super) fn find_version(
+        &self,
+        key: &FileKey,
+        version: Option<u32>,
+    ) -> Result<StoreItem, Status> {
+        validate_key(key)?;
+
+        self.db
+            .get_version(&key.namespace, &key.key, version.map(|v| v as i32))
+            .ok_or_else(|| match version {
+                Some(version) => Status::not_found(format!(
+                    "Could not found version {} of \"{}\"!",
+                    version, key.key
+                )),
+                None => Status::not_found(format!("Could not found key \"{}\"!", key.key)),
+            })
+    }
+
+    pub(super) fn list_versions(&self, key: &FileKey) -> Result<ListVersionsResponse, Status> {
+        validate_key(key)?;
+
+        let (stored, items) =
+            self.db
+                .get_versions(&key.namespace, &key.key)
+                .map_err(|e| match e {
+                    diesel::result::Error::NotFound => {
+                        Status::not_found(format!("Could not found key \"{}\"!", key.key))
+                    }
+                    e => {
+                        error!("Error during loading file versions from DB! Error: {}", &e);
+                        Status::internal("Internal service error!")
+                    }
+                })?;
+
+        Ok(ListVersionsResponse {
+            versions: items
+                .iter()
+                .map(|item| FileVersion {
+                    version: item.version.unwrap_or_default() as u32,
+                    info: Some(file_info(item, item.file_size.unwrap_or(0) as u64)),
+                    created_at: item.created_at.and_utc().timestamp(),
+                    is_error: item.file_is_error,
+                })
+                .collect(),
+            keep_versions: stored.keep_versions.unwrap_or(self.keep_versions) as u32,
+        })
+    }
+}
+
+/// Returns the key an upload is stored under, if it has one.
+pub(super) fn versioned_key(header: &UploadHeader) -> Option<NewVersionedKey> {
+    header.key.as_ref().map(|key| NewVersionedKey {
+        namespace: key.namespace.clone(),
+        file_key: key.key.clone(),
+        latest_version: 1,
+        keep_versions: header.keep_versions.map(|keep| keep as i32),
+    })
+}
+
+/// Checks the key and retention of an upload header.
+pub(super) fn validate_version_header(header: &UploadHeader) -> Result<(), Status> {
+    match &header.key {
+        Some(key) => validate_key(key)?,
+        None if header.keep_versions.is_some() => {
+            return Err(Status::invalid_argument(
+                "Versions to keep can only be set with a key!",
+            ))
+        }
+        None => {}
+    }
+
+    if header
+        .keep_versions
+        .is_some_and(|keep| keep > i32::MAX as u32)
+    {
+        return Err(Status::invalid_argument("Too many versions to keep!"));
+    }
+
+    Ok(())
+}
+
+fn validate_key(key: &FileKey) -> Result<(), Status> {
+    if key.key.is_empty() {
+        return Err(Status::invalid_argument("File key should not be empty!"));
+    }
+
+    if key.key.len() > MAX_KEY_LEN || key.namespace.len() > MAX_KEY_LEN {
+        return Err(Status::invalid_argument(format!(
+            "File key and namespace should not exceed {} bytes!",
+            MAX_KEY_LEN
+        )));
+    }
+
+    if key.key.contains('\0') || key.namespace.contains('\0') {
+        return Err(Status::invalid_argument(
+            "File key and namespace should not contain NUL characters!",
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+use tracing::{error, warn, Instrument};
+
+use super::FileStorage;
+use crate::{
+    db::DbState,
+    events::{EventKind, EVENT_BATCH_SIZE},
+    models::StoredEvent,
+    storage::{EventType, FileEvent, FileKey, WatchEventsRequest},
+};
+
+type EventSender = mpsc::Sender<Result<FileEvent, Status>>;
+
+/// Events sent to a watcher ahead of it.
+const WATCH_BUFFER: usize = 64;
+
+/// Which events a watcher gets.
+struct EventFilter {
+    namespace: Option<String>,
+    name_prefix: String,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &StoredEvent) -> bool {
+        if let Some(namespace) = &self.namespace {
+            if event.namespace.as_deref().unwrap_or_default() != namespace {
+                return false;
+            }
+        }
+
+        event.file_name.starts_with(&self.name_prefix)
+    }
+}
+
+impl FileStorage {
+    /// Streams the events after the cursor, if any, then the new ones as
+    /// they are committed by any server.
+    pub(super) fn watch_events(
+        &self,
+        request: WatchEventsRequest,
+    ) -> Result<ReceiverStream<Result<FileEvent, Status>>, Status> {
+        let after = match request.cursor.as_str() {
+            "" => None,
+            cursor => Some(self.cursor_position(cursor)?),
+        };
+        let filter = EventFilter {
+            namespace: request.namespace,
+            name_prefix: request.name_prefix,
+        };
+
+        // Subscribed before looking back, so no event falls in between
+        let mut live = self.events.subscribe();
+        let after = match after {
+            Some(after) => after,
+            None => self
+                .db
+                .get_latest_event_position()
+                .map_err(|e| {
+                    error!("Error during loading storage events from DB! Error: {}", &e);
+                    Status::internal("Internal service error!")
+                })?
+                .unwrap_or_default(),
+        };
+
+        let db = self.db.clone();
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+
+        tokio::spawn(
+            async move {
+                // Events up to this one were loaded from the table
+                let Some(mut loaded) = send_stored(&db, &filter, after, &tx).await else {
+                    return;
+                };
+                let mut last = loaded;
+
+                loop {
+                    let received = tokio::select! {
+                        received = live.recv() => received,
+                        _ = tx.closed() => return,
+                    };
+
+                    match received {
+                        Ok(event) if (event.txid, event.id) <= loaded => {}
+                        Ok(event) => {
+                            last = (event.txid, event.id);
+                            if filter.matches(&event)
+                                && tx.send(Ok(event_message(event))).await.is_err()
+                            {
+                                return;
+                            }
+                        }
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!("Watcher missed {} events, loading them again", missed);
+                            let Some(position) = send_stored(&db, &filter, last, &tx).await else {
+                                return;
+                            };
+                            loaded = position;
+                            last = position;
+                        }
+                        Err(RecvError::Closed) => return,
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// The position of the event a cursor was taken from. `0` is before the
+    /// oldest event, and plain ids of cursors from earlier versions are
+    /// looked up.
+    fn cursor_position(&self, cursor: &str) -> Result<(i64, i64), Status> {
+        let invalid = || Status::invalid_argument("Invalid cursor!");
+        let number = |text: &str| text.parse::<i64>().ok().filter(|n| *n >= 0);
+
+        match cursor.split_once(':') {
+            Some((txid, id)) => Ok((
+                number(txid).ok_or_else(invalid)?,
+                number(id).ok_or_else(invalid)?,
+            )),
+            None => match number(cursor).ok_or_else(invalid)? {
+                0 => Ok((0, 0)),
+                id => self.db.get_event_position(id).ok_or_else(invalid),
+            },
+        }
+    }
+}
+
+/// Sends the matching events stored after the `after` position and returns
+/// the position of the last one, or nothing if the stream ended.
+async fn send_stored(
+    db: &DbState,
+    filter: &EventFilter,
+    mut after: (i64, i64),
+    tx: &EventSender,
+) -> Option<(i64, i64)> {
+    loop {
+        let events = match db.get_events_after(after, EVENT_BATCH_SIZE) {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Error during loading storage events from DB! Error: {}", &e);
+                let _ = tx
+                    .send(Err(Status::internal("Internal service error!")))
+                    .await;
+                return None;
+            }
+        };
+        let done = (events.len() as i64) < EVENT_BATCH_SIZE;
+
+        for event in events {
+            after = (event.txid, event.id);
+            if filter.matches(&event) && tx.send(Ok(event_message(event))).await.is_err() {
+                return None;
+            }
+        }
+
+        if done {
+            return Some(after);
+        }
+    }
+}
+
+fn event_message(event: StoredEvent) -> FileEvent {
+    let event_type = match EventKind::parse(&event.kind) {
+        Some(EventKind::FileUploaded) => EventType::FileUploaded,
+        Some(EventKind::FileDeleted) => EventType::FileDeleted,
+        Some(EventKind::FileCorrupted) => EventType::FileCorrupted,
+        Some(EventKind::FileExpired) => EventType::FileExpired,
+        None => {
+            warn!("Unknown storage event kind: {}", event.kind);
+            EventType::FileUploaded
+        }
+    };
+
+    FileEvent {
+        cursor: format!("{}:{}", event.txid, event.id),
+        r#type: event_type as i32,
+        created_at: event.created_at.and_utc().timestamp(),
+        file_hash: event.file_hash,
+        file_name: event.file_name,
+        size: event.file_size as u64,
+        key: event.file_key.map(|key| FileKey {
+            namespace: event.namespace.unwrap_or_default(),
+            key,
+        }),
+        version: event.version.unwrap_or_default() as u32,
+    }
+}
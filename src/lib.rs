@@ -1,7 +1,20 @@
+pub mod access_log;
+pub mod admin;
+pub mod audit;
+pub mod bao;
+pub mod call_body;
+pub mod chunker;
 pub mod db;
+pub mod events;
 pub mod grpc;
+pub mod listener;
+pub mod metrics;
 pub mod models;
+pub mod ratelimit;
 pub mod schema;
+pub mod telemetry;
+pub mod volumes;
+pub mod webhooks;
 
 pub mod storage {
     tonic::include_proto!("storage");
@@ -0,0 +1,126 @@
+use pq_sys::{
+    ConnStatusType, ExecStatusType, PGconn, PQclear, PQconnectdb, PQconsumeInput, PQerrorMessage,
+    PQexec, PQfinish, PQfreemem, PQnotifies, PQresultStatus, PQsetnonblocking, PQsocket, PQstatus,
+};
+use std::{
+    ffi::{CStr, CString},
+    os::fd::{AsRawFd, RawFd},
+};
+use tokio::io::{unix::AsyncFd, Interest};
+
+/// Socket of a libpq connection, owned by the connection.
+struct Socket(RawFd);
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// A libpq connection, closed on drop.
+struct Connection(*mut PGconn);
+
+// The connection is only ever used by its owner.
+unsafe impl Send for Connection {}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { PQfinish(self.0) };
+    }
+}
+
+/// A Postgres connection of its own listening on a notification channel.
+/// Diesel connections don't receive notifications, so this one talks to libpq
+/// directly.
+pub struct PgListener {
+    // Deregistered before the connection closes its socket
+    socket: AsyncFd<Socket>,
+    conn: Connection,
+}
+
+impl PgListener {
+    /// Connects to `database_url` and listens on `channel`.
+    pub async fn connect(database_url: &str, channel: &str) -> Result<Self, String> {
+        let url = CString::new(database_url).map_err(|e| e.to_string())?;
+        let listen = CString::new(format!("LISTEN \"{}\"", channel.replace('"', "\"\"")))
+            .map_err(|e| e.to_string())?;
+
+        // Connecting blocks until the server answers
+        let conn = tokio::task::spawn_blocking(move || unsafe { listen_on(&url, &listen) })
+            .await
+            .map_err(|e| e.to_string())??;
+
+        let socket =
+            AsyncFd::with_interest(Socket(unsafe { PQsocket(conn.0) }), Interest::READABLE)
+                .map_err(|e| e.to_string())?;
+        Ok(Self { socket, conn })
+    }
+
+    /// Waits for notifications and returns their payloads, in the order they
+    /// were committed.
+    pub async fn recv(&mut self) -> Result<Vec<String>, String> {
+        loop {
+            let payloads = self.take_notifications();
+            if !payloads.is_empty() {
+                return Ok(payloads);
+            }
+
+            let mut guard = self.socket.readable().await.map_err(|e| e.to_string())?;
+            if unsafe { PQconsumeInput(self.conn.0) } == 0 {
+                return Err(unsafe { last_error(self.conn.0) });
+            }
+            guard.clear_ready();
+        }
+    }
+
+    fn take_notifications(&mut self) -> Vec<String> {
+        let mut payloads = Vec::new();
+        unsafe {
+            loop {
+                let notify = PQnotifies(self.conn.0);
+                if notify.is_null() {
+                    break;
+                }
+                payloads.push(
+                    CStr::from_ptr((*notify).extra)
+                        .to_string_lossy()
+                        .into_owned(),
+                );
+                PQfreemem(notify.cast());
+            }
+        }
+        payloads
+    }
+}
+
+/// Opens a connection running `listen`, switched to non-blocking mode so
+/// reading notifications never waits on the socket.
+unsafe fn listen_on(url: &CStr, listen: &CStr) -> Result<Connection, String> {
+    let conn = PQconnectdb(url.as_ptr());
+    if conn.is_null() {
+        return Err("Out of memory".to_owned());
+    }
+    let conn = Connection(conn);
+    if PQstatus(conn.0) != ConnStatusType::CONNECTION_OK {
+        return Err(last_error(conn.0));
+    }
+
+    let res = PQexec(conn.0, listen.as_ptr());
+    let listening = PQresultStatus(res) == ExecStatusType::PGRES_COMMAND_OK;
+    PQclear(res);
+    if !listening {
+        return Err(last_error(conn.0));
+    }
+
+    if PQsetnonblocking(conn.0, 1) != 0 {
+        return Err(last_error(conn.0));
+    }
+    Ok(conn)
+}
+
+unsafe fn last_error(conn: *const PGconn) -> String {
+    CStr::from_ptr(PQerrorMessage(conn))
+        .to_string_lossy()
+        .trim()
+        .to_owned()
+}
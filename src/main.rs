@@ -1,9 +1,16 @@
 use dotenvy::dotenv;
-use log::info;
-use std::{env, net::SocketAddr};
+use std::{env, net::SocketAddr, sync::Arc};
 use tonic::transport::Server;
+use tracing::info;
 
-use grpc_storage::{grpc::FileStorage, storage::storage_server::StorageServer};
+use grpc_storage::{
+    access_log::AccessLogLayer,
+    admin::{AdminAuth, StorageAdmin},
+    grpc::FileStorage,
+    metrics::{self, MetricsLayer},
+    storage::{admin_server::AdminServer, storage_server::StorageServer},
+    telemetry::{self, RequestIdLayer},
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -13,19 +20,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         env::set_var("RUST_LOG", "info");
     }
 
-    env_logger::builder()
-        .format_timestamp(Some(env_logger::TimestampPrecision::Seconds))
-        .try_init()?;
+    let _telemetry = telemetry::init()?;
 
     let addr = env::var("SERVER_ADDR")
         .unwrap_or("[::1]:50051".to_string())
         .parse::<SocketAddr>()
-        .expect(&format!("'SERVER_ADDR' - should be an IPv4/6 address"));
+        .expect("'SERVER_ADDR' - should be an IPv4/6 address");
 
     info!("Server listening on {}", addr);
 
+    let storage = Arc::new(FileStorage::new());
+    storage.clone().spawn_reaper();
+    storage.events().spawn_listener();
+    storage.webhooks().spawn_dispatcher();
+    metrics::spawn_server(storage.clone());
+    let admin_auth = AdminAuth::new(env::var("ADMIN_TOKEN").ok());
+
     Server::builder()
-        .add_service(StorageServer::new(FileStorage::new()))
+        .layer(MetricsLayer::new(storage.metrics().clone()))
+        .layer(RequestIdLayer::new())
+        .layer(AccessLogLayer::from_env())
+        .add_service(StorageServer::from_arc(storage.clone()))
+        .add_service(AdminServer::with_interceptor(
+            StorageAdmin::new(storage),
+            admin_auth,
+        ))
         .serve(addr)
         .await?;
 
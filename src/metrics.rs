@@ -0,0 +1,465 @@
+use bytes::Bytes;
+use dotenvy::dotenv;
+use http_body_util::Full;
+use hyper::{
+    header, server::conn::http1, service::service_fn, Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    env,
+    fmt::{Display, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::net::TcpListener;
+use tonic::{body::BoxBody, codegen::Service, Code};
+use tower_layer::Layer;
+use tracing::{error, info};
+
+use crate::{
+    call_body::{observe_call, CallFuture, CallObserver},
+    grpc::FileStorage,
+    storage::VolumeStatus,
+};
+
+/// Upper bounds of the RPC duration histogram, in seconds. Streaming calls
+/// take as long as their stream.
+const DURATION_BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+// `METHODS`, the methods of the proto services, listed by build.rs. Calls
+// to any other path are counted as `unknown`.
+include!(concat!(env!("OUT_DIR"), "/methods.rs"));
+
+#[derive(Default)]
+struct MethodStats {
+    // Finished calls per gRPC code
+    codes: BTreeMap<String, u64>,
+    active: i64,
+    received_bytes: u64,
+    sent_bytes: u64,
+    // Calls per bucket (not cumulative), the last one is +Inf
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    duration_sum: f64,
+}
+
+#[derive(Default)]
+struct Counters {
+    methods: BTreeMap<String, MethodStats>,
+    corrupted_reads: u64,
+}
+
+/// State of the storage sampled on each scrape.
+pub struct StorageGauges {
+    pub pool_connections: u32,
+    pub pool_idle_connections: u32,
+    pub pool_max_size: u32,
+    // Unset if they couldn't be loaded from DB
+    pub objects: Option<i64>,
+    pub stored_bytes: Option<i64>,
+    pub damaged_files: Option<i64>,
+    pub volumes: Vec<VolumeStatus>,
+}
+
+/// Counters of the gRPC calls and of the storage, exposed in the Prometheus
+/// text format.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a file whose content didn't match its hash on read.
+    pub fn record_corrupted_read(&self) {
+        self.counters.lock().unwrap().corrupted_reads += 1;
+    }
+
+    fn method<R>(&self, method: &str, update: impl FnOnce(&mut MethodStats) -> R) -> R {
+        let mut counters = self.counters.lock().unwrap();
+        match counters.methods.get_mut(method) {
+            Some(stats) => update(stats),
+            None => update(counters.methods.entry(method.to_owned()).or_default()),
+        }
+    }
+
+    fn start_call(&self, method: String) -> Call {
+        self.method(&method, |stats| stats.active += 1);
+        Call {
+            metrics: self.clone(),
+            method,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn render(&self, gauges: &StorageGauges) -> String {
+        let mut out = String::new();
+        let counters = self.counters.lock().unwrap();
+        let methods = &counters.methods;
+
+        family(
+            &mut out,
+            "rpc_requests_total",
+            "counter",
+            "Finished RPCs by method and gRPC code.",
+        );
+        for (method, stats) in methods {
+            for (code, count) in &stats.codes {
+                let labels = format!("method=\"{}\",code=\"{}\"", method, code);
+                sample(&mut out, "rpc_requests_total", &labels, count);
+            }
+        }
+
+        family(
+            &mut out,
+            "rpc_duration_seconds",
+            "histogram",
+            "Duration of finished RPCs, streams included.",
+        );
+        for (method, stats) in methods {
+            let mut cumulative = 0;
+            for (i, count) in stats.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = DURATION_BUCKETS
+                    .get(i)
+                    .map_or("+Inf".to_owned(), |bound| bound.to_string());
+                let labels = format!("method=\"{}\",le=\"{}\"", method, le);
+                sample(&mut out, "rpc_duration_seconds_bucket", &labels, cumulative);
+            }
+            let labels = format!("method=\"{}\"", method);
+            sample(
+                &mut out,
+                "rpc_duration_seconds_sum",
+                &labels,
+                stats.duration_sum,
+            );
+            sample(&mut out, "rpc_duration_seconds_count", &labels, cumulative);
+        }
+
+        family(
+            &mut out,
+            "rpc_active",
+            "gauge",
+            "RPCs in progress, open streams included.",
+        );
+        for (method, stats) in methods {
+            sample(
+                &mut out,
+                "rpc_active",
+                &format!("method=\"{}\"", method),
+                stats.active,
+            );
+        }
+
+        family(
+            &mut out,
+            "rpc_received_bytes_total",
+            "counter",
+            "Message bytes received, e.g. uploaded data.",
+        );
+        for (method, stats) in methods {
+            let labels = format!("method=\"{}\"", method);
+            sample(
+                &mut out,
+                "rpc_received_bytes_total",
+                &labels,
+                stats.received_bytes,
+            );
+        }
+
+        family(
+            &mut out,
+            "rpc_sent_bytes_total",
+            "counter",
+            "Message bytes sent, e.g. fetched data.",
+        );
+        for (method, stats) in methods {
+            let labels = format!("method=\"{}\"", method);
+            sample(&mut out, "rpc_sent_bytes_total", &labels, stats.sent_bytes);
+        }
+
+        family(
+            &mut out,
+            "corrupted_reads_total",
+            "counter",
+            "Files whose content didn't match their hash on read.",
+        );
+        sample(
+            &mut out,
+            "corrupted_reads_total",
+            "",
+            counters.corrupted_reads,
+        );
+        drop(counters);
+
+        family(
+            &mut out,
+            "db_pool_connections",
+            "gauge",
+            "Connections of the DB pool.",
+        );
+        sample(&mut out, "db_pool_connections", "", gauges.pool_connections);
+        family(
+            &mut out,
+            "db_pool_idle_connections",
+            "gauge",
+            "Idle connections of the DB pool.",
+        );
+        sample(
+            &mut out,
+            "db_pool_idle_connections",
+            "",
+            gauges.pool_idle_connections,
+        );
+        family(
+            &mut out,
+            "db_pool_max_size",
+            "gauge",
+            "Most connections the DB pool opens.",
+        );
+        sample(&mut out, "db_pool_max_size", "", gauges.pool_max_size);
+
+        if let Some(objects) = gauges.objects {
+            family(&mut out, "objects", "gauge", "Stored files.");
+            sample(&mut out, "objects", "", objects);
+        }
+        if let Some(bytes) = gauges.stored_bytes {
+            family(
+                &mut out,
+                "stored_bytes",
+                "gauge",
+                "Total size of the stored files.",
+            );
+            sample(&mut out, "stored_bytes", "", bytes);
+        }
+        if let Some(damaged) = gauges.damaged_files {
+            family(
+                &mut out,
+                "damaged_files",
+                "gauge",
+                "Stored files found missing or corrupted.",
+            );
+            sample(&mut out, "damaged_files", "", damaged);
+        }
+
+        family(
+            &mut out,
+            "volume_free_bytes",
+            "gauge",
+            "Free space of the storage volumes.",
+        );
+        for vol in &gauges.volumes {
+            let labels = format!("volume=\"{}\"", escape(&vol.name));
+            sample(&mut out, "volume_free_bytes", &labels, vol.free_bytes);
+        }
+        family(
+            &mut out,
+            "volume_total_bytes",
+            "gauge",
+            "Size of the storage volumes.",
+        );
+        for vol in &gauges.volumes {
+            let labels = format!("volume=\"{}\"", escape(&vol.name));
+            sample(&mut out, "volume_total_bytes", &labels, vol.total_bytes);
+        }
+
+        out
+    }
+}
+
+/// Prefix of the metric names.
+const PREFIX: &str = "grpc_storage_";
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}{} {}", PREFIX, name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl Display) {
+    match labels {
+        "" => writeln!(out, "{}{} {}", PREFIX, name, value),
+        labels => writeln!(out, "{}{}{{{}}} {}", PREFIX, name, labels, value),
+    }
+    .unwrap_or_default();
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A call in progress, recorded once it's finished.
+struct Call {
+    metrics: Metrics,
+    method: String,
+    started: Instant,
+}
+
+impl CallObserver for Call {
+    fn received(&self, bytes: u64) {
+        self.metrics
+            .method(&self.method, |stats| stats.received_bytes += bytes);
+    }
+
+    fn sent(&self, bytes: u64) {
+        self.metrics
+            .method(&self.method, |stats| stats.sent_bytes += bytes);
+    }
+
+    fn finished(&self, code: Code) {
+        let code = format!("{:?}", code);
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        self.metrics.method(&self.method, |stats| {
+            stats.active -= 1;
+            *stats.codes.entry(code).or_default() += 1;
+            stats.buckets[bucket] += 1;
+            stats.duration_sum += elapsed;
+        });
+    }
+}
+
+/// Tracks the calls of the wrapped gRPC services.
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Metrics,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S> Service<Request<BoxBody>> for MetricsService<S>
+where
+    S: Service<Request<BoxBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = CallFuture<S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
+        let method = request
+            .uri()
+            .path()
+            .strip_prefix("/storage.")
+            .and_then(|method| METHODS.iter().find(|known| **known == method))
+            .map_or("unknown", |method| method)
+            .to_owned();
+        let call = self.metrics.start_call(method);
+
+        observe_call(&mut self.inner, request, Arc::new(call))
+    }
+}
+
+/// Serves `GET /metrics` on `METRICS_ADDR`, if it's set.
+pub fn spawn_server(storage: Arc<FileStorage>) {
+    dotenv().ok();
+
+    let addr = match env::var("METRICS_ADDR") {
+        Ok(addr) if !addr.is_empty() => addr.parse::<SocketAddr>().unwrap_or_else(|_| {
+            error!("'METRICS_ADDR' - should be an IPv4/6 address");
+            panic!()
+        }),
+        _ => {
+            info!("Metrics endpoint is disabled");
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Couldn't serve metrics on {}! Error: {}", addr, e);
+                return;
+            }
+        };
+        info!("Metrics served on http://{}/metrics", addr);
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Couldn't accept metrics connection! Error: {}", e);
+                    continue;
+                }
+            };
+
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request| scrape(storage.clone(), request));
+                // Scrapers going away is no concern
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+}
+
+async fn scrape(
+    storage: Arc<FileStorage>,
+    request: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let status = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => None,
+        (_, "/metrics") => Some(StatusCode::METHOD_NOT_ALLOWED),
+        _ => Some(StatusCode::NOT_FOUND),
+    };
+    if let Some(status) = status {
+        let mut response = Response::new(Full::new(Bytes::new()));
+        *response.status_mut() = status;
+        return Ok(response);
+    }
+
+    // The gauges are loaded from DB
+    let body = tokio::task::spawn_blocking(move || storage.metrics().render(&storage.gauges()))
+        .await
+        .unwrap_or_default();
+
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok(response)
+}
@@ -1,7 +1,12 @@
-use crate::schema::store;
+use crate::schema::{
+    audit_log, chunks, file_chunks, file_keys, governance_audit, multipart_parts,
+    multipart_uploads, namespace_retention, outbox, refs, storage_events, storage_roots, store,
+    webhook_deliveries, webhooks,
+};
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 
-#[derive(Queryable, Selectable, Debug)]
+#[derive(Queryable, Selectable, Clone, Debug)]
 #[diesel(table_name = store)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct StoreItem {
@@ -10,6 +15,21 @@ pub struct StoreItem {
     pub file_path: String,
     pub file_hash: String,
     pub file_is_error: bool,
+    pub root_id: Option<i32>,
+    pub file_size: Option<i64>,
+    pub content_type: Option<String>,
+    pub metadata: serde_json::Value,
+    pub chunked: bool,
+    pub blake3_hash: Option<String>,
+    pub outboard_path: Option<String>,
+    pub key_id: Option<i32>,
+    pub version: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub tags: Vec<String>,
+    pub generation: i64,
+    pub expires_at: Option<NaiveDateTime>,
+    pub retain_until: Option<NaiveDateTime>,
+    pub legal_hold: bool,
 }
 
 #[derive(Insertable, Debug)]
@@ -18,4 +38,306 @@ pub struct NewStoreItem {
     pub file_name: String,
     pub file_path: String,
     pub file_hash: String,
+    pub root_id: Option<i32>,
+    pub file_size: Option<i64>,
+    pub content_type: Option<String>,
+    pub metadata: serde_json::Value,
+    pub chunked: bool,
+    pub blake3_hash: Option<String>,
+    pub outboard_path: Option<String>,
+    pub tags: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub retain_until: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = storage_roots)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StorageRoot {
+    pub id: i32,
+    pub name: String,
+    pub path: String,
+    pub weight: i32,
+    pub min_free_bytes: i64,
+    pub state: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = storage_roots)]
+pub struct NewStorageRoot {
+    pub name: String,
+    pub path: String,
+    pub weight: i32,
+    pub min_free_bytes: i64,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = multipart_uploads)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MultipartUpload {
+    pub id: i32,
+    pub upload_id: String,
+    pub file_name: String,
+    pub file_size: Option<i64>,
+    pub content_type: Option<String>,
+    pub metadata: serde_json::Value,
+    pub expected_hash: Option<String>,
+    pub root_id: i32,
+    pub created_at: NaiveDateTime,
+    pub namespace: Option<String>,
+    pub file_key: Option<String>,
+    pub keep_versions: Option<i32>,
+    pub tags: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub retain_until: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = multipart_uploads)]
+pub struct NewMultipartUpload {
+    pub upload_id: String,
+    pub file_name: String,
+    pub file_size: Option<i64>,
+    pub content_type: Option<String>,
+    pub metadata: serde_json::Value,
+    pub expected_hash: Option<String>,
+    pub root_id: i32,
+    pub namespace: Option<String>,
+    pub file_key: Option<String>,
+    pub keep_versions: Option<i32>,
+    pub tags: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub retain_until: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = multipart_parts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MultipartPart {
+    pub id: i32,
+    pub multipart_id: i32,
+    pub part_number: i32,
+    pub part_hash: String,
+    pub part_size: i64,
+    pub file_path: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = multipart_parts)]
+pub struct NewMultipartPart {
+    pub multipart_id: i32,
+    pub part_number: i32,
+    pub part_hash: String,
+    pub part_size: i64,
+    pub file_path: String,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = chunks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Chunk {
+    pub id: i32,
+    pub chunk_hash: String,
+    pub chunk_size: i64,
+    pub root_id: i32,
+    pub file_path: String,
+    pub last_used_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = chunks)]
+pub struct NewChunk {
+    pub chunk_hash: String,
+    pub chunk_size: i64,
+    pub root_id: i32,
+    pub file_path: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = file_chunks)]
+pub struct NewFileChunk {
+    pub store_id: i32,
+    pub position: i32,
+    pub chunk_id: i32,
+}
+
+/// A logical file name within a namespace, its uploads are numbered versions.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = file_keys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct VersionedKey {
+    pub id: i32,
+    pub namespace: String,
+    pub file_key: String,
+    pub latest_version: i32,
+    pub keep_versions: Option<i32>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = file_keys)]
+pub struct NewVersionedKey {
+    pub namespace: String,
+    pub file_key: String,
+    pub latest_version: i32,
+    pub keep_versions: Option<i32>,
+}
+
+/// A named pointer at a stored file hash.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = refs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FileRef {
+    pub name: String,
+    pub file_hash: String,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = refs)]
+pub struct NewFileRef {
+    pub name: String,
+    pub file_hash: String,
+}
+
+/// Default retention of the files uploaded to a namespace.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = namespace_retention)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NamespaceRetention {
+    pub namespace: String,
+    pub retention_secs: i64,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = namespace_retention)]
+pub struct NewNamespaceRetention {
+    pub namespace: String,
+    pub retention_secs: i64,
+}
+
+/// A record of an admin changing retention locks or legal holds.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = governance_audit)]
+pub struct NewGovernanceAudit {
+    pub action: String,
+    pub target: String,
+    pub reason: String,
+    pub details: serde_json::Value,
+}
+
+/// A call recorded in the audit log.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = audit_log)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuditRecord {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub operation: String,
+    pub caller: String,
+    pub peer: String,
+    pub file_hash: Option<String>,
+    pub bytes: i64,
+    pub outcome: String,
+    pub message: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditRecord {
+    pub operation: String,
+    pub caller: String,
+    pub peer: String,
+    pub file_hash: Option<String>,
+    pub bytes: i64,
+    pub outcome: String,
+    pub message: String,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = storage_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StoredEvent {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub kind: String,
+    pub file_hash: String,
+    pub file_name: String,
+    pub file_size: i64,
+    pub namespace: Option<String>,
+    pub file_key: Option<String>,
+    pub version: Option<i32>,
+    pub txid: i64,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = storage_events)]
+pub struct NewStoredEvent {
+    pub kind: String,
+    pub file_hash: String,
+    pub file_name: String,
+    pub file_size: i64,
+    pub namespace: Option<String>,
+    pub file_key: Option<String>,
+    pub version: Option<i32>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = webhooks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webhooks)]
+pub struct NewWebhook {
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = outbox)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OutboxEvent {
+    pub id: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub created_at: NaiveDateTime,
+    pub dispatched: bool,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = outbox)]
+pub struct NewOutboxEvent {
+    pub kind: String,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = webhook_deliveries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i32,
+    pub outbox_id: i64,
+    pub state: String,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDelivery {
+    pub webhook_id: i32,
+    pub outbox_id: i64,
 }
@@ -0,0 +1,458 @@
+// Calls over their limit fail with the `Status` sent back to the client
+#![allow(clippy::result_large_err)]
+
+use dotenvy::dotenv;
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tonic::Status;
+use tracing::{error, info, warn};
+
+use crate::audit::Caller;
+
+/// Metadata key telling a limited client how many seconds to wait.
+pub const RETRY_AFTER_HEADER: &str = "retry-after";
+
+/// How often buckets that refilled completely are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Requests and bytes a client may use per second, 0 is unlimited.
+#[derive(Clone, Copy, Debug)]
+struct Limit {
+    requests_per_sec: f64,
+    requests_burst: f64,
+    bytes_per_sec: f64,
+    bytes_burst: f64,
+}
+
+impl Limit {
+    /// Bursts default to one second worth of the rate.
+    fn new(requests_per_sec: f64, bytes_per_sec: f64) -> Self {
+        Self {
+            requests_per_sec,
+            requests_burst: requests_per_sec.max(1.0),
+            bytes_per_sec,
+            bytes_burst: bytes_per_sec,
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.requests_per_sec == 0.0 && self.bytes_per_sec == 0.0
+    }
+}
+
+/// What clients are told apart by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ClientKey {
+    /// The peer IP, clients can't pick another one
+    Peer,
+    /// `x-client-id`, the peer IP for calls without one. Clients pick it
+    /// themselves, so it's only for trusted ones.
+    Identity,
+}
+
+impl FromStr for ClientKey {
+    type Err = ();
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        match key {
+            "identity" => Ok(Self::Identity),
+            "peer" => Ok(Self::Peer),
+            _ => Err(()),
+        }
+    }
+}
+
+struct RateLimitConfig {
+    default: Limit,
+    namespaces: HashMap<String, Limit>,
+    key: ClientKey,
+    max_delay: Duration,
+}
+
+impl RateLimitConfig {
+    /// Reads `RATE_LIMIT_REQUESTS_PER_SEC`, `RATE_LIMIT_REQUESTS_BURST`,
+    /// `RATE_LIMIT_BYTES_PER_SEC`, `RATE_LIMIT_BYTES_BURST`,
+    /// `RATE_LIMIT_NAMESPACES` (`<namespace>=<requests/s>:<bytes/s>,...`),
+    /// `RATE_LIMIT_BY` and `RATE_LIMIT_MAX_DELAY_MS`.
+    fn from_env() -> Self {
+        dotenv().ok();
+
+        let mut default = Limit::new(
+            rate("RATE_LIMIT_REQUESTS_PER_SEC", 0.0),
+            rate("RATE_LIMIT_BYTES_PER_SEC", 0.0),
+        );
+        default.requests_burst = rate("RATE_LIMIT_REQUESTS_BURST", default.requests_burst);
+        default.bytes_burst = rate("RATE_LIMIT_BYTES_BURST", default.bytes_burst);
+
+        let namespaces = env::var("RATE_LIMIT_NAMESPACES")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                namespace_limit(entry.trim()).unwrap_or_else(|| {
+                    error!(
+                        "'RATE_LIMIT_NAMESPACES' - should be a list of \
+                         <namespace>=<requests/s>:<bytes/s>, got \"{}\"",
+                        entry
+                    );
+                    panic!()
+                })
+            })
+            .collect();
+
+        let key = env::var("RATE_LIMIT_BY")
+            .unwrap_or("peer".to_owned())
+            .parse()
+            .unwrap_or_else(|_| {
+                error!("'RATE_LIMIT_BY' - should be 'peer' or 'identity'");
+                panic!()
+            });
+
+        let max_delay: u64 = env::var("RATE_LIMIT_MAX_DELAY_MS")
+            .unwrap_or("1000".to_owned())
+            .parse()
+            .unwrap_or_else(|_| {
+                error!(
+                    "'RATE_LIMIT_MAX_DELAY_MS' - should be an integer value in range: [0;{}]",
+                    u64::MAX
+                );
+                panic!()
+            });
+
+        Self {
+            default,
+            namespaces,
+            key,
+            max_delay: Duration::from_millis(max_delay),
+        }
+    }
+}
+
+/// A non negative rate from the environment.
+fn rate(name: &str, default: f64) -> f64 {
+    match env::var(name) {
+        Ok(val) if !val.is_empty() => val
+            .parse()
+            .ok()
+            .filter(|rate: &f64| rate.is_finite() && *rate >= 0.0)
+            .unwrap_or_else(|| {
+                error!("'{}' - should be a non negative number", name);
+                panic!()
+            }),
+        _ => default,
+    }
+}
+
+fn namespace_limit(entry: &str) -> Option<(String, Limit)> {
+    let (namespace, limit) = entry.split_once('=')?;
+    let (requests, bytes) = limit.split_once(':')?;
+    let requests: f64 = requests.trim().parse().ok()?;
+    let bytes: f64 = bytes.trim().parse().ok()?;
+    if !(requests.is_finite() && bytes.is_finite() && requests >= 0.0 && bytes >= 0.0) {
+        return None;
+    }
+    Some((namespace.trim().to_owned(), Limit::new(requests, bytes)))
+}
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    // Negative while taken ahead of time
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    /// Takes a token if there is one, otherwise returns how long until there
+    /// is.
+    fn try_take(&mut self) -> Result<(), Duration> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+
+    /// Takes `amount` tokens ahead of time, unless the ones taken before are
+    /// more than `max_behind` ahead already. Returns how long to wait for
+    /// them, or how long until the earlier ones are paid off.
+    fn reserve(&mut self, amount: f64, max_behind: Duration) -> Result<Duration, Duration> {
+        if self.rate == 0.0 {
+            return Ok(Duration::ZERO);
+        }
+        self.refill();
+        let behind = Duration::from_secs_f64((-self.tokens).max(0.0) / self.rate);
+        if behind > max_behind {
+            return Err(behind);
+        }
+
+        self.tokens -= amount;
+        Ok(Duration::from_secs_f64((-self.tokens).max(0.0) / self.rate))
+    }
+}
+
+struct Buckets {
+    requests: Bucket,
+    bytes: Bucket,
+}
+
+struct State {
+    // Per client, and namespace if it has limits of its own
+    buckets: HashMap<(String, Option<String>), Buckets>,
+    pruned: Instant,
+}
+
+/// Token buckets limiting the requests and bytes per second of each client.
+#[derive(Clone)]
+pub struct RateLimits {
+    config: Arc<RateLimitConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        let config = RateLimitConfig::from_env();
+        if config.default.is_unlimited() && config.namespaces.is_empty() {
+            info!("Rate limits are disabled");
+        } else {
+            info!(
+                "Rate limits per client: {} requests/s, {} bytes/s ({} namespace overrides)",
+                config.default.requests_per_sec,
+                config.default.bytes_per_sec,
+                config.namespaces.len()
+            );
+        }
+
+        Self {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(State {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            })),
+        }
+    }
+
+    /// The limits of a caller's calls on a namespace (`None` for calls
+    /// outside of one).
+    pub fn client(&self, caller: &Caller, namespace: Option<&str>) -> ClientLimit {
+        let (namespace, limit) =
+            match namespace.and_then(|ns| self.config.namespaces.get_key_value(ns)) {
+                Some((namespace, limit)) => (Some(namespace.clone()), *limit),
+                None => (None, self.config.default),
+            };
+        if limit.is_unlimited() {
+            return ClientLimit::default();
+        }
+
+        let peer = caller
+            .peer
+            .parse::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| caller.peer.clone());
+        let client = match self.config.key {
+            ClientKey::Identity if !caller.identity.is_empty() => caller.identity.clone(),
+            _ => peer,
+        };
+
+        ClientLimit(Some(Arc::new(Client {
+            limits: self.clone(),
+            key: (client, namespace),
+            limit,
+        })))
+    }
+
+    fn with_buckets<R>(
+        &self,
+        key: &(String, Option<String>),
+        limit: &Limit,
+        update: impl FnOnce(&mut Buckets) -> R,
+    ) -> R {
+        let mut state = self.state.lock().unwrap();
+        if state.pruned.elapsed() >= PRUNE_INTERVAL {
+            // A full bucket is the same as a new one
+            state
+                .buckets
+                .retain(|_, buckets| !(buckets.requests.is_full() && buckets.bytes.is_full()));
+            state.pruned = Instant::now();
+        }
+
+        let buckets = state.buckets.entry(key.clone()).or_insert_with(|| Buckets {
+            requests: Bucket::new(limit.requests_per_sec, limit.requests_burst),
+            bytes: Bucket::new(limit.bytes_per_sec, limit.bytes_burst),
+        });
+        update(buckets)
+    }
+}
+
+struct Client {
+    limits: RateLimits,
+    key: (String, Option<String>),
+    limit: Limit,
+}
+
+/// The limits of one client's calls, unlimited by default.
+#[derive(Clone, Default)]
+pub struct ClientLimit(Option<Arc<Client>>);
+
+impl ClientLimit {
+    /// Counts a request, `RESOURCE_EXHAUSTED` if the client is over its
+    /// limit.
+    pub fn check_request(&self) -> Result<(), Status> {
+        let Some(client) = &self.0 else {
+            return Ok(());
+        };
+
+        client
+            .limits
+            .with_buckets(&client.key, &client.limit, |buckets| {
+                buckets.requests.try_take()
+            })
+            .map_err(|wait| {
+                warn!("Client \"{}\" is over its request rate", client.key.0);
+                exhausted("Too many requests!", wait)
+            })
+    }
+
+    /// Waits until the client may transfer `bytes` more, `RESOURCE_EXHAUSTED`
+    /// if its other transfers are more than `RATE_LIMIT_MAX_DELAY_MS` over
+    /// its bandwidth already.
+    pub async fn throttle(&self, bytes: usize) -> Result<(), Status> {
+        let Some(client) = &self.0 else {
+            return Ok(());
+        };
+
+        let max_delay = client.limits.config.max_delay;
+        let taken = client
+            .limits
+            .with_buckets(&client.key, &client.limit, |buckets| {
+                buckets.bytes.reserve(bytes as f64, max_delay)
+            });
+        match taken {
+            Ok(wait) if wait.is_zero() => Ok(()),
+            Ok(wait) => {
+                tokio::time::sleep(wait).await;
+                Ok(())
+            }
+            Err(wait) => {
+                warn!("Client \"{}\" is over its bandwidth", client.key.0);
+                Err(exhausted("Bandwidth limit exceeded!", wait))
+            }
+        }
+    }
+}
+
+/// `RESOURCE_EXHAUSTED` telling the client when to retry, in whole seconds.
+fn exhausted(message: &str, wait: Duration) -> Status {
+    let mut status = Status::resource_exhausted(message);
+    let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+    status
+        .metadata_mut()
+        .insert(RETRY_AFTER_HEADER, secs.into());
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lets `secs` pass for the bucket.
+    fn age(bucket: &mut Bucket, secs: f64) {
+        bucket.updated -= Duration::from_secs_f64(secs);
+    }
+
+    fn assert_close(actual: Duration, expected: f64) {
+        let diff = (actual.as_secs_f64() - expected).abs();
+        assert!(diff < 0.01, "{:?} is not about {}s", actual, expected);
+    }
+
+    #[test]
+    fn takes_burst_then_waits_for_refill() {
+        let mut bucket = Bucket::new(2.0, 3.0);
+        for _ in 0..3 {
+            assert!(bucket.try_take().is_ok());
+        }
+        // One token takes half a second at 2/s
+        assert_close(bucket.try_take().unwrap_err(), 0.5);
+
+        age(&mut bucket, 0.25);
+        assert_close(bucket.try_take().unwrap_err(), 0.25);
+        age(&mut bucket, 0.25);
+        assert!(bucket.try_take().is_ok());
+    }
+
+    #[test]
+    fn refill_stops_at_burst() {
+        let mut bucket = Bucket::new(10.0, 5.0);
+        bucket.tokens = 0.0;
+        assert!(!bucket.is_full());
+
+        age(&mut bucket, 0.2);
+        bucket.refill();
+        assert!((bucket.tokens - 2.0).abs() < 0.1);
+
+        age(&mut bucket, 60.0);
+        assert!(bucket.is_full());
+        assert_eq!(bucket.tokens, 5.0);
+    }
+
+    #[test]
+    fn reserve_waits_for_the_debt() {
+        let max_behind = Duration::from_secs(1);
+        let mut bucket = Bucket::new(1000.0, 1000.0);
+
+        // Covered by the burst
+        assert_close(bucket.reserve(600.0, max_behind).unwrap(), 0.0);
+        // 500 bytes over at 1000/s
+        assert_close(bucket.reserve(900.0, max_behind).unwrap(), 0.5);
+        // Taken while only half a second behind
+        assert_close(bucket.reserve(1000.0, max_behind).unwrap(), 1.5);
+        // Now more than a second behind
+        assert_close(bucket.reserve(1.0, max_behind).unwrap_err(), 1.5);
+
+        age(&mut bucket, 1.0);
+        assert_close(bucket.reserve(100.0, max_behind).unwrap(), 0.6);
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let mut bucket = Bucket::new(0.0, 0.0);
+        for _ in 0..100 {
+            assert!(bucket.try_take().is_ok());
+        }
+        assert_eq!(
+            bucket.reserve(1e12, Duration::ZERO).unwrap(),
+            Duration::ZERO
+        );
+    }
+}
@@ -1,5 +1,142 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Int8,
+        created_at -> Timestamp,
+        operation -> Varchar,
+        caller -> Varchar,
+        peer -> Varchar,
+        file_hash -> Nullable<Varchar>,
+        bytes -> Int8,
+        outcome -> Varchar,
+        message -> Varchar,
+    }
+}
+
+diesel::table! {
+    chunks (id) {
+        id -> Int4,
+        chunk_hash -> Varchar,
+        chunk_size -> Int8,
+        root_id -> Int4,
+        file_path -> Varchar,
+        last_used_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    file_chunks (store_id, position) {
+        store_id -> Int4,
+        position -> Int4,
+        chunk_id -> Int4,
+    }
+}
+
+diesel::table! {
+    file_keys (id) {
+        id -> Int4,
+        namespace -> Varchar,
+        file_key -> Varchar,
+        latest_version -> Int4,
+        keep_versions -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    governance_audit (id) {
+        id -> Int4,
+        action -> Varchar,
+        target -> Varchar,
+        reason -> Varchar,
+        details -> Jsonb,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    multipart_parts (id) {
+        id -> Int4,
+        multipart_id -> Int4,
+        part_number -> Int4,
+        part_hash -> Varchar,
+        part_size -> Int8,
+        file_path -> Varchar,
+    }
+}
+
+diesel::table! {
+    multipart_uploads (id) {
+        id -> Int4,
+        upload_id -> Varchar,
+        file_name -> Varchar,
+        file_size -> Nullable<Int8>,
+        content_type -> Nullable<Varchar>,
+        metadata -> Jsonb,
+        expected_hash -> Nullable<Varchar>,
+        root_id -> Int4,
+        created_at -> Timestamp,
+        namespace -> Nullable<Varchar>,
+        file_key -> Nullable<Varchar>,
+        keep_versions -> Nullable<Int4>,
+        tags -> Array<Text>,
+        expires_at -> Nullable<Timestamp>,
+        retain_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    namespace_retention (namespace) {
+        namespace -> Varchar,
+        retention_secs -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    outbox (id) {
+        id -> Int8,
+        kind -> Varchar,
+        payload -> Jsonb,
+        created_at -> Timestamp,
+        dispatched -> Bool,
+    }
+}
+
+diesel::table! {
+    refs (name) {
+        name -> Varchar,
+        file_hash -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    storage_roots (id) {
+        id -> Int4,
+        name -> Varchar,
+        path -> Varchar,
+        weight -> Int4,
+        min_free_bytes -> Int8,
+        state -> Varchar,
+    }
+}
+
+diesel::table! {
+    storage_events (id) {
+        id -> Int8,
+        created_at -> Timestamp,
+        kind -> Varchar,
+        file_hash -> Varchar,
+        file_name -> Varchar,
+        file_size -> Int8,
+        namespace -> Nullable<Varchar>,
+        file_key -> Nullable<Varchar>,
+        version -> Nullable<Int4>,
+        txid -> Int8,
+    }
+}
+
 diesel::table! {
     store (id) {
         id -> Int4,
@@ -7,5 +144,73 @@ diesel::table! {
         file_path -> Varchar,
         file_hash -> Varchar,
         file_is_error -> Bool,
+        root_id -> Nullable<Int4>,
+        file_size -> Nullable<Int8>,
+        content_type -> Nullable<Varchar>,
+        metadata -> Jsonb,
+        chunked -> Bool,
+        blake3_hash -> Nullable<Varchar>,
+        outboard_path -> Nullable<Varchar>,
+        key_id -> Nullable<Int4>,
+        version -> Nullable<Int4>,
+        created_at -> Timestamp,
+        tags -> Array<Text>,
+        generation -> Int8,
+        expires_at -> Nullable<Timestamp>,
+        retain_until -> Nullable<Timestamp>,
+        legal_hold -> Bool,
     }
 }
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int8,
+        webhook_id -> Int4,
+        outbox_id -> Int8,
+        state -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        last_status -> Nullable<Int4>,
+        last_error -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        url -> Varchar,
+        secret -> Varchar,
+        events -> Array<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(chunks -> storage_roots (root_id));
+diesel::joinable!(file_chunks -> chunks (chunk_id));
+diesel::joinable!(file_chunks -> store (store_id));
+diesel::joinable!(multipart_parts -> multipart_uploads (multipart_id));
+diesel::joinable!(multipart_uploads -> storage_roots (root_id));
+diesel::joinable!(store -> file_keys (key_id));
+diesel::joinable!(store -> storage_roots (root_id));
+diesel::joinable!(webhook_deliveries -> outbox (outbox_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    chunks,
+    file_chunks,
+    file_keys,
+    governance_audit,
+    multipart_parts,
+    multipart_uploads,
+    namespace_retention,
+    outbox,
+    refs,
+    storage_events,
+    storage_roots,
+    store,
+    webhook_deliveries,
+    webhooks,
+);
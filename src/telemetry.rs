@@ -0,0 +1,233 @@
+use diesel::connection::{Instrumentation, InstrumentationEvent};
+use dotenvy::dotenv;
+use hyper::{header::HeaderValue, Request, Response};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime,
+    trace::{Config, TracerProvider},
+    Resource,
+};
+use std::{
+    env,
+    future::Future,
+    io::{self, IsTerminal},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tonic::codegen::Service;
+use tower_layer::Layer;
+use tracing::{field, info, info_span, Instrument, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use uuid::Uuid;
+
+use crate::call_body::take_ready;
+
+/// Metadata key the request ID is read from and echoed back in.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request ID taken from a client, longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Longest statement recorded on a query span.
+const MAX_STATEMENT_LEN: usize = 2048;
+
+/// Flushes the spans still waiting for export on drop.
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Could not flush the remaining spans! Error: {}", e);
+            }
+        }
+    }
+}
+
+/// Sets up logging filtered by `RUST_LOG`, and exporting spans over OTLP
+/// when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Must be called within the
+/// runtime, the exporter runs on it.
+pub fn init() -> Result<Telemetry, Box<dyn std::error::Error>> {
+    dotenv().ok();
+
+    let endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty());
+    let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or("grpc-storage".to_owned());
+
+    let provider =
+        match &endpoint {
+            Some(endpoint) => Some(
+                opentelemetry_otlp::new_pipeline()
+                    .tracing()
+                    .with_exporter(
+                        opentelemetry_otlp::new_exporter()
+                            .tonic()
+                            .with_endpoint(endpoint),
+                    )
+                    .with_trace_config(Config::default().with_resource(Resource::new([
+                        KeyValue::new("service.name", service_name),
+                    ])))
+                    .install_batch(runtime::Tokio)?,
+            ),
+            None => None,
+        };
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(io::stderr)
+                .with_ansi(io::stderr().is_terminal()),
+        )
+        .with(provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("grpc-storage"))
+        }))
+        .try_init()?;
+
+    if let Some(endpoint) = endpoint {
+        info!("Exporting spans to {}", endpoint);
+    }
+
+    Ok(Telemetry { provider })
+}
+
+/// Records each Diesel query run within a span in a child span of it. Meant for
+/// `diesel::connection::set_default_instrumentation`.
+pub fn query_spans() -> Option<Box<dyn Instrumentation>> {
+    Some(Box::new(QuerySpans::default()))
+}
+
+#[derive(Default)]
+struct QuerySpans {
+    // Spans of the queries running, only transactions nest them
+    running: Vec<Span>,
+}
+
+impl Instrumentation for QuerySpans {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            // Polling in the background isn't worth a trace of its own
+            InstrumentationEvent::StartQuery { .. } if Span::current().is_none() => {
+                self.running.push(Span::none());
+            }
+            InstrumentationEvent::StartQuery { query, .. } => {
+                let query = query.to_string();
+                self.running.push(info_span!(
+                    "db.query",
+                    db.system = "postgresql",
+                    db.statement = statement(&query),
+                    otel.status_code = field::Empty,
+                    error = field::Empty,
+                ));
+            }
+            InstrumentationEvent::FinishQuery { error, .. } => {
+                if let Some(span) = self.running.pop() {
+                    if let Some(e) = error {
+                        span.record("otel.status_code", "ERROR");
+                        span.record("error", field::display(e));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The SQL of a query, without the values bound to it.
+fn statement(query: &str) -> &str {
+    let sql = query.split(" -- binds: ").next().unwrap_or_default();
+    if sql.len() <= MAX_STATEMENT_LEN {
+        return sql;
+    }
+
+    let mut end = MAX_STATEMENT_LEN;
+    while !sql.is_char_boundary(end) {
+        end -= 1;
+    }
+    &sql[..end]
+}
+
+/// Takes the request ID a client sent, or generates one.
+fn request_id(value: Option<&HeaderValue>) -> String {
+    value
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+        })
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Runs each call in a span carrying its request ID, and echoes the ID back
+/// in the response metadata.
+#[derive(Clone, Default)]
+pub struct RequestIdLayer;
+
+impl RequestIdLayer {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<ReqBody>) -> Self::Future {
+        let mut inner = take_ready(&mut self.inner);
+
+        let id = request_id(request.headers().get(REQUEST_ID_HEADER));
+        // Only valid header characters are kept
+        let header = HeaderValue::from_str(&id).expect("request ID is a valid header");
+        request
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER, header.clone());
+
+        let span = info_span!(
+            "rpc",
+            otel.name = request.uri().path(),
+            otel.kind = "server",
+            request_id = %id,
+        );
+
+        Box::pin(
+            async move {
+                let mut response = inner.call(request).await?;
+                response.headers_mut().insert(REQUEST_ID_HEADER, header);
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
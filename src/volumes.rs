@@ -0,0 +1,487 @@
+use dotenvy::dotenv;
+use fs4::{available_space, total_space};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env, fmt, io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Mutex, RwLock},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    db::DbState,
+    models::{NewStorageRoot, StorageRoot, StoreItem},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VolumeState {
+    Online,
+    ReadOnly,
+    Offline,
+}
+
+impl VolumeState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VolumeState::Online => "online",
+            VolumeState::ReadOnly => "read-only",
+            VolumeState::Offline => "offline",
+        }
+    }
+}
+
+impl FromStr for VolumeState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(VolumeState::Online),
+            "read-only" => Ok(VolumeState::ReadOnly),
+            "offline" => Ok(VolumeState::Offline),
+            _ => Err(format!("Unknown volume state: '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for VolumeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How a volume is chosen for a new upload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlacementPolicy {
+    /// Volume with the most available space (scaled by weight).
+    MostFree,
+    /// Smooth weighted round robin.
+    RoundRobin,
+    /// Weighted rendezvous hashing of the file name.
+    Hash,
+}
+
+impl FromStr for PlacementPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "most-free" => Ok(PlacementPolicy::MostFree),
+            "round-robin" => Ok(PlacementPolicy::RoundRobin),
+            "hash" => Ok(PlacementPolicy::Hash),
+            _ => Err(format!("Unknown placement policy: '{}'", s)),
+        }
+    }
+}
+
+pub struct Volume {
+    pub id: i32,
+    pub name: String,
+    pub path: PathBuf,
+    pub weight: u32,
+    pub min_free_bytes: u64,
+    state: RwLock<VolumeState>,
+}
+
+impl Volume {
+    fn from_root(root: StorageRoot) -> Self {
+        let state = root.state.parse().unwrap_or_else(|e| {
+            warn!("Volume '{}': {}, assuming online", root.name, e);
+            VolumeState::Online
+        });
+
+        Self {
+            id: root.id,
+            name: root.name,
+            path: PathBuf::from(root.path),
+            weight: root.weight.max(0) as u32,
+            min_free_bytes: root.min_free_bytes.max(0) as u64,
+            state: RwLock::new(state),
+        }
+    }
+
+    pub fn state(&self) -> VolumeState {
+        *self.state.read().unwrap()
+    }
+
+    pub fn free_space(&self) -> io::Result<u64> {
+        available_space(&self.path)
+    }
+
+    pub fn total_space(&self) -> io::Result<u64> {
+        total_space(&self.path)
+    }
+
+    /// Share of the volume's file system in use, in percent.
+    pub fn usage_percent(&self) -> io::Result<f64> {
+        let total = self.total_space()?;
+        if total == 0 {
+            return Ok(100.0);
+        }
+
+        let free = self.free_space()?.min(total);
+        Ok((total - free) as f64 * 100.0 / total as f64)
+    }
+
+    /// Free space left above the volume's watermarks, `None` if the volume
+    /// can't take new files.
+    fn headroom(&self, high_watermark: f64) -> io::Result<Option<u64>> {
+        if self.state() != VolumeState::Online || self.weight == 0 {
+            return Ok(None);
+        }
+
+        if self.usage_percent()? >= high_watermark {
+            return Ok(None);
+        }
+
+        let free = self.free_space()?;
+        Ok(free
+            .checked_sub(self.min_free_bytes)
+            .filter(|room| *room > 0))
+    }
+}
+
+pub struct VolumeSet {
+    volumes: Vec<Volume>,
+    retired: RwLock<HashMap<i32, PathBuf>>,
+    policy: PlacementPolicy,
+    rr_weights: Mutex<Vec<i64>>,
+    pub low_watermark: f64,  //in percent of disk usage
+    pub high_watermark: f64, //in percent of disk usage
+}
+
+impl VolumeSet {
+    /// Builds the volume set from `STORAGE_VOLUMES`, a `;` separated list of
+    /// `name=path[,weight[,min_free_bytes]]` entries. Falls back to a single
+    /// volume made of `STORAGE_ROOT_NAME` and `STORAGE_FOLDER`.
+    pub fn from_env(db: &DbState) -> Self {
+        dotenv().ok();
+
+        let configured = match env::var("STORAGE_VOLUMES") {
+            Ok(list) => list
+                .split(';')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| {
+                    parse_volume(entry).unwrap_or_else(|e| {
+                        error!("'STORAGE_VOLUMES' - {}", e);
+                        panic!()
+                    })
+                })
+                .collect(),
+            Err(_) => {
+                let dir = env::var("STORAGE_FOLDER")
+                    .unwrap_or(env::current_dir().unwrap().display().to_string());
+                vec![NewStorageRoot {
+                    name: env::var("STORAGE_ROOT_NAME").unwrap_or("default".to_owned()),
+                    path: dir,
+                    weight: 1,
+                    min_free_bytes: 0,
+                }]
+            }
+        };
+
+        if configured.is_empty() {
+            error!("'STORAGE_VOLUMES' - at least one volume should be configured!");
+            panic!()
+        }
+
+        let policy = env::var("STORAGE_PLACEMENT")
+            .unwrap_or("most-free".to_owned())
+            .parse()
+            .unwrap_or_else(|e| {
+                error!("'STORAGE_PLACEMENT' - {} (most-free, round-robin, hash)", e);
+                panic!()
+            });
+
+        let low_watermark = parse_watermark("DISK_LOW_WATERMARK", 90.0);
+        let high_watermark = parse_watermark("DISK_HIGH_WATERMARK", 95.0);
+        if low_watermark > high_watermark {
+            error!("'DISK_LOW_WATERMARK' - should not be above 'DISK_HIGH_WATERMARK'");
+            panic!()
+        }
+
+        let volumes: Vec<Volume> = configured
+            .iter()
+            .map(|root| {
+                let dir = PathBuf::from(&root.path);
+                if !dir.exists() || !dir.is_dir() {
+                    error!(
+                        "Volume '{}' path \"{}\" - doesn't exists or it's not a directory!",
+                        root.name, root.path
+                    );
+                    panic!()
+                }
+
+                let root = db.register_storage_root(root).unwrap_or_else(|e| {
+                    error!("Couldn't register storage root '{}'! Err: {}", root.name, e);
+                    panic!()
+                });
+
+                info!(
+                    "Storage volume '{}': {} (weight: {}, min free: {} bytes, {})",
+                    root.name, root.path, root.weight, root.min_free_bytes, root.state
+                );
+
+                Volume::from_root(root)
+            })
+            .collect();
+
+        Self {
+            rr_weights: Mutex::new(vec![0; volumes.len()]),
+            volumes,
+            retired: RwLock::new(HashMap::new()),
+            policy,
+            low_watermark,
+            high_watermark,
+        }
+    }
+
+    pub fn volumes(&self) -> &[Volume] {
+        &self.volumes
+    }
+
+    pub fn get(&self, volume_id: i32) -> Option<&Volume> {
+        self.volumes.iter().find(|vol| vol.id == volume_id)
+    }
+
+    pub fn get_by_name(&self, volume_name: &str) -> Option<&Volume> {
+        self.volumes.iter().find(|vol| vol.name == volume_name)
+    }
+
+    /// Picks a volume with at least `reserve` bytes of headroom for a new file
+    /// according to the placement policy. `key` is only used by the hash-based
+    /// policy.
+    pub fn place(&self, db: &DbState, key: &str, reserve: u64) -> Option<&Volume> {
+        let candidates: Vec<(usize, u64)> = self
+            .volumes
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, vol)| match vol.headroom(self.high_watermark) {
+                Ok(room) => room.filter(|room| *room >= reserve).map(|room| (idx, room)),
+                Err(e) => {
+                    warn!("Couldn't get free space of volume '{}': {}", vol.name, e);
+                    self.report_io_error(db, vol, &e, false);
+                    None
+                }
+            })
+            .collect();
+
+        let chosen = match self.policy {
+            PlacementPolicy::MostFree => candidates
+                .iter()
+                .max_by_key(|(idx, room)| *room as u128 * self.volumes[*idx].weight as u128)
+                .map(|(idx, _)| *idx),
+            PlacementPolicy::RoundRobin => {
+                let mut current = self.rr_weights.lock().unwrap();
+                let total: i64 = candidates
+                    .iter()
+                    .map(|(idx, _)| self.volumes[*idx].weight as i64)
+                    .sum();
+
+                for (idx, _) in &candidates {
+                    current[*idx] += self.volumes[*idx].weight as i64;
+                }
+
+                let best = candidates
+                    .iter()
+                    .map(|(idx, _)| *idx)
+                    .max_by_key(|idx| current[*idx]);
+                if let Some(idx) = best {
+                    current[idx] -= total;
+                }
+                best
+            }
+            PlacementPolicy::Hash => candidates
+                .iter()
+                .map(|(idx, _)| (*idx, rendezvous_score(key, &self.volumes[*idx])))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(idx, _)| idx),
+        };
+
+        chosen.map(|idx| &self.volumes[idx])
+    }
+
+    /// Resolves the on-disk location of a stored item together with the volume
+    /// it lives on. Legacy records without a root keep their absolute path.
+    pub fn locate(&self, db: &DbState, item: &StoreItem) -> (Option<&Volume>, PathBuf) {
+        let Some(rec_root) = item.root_id else {
+            return (None, PathBuf::from(&item.file_path));
+        };
+
+        if let Some(vol) = self.get(rec_root) {
+            return (Some(vol), vol.path.join(&item.file_path));
+        }
+
+        if let Some(dir) = self.retired.read().unwrap().get(&rec_root) {
+            return (None, dir.join(&item.file_path));
+        }
+
+        match db.get_storage_root(rec_root) {
+            Some(root) => {
+                let dir = PathBuf::from(root.path);
+                let path = dir.join(&item.file_path);
+                self.retired.write().unwrap().insert(rec_root, dir);
+                (None, path)
+            }
+            None => {
+                warn!(
+                    "Unknown storage root id:{} for \"{}\"",
+                    rec_root, item.file_path
+                );
+                (None, PathBuf::from(&item.file_path))
+            }
+        }
+    }
+
+    pub fn set_state(
+        &self,
+        db: &DbState,
+        volume: &Volume,
+        state: VolumeState,
+    ) -> Result<(), diesel::result::Error> {
+        db.set_storage_root_state(volume.id, state.as_str())?;
+        *volume.state.write().unwrap() = state;
+        Ok(())
+    }
+
+    /// Whether the volume can keep receiving data: it's online and stays below
+    /// the high watermark and its own free-space floor.
+    pub fn has_room(&self, volume: &Volume) -> io::Result<bool> {
+        Ok(volume.headroom(self.high_watermark)?.is_some())
+    }
+
+    /// Whether any volume can still take new files.
+    pub fn any_writable(&self) -> bool {
+        self.volumes
+            .iter()
+            .any(|vol| self.has_room(vol).unwrap_or(false))
+    }
+
+    /// Whether any online volume dropped below the low watermark, i.e. uploads
+    /// paused because of full disks may be resumed.
+    pub fn below_low_watermark(&self) -> bool {
+        self.volumes.iter().any(|vol| {
+            vol.state() == VolumeState::Online
+                && vol.weight > 0
+                && vol
+                    .usage_percent()
+                    .is_ok_and(|usage| usage < self.low_watermark)
+        })
+    }
+
+    /// Checks that the volume root is still accessible, taking the volume
+    /// offline if it's not.
+    pub fn check_reachable(&self, db: &DbState, volume: &Volume) -> bool {
+        match volume.path.metadata() {
+            Ok(_) => volume.state() != VolumeState::Offline,
+            Err(e) => {
+                self.report_io_error(db, volume, &e, false);
+                false
+            }
+        }
+    }
+
+    /// Downgrades a volume after an I/O error. A volume whose root can't be
+    /// accessed anymore goes offline, otherwise failed writes turn it read-only.
+    pub fn report_io_error(&self, db: &DbState, volume: &Volume, err: &io::Error, write: bool) {
+        if err.kind() == io::ErrorKind::NotFound && volume.path.is_dir() {
+            return;
+        }
+
+        // A full disk is handled by the watermarks, the volume itself is fine.
+        if err.kind() == io::ErrorKind::StorageFull {
+            return;
+        }
+        // Neither does a file name taken by a concurrent upload.
+        if err.kind() == io::ErrorKind::AlreadyExists {
+            return;
+        }
+
+        let new_state = if volume.path.metadata().is_err() {
+            VolumeState::Offline
+        } else if write {
+            VolumeState::ReadOnly
+        } else {
+            return;
+        };
+
+        if volume.state() == new_state || volume.state() == VolumeState::Offline {
+            return;
+        }
+
+        warn!(
+            "Volume '{}' switched to {} after I/O error: {}",
+            volume.name, new_state, err
+        );
+        if let Err(e) = self.set_state(db, volume, new_state) {
+            error!("Could not update volume state in DB! Error: {}", e);
+            *volume.state.write().unwrap() = new_state;
+        }
+    }
+}
+
+fn parse_volume(entry: &str) -> Result<NewStorageRoot, String> {
+    let (name, spec) = entry.split_once('=').ok_or(format!(
+        "'{}' should look like name=path[,weight[,min_free_bytes]]",
+        entry
+    ))?;
+    let mut fields = spec.split(',').map(str::trim);
+
+    let path = fields.next().unwrap_or_default().to_owned();
+    if name.trim().is_empty() || path.is_empty() {
+        return Err(format!("'{}' - volume name and path are required", entry));
+    }
+
+    let weight = match fields.next() {
+        Some(val) => val.parse::<i32>().ok().filter(|w| *w >= 0).ok_or(format!(
+            "'{}' - weight should be a non-negative integer",
+            entry
+        ))?,
+        None => 1,
+    };
+
+    let min_free_bytes = match fields.next() {
+        Some(val) => val.parse::<i64>().ok().filter(|b| *b >= 0).ok_or(format!(
+            "'{}' - min_free_bytes should be a non-negative integer",
+            entry
+        ))?,
+        None => 0,
+    };
+
+    Ok(NewStorageRoot {
+        name: name.trim().to_owned(),
+        path,
+        weight,
+        min_free_bytes,
+    })
+}
+
+fn parse_watermark(var: &str, default: f64) -> f64 {
+    match env::var(var) {
+        Ok(val) => val
+            .parse::<f64>()
+            .ok()
+            .filter(|w| (0.0..=100.0).contains(w))
+            .unwrap_or_else(|| {
+                error!(
+                    "'{}' - should be a disk usage percentage in range: [0;100]",
+                    var
+                );
+                panic!()
+            }),
+        Err(_) => default,
+    }
+}
+
+fn rendezvous_score(key: &str, volume: &Volume) -> f64 {
+    let digest = Sha256::new()
+        .chain_update(key.as_bytes())
+        .chain_update(volume.name.as_bytes())
+        .finalize();
+    let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    let unit = (hash as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+
+    volume.weight as f64 / -unit.ln()
+}
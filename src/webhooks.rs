@@ -0,0 +1,566 @@
+// Admin calls fail with the `Status` sent back to the client
+#![allow(clippy::result_large_err)]
+
+use chrono::{TimeDelta, Utc};
+use dotenvy::dotenv;
+use hmac::{Hmac, Mac};
+use reqwest::{header, redirect, Client, Url};
+use sha2::Sha256;
+use std::{env, time::Duration};
+use tokio::{task::JoinSet, time::MissedTickBehavior};
+use tonic::Status;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    db::{DbState, DELIVERY_DEAD, DELIVERY_DELIVERED, DELIVERY_PENDING},
+    events::EventKind,
+    models::{NewWebhook, OutboxEvent, Webhook, WebhookDelivery},
+    storage::{
+        DeliveryState, EventType, ListDeliveriesRequest, ListDeliveriesResponse,
+        ListWebhooksResponse, RegisterWebhookRequest, Webhook as ProtoWebhook,
+        WebhookDelivery as ProtoWebhookDelivery,
+    },
+};
+
+/// Outbox events handed out to the webhooks at once.
+const FAN_OUT_BATCH_SIZE: i64 = 500;
+
+/// Deliveries attempted at once by a dispatcher.
+const DELIVERY_BATCH_SIZE: i64 = 32;
+
+/// Deliveries returned by one `ListDeliveries` call at most.
+const MAX_LISTED_DELIVERIES: u32 = 1000;
+
+/// Longest wait before attempting a failed delivery again.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How long successful deliveries are kept.
+const DELIVERY_RETENTION: TimeDelta = TimeDelta::days(7);
+
+/// How often old deliveries are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Longest part of an error response kept with the delivery.
+const MAX_ERROR_LEN: usize = 512;
+
+/// How often and how persistently events are delivered.
+#[derive(Clone, Copy, Debug)]
+struct WebhookConfig {
+    poll_interval: Duration,
+    max_attempts: i32,
+    backoff: Duration,
+    timeout: Duration,
+}
+
+impl WebhookConfig {
+    /// Reads `WEBHOOK_POLL_INTERVAL_MS` (0 disables the dispatcher),
+    /// `WEBHOOK_MAX_ATTEMPTS`, `WEBHOOK_BACKOFF_SECS` and
+    /// `WEBHOOK_TIMEOUT_SECS`.
+    fn from_env() -> Option<Self> {
+        dotenv().ok();
+
+        let poll_interval: u64 = env::var("WEBHOOK_POLL_INTERVAL_MS")
+            .unwrap_or("1000".to_owned())
+            .parse()
+            .unwrap_or_else(|_| {
+                error!(
+                    "'WEBHOOK_POLL_INTERVAL_MS' - should be an integer value in range: [0;{}]",
+                    u64::MAX
+                );
+                panic!()
+            });
+
+        let max_attempts: i32 = env::var("WEBHOOK_MAX_ATTEMPTS")
+            .unwrap_or("8".to_owned())
+            .parse()
+            .ok()
+            .filter(|attempts| *attempts >= 1)
+            .unwrap_or_else(|| {
+                error!(
+                    "'WEBHOOK_MAX_ATTEMPTS' - should be an integer value in range: [1;{}]",
+                    i32::MAX
+                );
+                panic!()
+            });
+
+        let backoff: u64 = env::var("WEBHOOK_BACKOFF_SECS")
+            .unwrap_or("10".to_owned())
+            .parse()
+            .ok()
+            .filter(|secs| (1..=MAX_BACKOFF.as_secs()).contains(secs))
+            .unwrap_or_else(|| {
+                error!(
+                    "'WEBHOOK_BACKOFF_SECS' - should be an integer value in range: [1;{}]",
+                    MAX_BACKOFF.as_secs()
+                );
+                panic!()
+            });
+
+        let timeout: u64 = env::var("WEBHOOK_TIMEOUT_SECS")
+            .unwrap_or("10".to_owned())
+            .parse()
+            .ok()
+            .filter(|secs| (1..=3600).contains(secs))
+            .unwrap_or_else(|| {
+                error!("'WEBHOOK_TIMEOUT_SECS' - should be an integer value in range: [1;3600]");
+                panic!()
+            });
+
+        (poll_interval > 0).then(|| Self {
+            poll_interval: Duration::from_millis(poll_interval),
+            max_attempts,
+            backoff: Duration::from_secs(backoff),
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+
+    /// Wait after the given number of failed attempts, doubling each time.
+    fn backoff_after(&self, attempts: i32) -> Duration {
+        let factor = 1u32 << attempts.clamp(1, 16).saturating_sub(1);
+        (self.backoff * factor).min(MAX_BACKOFF)
+    }
+}
+
+/// Webhooks notified of uploads, deletions and expirations. The changes of
+/// the store add their events to the outbox in the same transaction, the
+/// dispatcher of every server then delivers them at least once.
+#[derive(Clone)]
+pub struct Webhooks {
+    db: DbState,
+}
+
+impl Webhooks {
+    pub fn new(db: DbState) -> Self {
+        Self { db }
+    }
+
+    pub fn register(&self, request: RegisterWebhookRequest) -> Result<ProtoWebhook, Status> {
+        validate_url(&request.url)?;
+
+        let mut events = Vec::new();
+        for event in request.events {
+            let kind = match EventType::try_from(event) {
+                Ok(EventType::FileUploaded) => EventKind::FileUploaded,
+                Ok(EventType::FileDeleted) => EventKind::FileDeleted,
+                Ok(EventType::FileExpired) => EventKind::FileExpired,
+                _ => {
+                    return Err(Status::invalid_argument(
+                        "Webhooks only get upload, delete and expire events!",
+                    ))
+                }
+            };
+            if !events.contains(&kind.as_str().to_owned()) {
+                events.push(kind.as_str().to_owned());
+            }
+        }
+
+        let secret = match request.secret.as_str() {
+            "" => Uuid::new_v4().simple().to_string(),
+            secret => secret.to_owned(),
+        };
+
+        let webhook = self
+            .db
+            .add_webhook(&NewWebhook {
+                url: request.url,
+                secret,
+                events,
+            })
+            .map_err(|e| {
+                error!("Error during adding webhook to DB! Error: {}", &e);
+                Status::internal("Internal service error!")
+            })?;
+
+        info!("Registered webhook {} to {}", webhook.id, webhook.url);
+        let secret = webhook.secret.clone();
+        Ok(ProtoWebhook {
+            secret,
+            ..webhook_message(webhook)
+        })
+    }
+
+    pub fn list(&self) -> Result<ListWebhooksResponse, Status> {
+        let webhooks = self.db.get_webhooks().map_err(|e| {
+            error!("Error during loading webhooks from DB! Error: {}", &e);
+            Status::internal("Internal service error!")
+        })?;
+
+        Ok(ListWebhooksResponse {
+            webhooks: webhooks.into_iter().map(webhook_message).collect(),
+        })
+    }
+
+    pub fn delete(&self, webhook: u32) -> Result<(), Status> {
+        let webhook =
+            i32::try_from(webhook).map_err(|_| Status::not_found("Webhook not found!"))?;
+
+        match self.db.remove_webhook(webhook) {
+            Ok(()) => {
+                info!("Deleted webhook {}", webhook);
+                Ok(())
+            }
+            Err(diesel::result::Error::NotFound) => Err(Status::not_found("Webhook not found!")),
+            Err(e) => {
+                error!("Error during removing webhook from DB! Error: {}", &e);
+                Err(Status::internal("Internal service error!"))
+            }
+        }
+    }
+
+    pub fn list_deliveries(
+        &self,
+        request: ListDeliveriesRequest,
+    ) -> Result<ListDeliveriesResponse, Status> {
+        let limit = match request.limit {
+            0 => MAX_LISTED_DELIVERIES,
+            limit => limit.min(MAX_LISTED_DELIVERIES),
+        };
+        let before_id = match request.page_token.as_str() {
+            "" => None,
+            token => Some(
+                token
+                    .parse()
+                    .map_err(|_| Status::invalid_argument("Invalid page token!"))?,
+            ),
+        };
+        let webhook = match request.webhook_id {
+            0 => None,
+            id => Some(i32::try_from(id).map_err(|_| Status::not_found("Webhook not found!"))?),
+        };
+        let state = match request.state.map(DeliveryState::try_from) {
+            None => None,
+            Some(Ok(state)) => Some(state_name(state)),
+            Some(Err(_)) => return Err(Status::invalid_argument("Unknown delivery state!")),
+        };
+
+        let deliveries = self
+            .db
+            .list_deliveries(webhook, state, before_id, limit as i64)
+            .map_err(|e| {
+                error!(
+                    "Error during loading webhook deliveries from DB! Error: {}",
+                    &e
+                );
+                Status::internal("Internal service error!")
+            })?;
+
+        let next_page_token = match deliveries.last() {
+            Some((last, _)) if deliveries.len() == limit as usize => last.id.to_string(),
+            _ => String::new(),
+        };
+
+        Ok(ListDeliveriesResponse {
+            deliveries: deliveries
+                .into_iter()
+                .map(|(delivery, event)| delivery_message(delivery, &event))
+                .collect(),
+            next_page_token,
+        })
+    }
+
+    pub fn retry(&self, delivery: u64) -> Result<ProtoWebhookDelivery, Status> {
+        let delivery =
+            i64::try_from(delivery).map_err(|_| Status::not_found("Delivery not found!"))?;
+
+        match self.db.retry_delivery(delivery) {
+            Ok((delivery, event)) => Ok(delivery_message(delivery, &event)),
+            Err(diesel::result::Error::NotFound) => Err(Status::not_found("Delivery not found!")),
+            Err(e) => {
+                error!("Error during retrying webhook delivery! Error: {}", &e);
+                Err(Status::internal("Internal service error!"))
+            }
+        }
+    }
+
+    /// Starts delivering the outbox events to the webhooks, and removing the
+    /// old deliveries.
+    pub fn spawn_dispatcher(&self) {
+        let Some(config) = WebhookConfig::from_env() else {
+            info!("Webhook dispatcher is disabled");
+            return;
+        };
+        // HTTPS endpoints are checked against the Mozilla root certificates
+        let client = Client::builder()
+            .user_agent("grpc-storage")
+            .timeout(config.timeout)
+            .redirect(redirect::Policy::none())
+            .build()
+            .unwrap_or_else(|e| {
+                error!("Could not create the webhook HTTP client! Error: {}", e);
+                panic!()
+            });
+        let webhooks = self.clone();
+
+        tokio::spawn(async move {
+            let mut poll = tokio::time::interval(config.poll_interval);
+            poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+            prune.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                tokio::select! {
+                    _ = poll.tick() => webhooks.dispatch(&client, config).await,
+                    _ = prune.tick() => webhooks.prune(),
+                }
+            }
+        });
+    }
+
+    /// Hands the new outbox events out to the webhooks, then attempts the due
+    /// deliveries until none are left.
+    async fn dispatch(&self, client: &Client, config: WebhookConfig) {
+        loop {
+            match self.db.fan_out_outbox(FAN_OUT_BATCH_SIZE) {
+                Ok(count) if count < FAN_OUT_BATCH_SIZE as usize => break,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Error during handing out webhook events! Error: {}", &e);
+                    break;
+                }
+            }
+        }
+
+        // Claimed deliveries are left to other dispatchers for a while, in
+        // case this one stops halfway
+        let lease = config.timeout.as_secs() as i64 + 60;
+        loop {
+            let claimed = match self.db.claim_deliveries(DELIVERY_BATCH_SIZE, lease) {
+                Ok(claimed) => claimed,
+                Err(e) => {
+                    error!(
+                        "Error during loading webhook deliveries from DB! Error: {}",
+                        &e
+                    );
+                    return;
+                }
+            };
+            let done = (claimed.len() as i64) < DELIVERY_BATCH_SIZE;
+
+            let mut attempts = JoinSet::new();
+            for (delivery, webhook, event) in claimed {
+                let db = self.db.clone();
+                let client = client.clone();
+                attempts.spawn(async move {
+                    let result = post_event(&client, &webhook, &delivery, &event).await;
+                    finish_delivery(&db, &config, &webhook, &delivery, result);
+                });
+            }
+            while attempts.join_next().await.is_some() {}
+
+            if done {
+                return;
+            }
+        }
+    }
+
+    fn prune(&self) {
+        match self
+            .db
+            .prune_deliveries((Utc::now() - DELIVERY_RETENTION).naive_utc())
+        {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} old webhook deliveries", removed),
+            Err(e) => error!(
+                "Error during removing old webhook deliveries! Error: {}",
+                &e
+            ),
+        }
+    }
+}
+
+/// Why an attempt failed, with the HTTP status if there was a response.
+struct AttemptError {
+    status: Option<u16>,
+    message: String,
+}
+
+impl AttemptError {
+    fn new(message: impl ToString) -> Self {
+        Self {
+            status: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Records the outcome of an attempt: delivered, due again after the backoff
+/// or dead after the last attempt.
+fn finish_delivery(
+    db: &DbState,
+    config: &WebhookConfig,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+    result: Result<u16, AttemptError>,
+) {
+    let attempts = delivery.attempts + 1;
+    let (state, retry_in, status, message) = match result {
+        Ok(status) => (DELIVERY_DELIVERED, 0, Some(status), None),
+        Err(e) => {
+            let state = if attempts >= config.max_attempts {
+                warn!(
+                    "Giving up on delivery {} to webhook {} after {} attempts: {}",
+                    delivery.id, webhook.id, attempts, e.message
+                );
+                DELIVERY_DEAD
+            } else {
+                warn!(
+                    "Delivery {} to webhook {} failed: {}",
+                    delivery.id, webhook.id, e.message
+                );
+                DELIVERY_PENDING
+            };
+            let retry_in = config.backoff_after(attempts).as_secs() as i64;
+            (state, retry_in, e.status, Some(e.message))
+        }
+    };
+
+    if let Err(e) = db.finish_delivery(delivery.id, state, retry_in, status.map(i32::from), message)
+    {
+        error!(
+            "Could not record delivery {} to webhook {}! Error: {}",
+            delivery.id, webhook.id, e
+        );
+    }
+}
+
+/// POSTs the event to the webhook and returns the HTTP status if it's a
+/// success one. Redirects aren't followed.
+async fn post_event(
+    client: &Client,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+    event: &OutboxEvent,
+) -> Result<u16, AttemptError> {
+    let mut payload = event.payload.clone();
+    if let Some(fields) = payload.as_object_mut() {
+        fields.insert("id".to_owned(), event.id.into());
+    }
+    let body = payload.to_string();
+    let timestamp = Utc::now().timestamp().to_string();
+    let signature = sign(&webhook.secret, &timestamp, &body);
+
+    let response = client
+        .post(&webhook.url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("X-Storage-Event", &event.kind)
+        .header("X-Storage-Delivery", delivery.id.to_string())
+        .header("X-Storage-Timestamp", &timestamp)
+        .header("X-Storage-Signature", format!("sha256={}", signature))
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| {
+            let mut message = if e.is_timeout() {
+                "Timed out".to_owned()
+            } else {
+                // The source tells what went wrong, e.g. a refused connection
+                // or an invalid certificate
+                let mut message = e.to_string();
+                let mut source = std::error::Error::source(&e);
+                while let Some(cause) = source {
+                    let cause_message = cause.to_string();
+                    if !message.ends_with(&cause_message) {
+                        message = format!("{}: {}", message, cause_message);
+                    }
+                    source = cause.source();
+                }
+                message
+            };
+            message.truncate(MAX_ERROR_LEN);
+            AttemptError::new(message)
+        })?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16())
+    } else {
+        Err(AttemptError {
+            status: Some(status.as_u16()),
+            message: format!("HTTP {}", status),
+        })
+    }
+}
+
+/// Accepts `http://` and `https://` URLs with a host.
+fn validate_url(url: &str) -> Result<(), Status> {
+    let url = Url::parse(url).map_err(|_| Status::invalid_argument("Invalid webhook URL!"))?;
+
+    match url.scheme() {
+        "http" | "https" if url.host().is_some() => Ok(()),
+        "http" | "https" => Err(Status::invalid_argument("Invalid webhook URL!")),
+        _ => Err(Status::invalid_argument(
+            "Webhook URLs must be http:// or https:// ones!",
+        )),
+    }
+}
+
+/// HMAC-SHA256 of `<timestamp>.<body>`, returned hex encoded.
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+fn event_type(kind: &str) -> EventType {
+    match EventKind::parse(kind) {
+        Some(EventKind::FileDeleted) => EventType::FileDeleted,
+        Some(EventKind::FileExpired) => EventType::FileExpired,
+        Some(EventKind::FileCorrupted) => EventType::FileCorrupted,
+        _ => EventType::FileUploaded,
+    }
+}
+
+fn state_name(state: DeliveryState) -> &'static str {
+    match state {
+        DeliveryState::Pending => DELIVERY_PENDING,
+        DeliveryState::Delivered => DELIVERY_DELIVERED,
+        DeliveryState::Dead => DELIVERY_DEAD,
+    }
+}
+
+fn delivery_state(state: &str) -> DeliveryState {
+    match state {
+        DELIVERY_DELIVERED => DeliveryState::Delivered,
+        DELIVERY_DEAD => DeliveryState::Dead,
+        _ => DeliveryState::Pending,
+    }
+}
+
+/// The secret is left out, it's only returned on registration.
+fn webhook_message(webhook: Webhook) -> ProtoWebhook {
+    ProtoWebhook {
+        id: webhook.id as u32,
+        url: webhook.url,
+        secret: String::new(),
+        events: webhook
+            .events
+            .iter()
+            .map(|kind| event_type(kind) as i32)
+            .collect(),
+        created_at: webhook.created_at.and_utc().timestamp(),
+    }
+}
+
+fn delivery_message(delivery: WebhookDelivery, event: &OutboxEvent) -> ProtoWebhookDelivery {
+    ProtoWebhookDelivery {
+        id: delivery.id as u64,
+        webhook_id: delivery.webhook_id as u32,
+        event_id: event.id as u64,
+        event_type: event_type(&event.kind) as i32,
+        file_hash: event.payload["fileHash"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned(),
+        state: delivery_state(&delivery.state) as i32,
+        attempts: delivery.attempts as u32,
+        next_attempt_at: delivery.next_attempt_at.and_utc().timestamp(),
+        last_status: delivery.last_status.unwrap_or_default() as u32,
+        last_error: delivery.last_error.unwrap_or_default(),
+        created_at: delivery.created_at.and_utc().timestamp(),
+        updated_at: delivery.updated_at.and_utc().timestamp(),
+    }
+}
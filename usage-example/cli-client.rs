@@ -1,18 +1,64 @@
-use grpc_storage::storage::{
-    storage_client::StorageClient, DeleteFileRequest, FetchFileRequest, UploadFileRequest,
+use grpc_storage::{
+    bao,
+    storage::{
+        admin_client::AdminClient, expiration, fetch_file_response::Data as FetchData,
+        storage_client::StorageClient, upload_archive_request, upload_part_request,
+        AbortMultipartUploadRequest, ArchiveFormat, BatchDeleteRequest, BatchStatRequest,
+        CompleteMultipartUploadRequest, CompletedPart, CreateMultipartUploadRequest,
+        DeleteFileRequest, DeleteRefRequest, DeleteWebhookRequest, DeliveryState, EventType,
+        Expiration, ExtendTtlRequest, FetchArchiveRequest, FetchFileRequest, FetchManyRequest,
+        FileInfo, FileKey, GetRefRequest, GetStatusRequest, HasFilesRequest, ListDeliveriesRequest,
+        ListFilesRequest, ListRefsRequest, ListVersionsRequest, ListWebhooksRequest,
+        OverrideRetentionRequest, QueryAuditLogRequest, RegisterWebhookRequest,
+        RetryDeliveryRequest, ServiceMode, SetLegalHoldRequest, SetModeRequest,
+        SetNamespaceRetentionRequest, SetRefRequest, SetRetentionRequest, UpdateMetadataRequest,
+        UploadArchiveHeader, UploadArchiveRequest, UploadFileRequest, UploadHeader,
+        UploadPartHeader, UploadPartRequest, WatchEventsRequest,
+    },
 };
+use sha2::{Digest, Sha256};
 use std::{
+    collections::HashMap,
     env,
     fs::File,
     io::{Read, Write},
+    path::Path,
 };
-use tonic::transport::Channel;
+use tonic::{
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::Channel,
+    Request, Status,
+};
+
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+type Client = StorageClient<InterceptedService<Channel, ClientId>>;
+
+/// Names the client with `CLIENT_ID` (if set) in the audit log of the server.
+#[derive(Clone)]
+struct ClientId(Option<String>);
+
+impl Interceptor for ClientId {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(id) = &self.0 {
+            let id = id
+                .parse()
+                .map_err(|_| Status::invalid_argument("Invalid CLIENT_ID"))?;
+            request.metadata_mut().insert("x-client-id", id);
+        }
+        Ok(request)
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
 
-    let mut client = StorageClient::connect(format!("http://{}", env::var("SERVER_ADDR")?)).await?;
+    let channel = Channel::from_shared(format!("http://{}", env::var("SERVER_ADDR")?))?
+        .connect()
+        .await?;
+    let mut client =
+        StorageClient::with_interceptor(channel.clone(), ClientId(env::var("CLIENT_ID").ok()));
 
     // Example Usage:
     let command = env::args().nth(1).expect("No command provided");
@@ -20,19 +66,643 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match command.as_str() {
         "upload" => {
             let file_path = env::args().nth(2).expect("No file path provided");
-            upload_file(&mut client, file_path).await?;
+            let args: Vec<String> = env::args().skip(3).collect();
+            let header = UploadHeader {
+                content_type: args
+                    .first()
+                    .filter(|arg| !arg.starts_with("--"))
+                    .cloned()
+                    .unwrap_or_default(),
+                metadata: metadata_args(&args),
+                skip_if_exists: args.iter().any(|arg| arg == "--skip-existing"),
+                key: flag_value(&args, "--key").map(|key| FileKey {
+                    namespace: flag_value(&args, "--namespace")
+                        .cloned()
+                        .unwrap_or_default(),
+                    key: key.clone(),
+                }),
+                keep_versions: flag_value(&args, "--keep").map(|keep| {
+                    keep.parse()
+                        .expect("'--keep' requires a number of versions")
+                }),
+                tags: flag_values(&args, "--tag"),
+                expiration: expiration_args(&args),
+                retain_until: flag_value(&args, "--retain-until")
+                    .map(|at| {
+                        at.parse()
+                            .expect("'--retain-until' requires a unix timestamp")
+                    })
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            upload_file(&mut client, file_path, header).await?;
+        }
+        "upload-multipart" => {
+            let file_path = env::args().nth(2).expect("No file path provided");
+            let part_size: usize = env::args()
+                .nth(3)
+                .map(|size| size.parse().expect("Part size should be a number of MiB"))
+                .unwrap_or(8);
+            upload_multipart(&mut client, file_path, part_size * 1024 * 1024).await?;
+        }
+        "upload-archive" => {
+            let file_path = env::args().nth(2).expect("No file path provided");
+            upload_archive(&mut client, file_path).await?;
         }
         "fetch" => {
             let file_hash = env::args().nth(2).expect("No file hash provided");
-            fetch_file(&mut client, file_hash, env::args().nth(3)).await?;
+            let args: Vec<String> = env::args().skip(3).collect();
+            let file_name = args.first().filter(|arg| !arg.starts_with("--")).cloned();
+
+            let (offset, length) = match args.iter().position(|arg| arg == "--range") {
+                Some(pos) => {
+                    let range = args
+                        .get(pos + 1)
+                        .expect("'--range' requires <offset>:[length]");
+                    let (offset, length) = range.split_once(':').unwrap_or((range, ""));
+                    (
+                        Some(offset.parse()?),
+                        Some(length)
+                            .filter(|len| !len.is_empty())
+                            .map(str::parse)
+                            .transpose()?,
+                    )
+                }
+                None => (None, None),
+            };
+
+            let request = FetchFileRequest {
+                file_hash,
+                verify: args.iter().any(|arg| arg == "--verify"),
+                proofs: args.iter().any(|arg| arg == "--proofs"),
+                offset,
+                length,
+                key: None,
+                version: None,
+                ref_name: String::new(),
+            };
+            fetch_file(&mut client, request, file_name).await?;
+        }
+        "fetch-key" => {
+            let key = env::args().nth(2).expect("No file key provided");
+            let args: Vec<String> = env::args().skip(3).collect();
+            let file_name = args.first().filter(|arg| !arg.starts_with("--")).cloned();
+
+            let request = FetchFileRequest {
+                key: Some(FileKey {
+                    namespace: flag_value(&args, "--namespace")
+                        .cloned()
+                        .unwrap_or_default(),
+                    key,
+                }),
+                version: flag_value(&args, "--version")
+                    .map(|v| v.parse())
+                    .transpose()?,
+                ..Default::default()
+            };
+            fetch_file(&mut client, request, file_name).await?;
+        }
+        "fetch-ref" => {
+            let ref_name = env::args().nth(2).expect("No ref name provided");
+            let request = FetchFileRequest {
+                ref_name,
+                ..Default::default()
+            };
+            fetch_file(&mut client, request, env::args().nth(3)).await?;
+        }
+        "set-ref" => {
+            let name = env::args().nth(2).expect("No ref name provided");
+            let file_hash = env::args().nth(3).expect("No file hash provided");
+            let args: Vec<String> = env::args().skip(4).collect();
+            let expected_hash = match flag_value(&args, "--expect") {
+                Some(hash) => Some(hash.clone()),
+                None if args.iter().any(|arg| arg == "--create") => Some(String::new()),
+                None => None,
+            };
+            let stored = client
+                .set_ref(SetRefRequest {
+                    name,
+                    file_hash,
+                    expected_hash,
+                })
+                .await?
+                .into_inner();
+            println!("{} -> {}", stored.name, stored.file_hash);
+        }
+        "get-ref" => {
+            let name = env::args().nth(2).expect("No ref name provided");
+            let stored = client.get_ref(GetRefRequest { name }).await?.into_inner();
+            println!(
+                "{} -> {} (set {})",
+                stored.name, stored.file_hash, stored.updated_at
+            );
+        }
+        "delete-ref" => {
+            let name = env::args().nth(2).expect("No ref name provided");
+            let args: Vec<String> = env::args().skip(3).collect();
+            client
+                .delete_ref(DeleteRefRequest {
+                    name,
+                    expected_hash: flag_value(&args, "--expect").cloned(),
+                })
+                .await?;
+            println!("Ref deleted");
+        }
+        "refs" => {
+            let prefix = env::args().nth(2).unwrap_or_default();
+            let mut start_after = String::new();
+            loop {
+                let page = client
+                    .list_refs(ListRefsRequest {
+                        prefix: prefix.clone(),
+                        start_after: start_after.clone(),
+                        limit: 0,
+                    })
+                    .await?
+                    .into_inner()
+                    .refs;
+                for stored in &page {
+                    println!("{} -> {}", stored.name, stored.file_hash);
+                }
+                match page.last() {
+                    Some(last) => start_after = last.name.clone(),
+                    None => break,
+                }
+            }
+        }
+        "versions" => {
+            let key = env::args().nth(2).expect("No file key provided");
+            let args: Vec<String> = env::args().skip(3).collect();
+            let response = client
+                .list_versions(ListVersionsRequest {
+                    key: Some(FileKey {
+                        namespace: flag_value(&args, "--namespace")
+                            .cloned()
+                            .unwrap_or_default(),
+                        key,
+                    }),
+                })
+                .await?
+                .into_inner();
+            for version in response.versions {
+                let info = version.info.unwrap_or_default();
+                println!(
+                    "v{} {} {} bytes, uploaded {}{}",
+                    version.version,
+                    info.file_hash,
+                    info.size,
+                    version.created_at,
+                    if version.is_error { " (broken)" } else { "" }
+                );
+            }
+            match response.keep_versions {
+                0 => println!("All versions are kept"),
+                keep => println!("Keeps the last {} versions", keep),
+            }
+        }
+        "has" => {
+            let file_hashes: Vec<String> = env::args().skip(2).collect();
+            let response = client
+                .has_files(HasFilesRequest {
+                    file_hashes: file_hashes.clone(),
+                })
+                .await?
+                .into_inner();
+            for hash in file_hashes {
+                let found = response.file_hashes.contains(&hash.to_ascii_lowercase());
+                println!("{} {}", hash, if found { "stored" } else { "missing" });
+            }
+        }
+        "stat" => {
+            let file_hashes: Vec<String> = env::args().skip(2).collect();
+            let response = client
+                .batch_stat(BatchStatRequest { file_hashes })
+                .await?
+                .into_inner();
+            for file in response.files {
+                match file.info {
+                    Some(info) => println!(
+                        "{} \"{}\" {} bytes, generation {}, tags [{}], metadata {:?}{}{}{}{}",
+                        file.file_hash,
+                        info.file_name,
+                        info.size,
+                        info.generation,
+                        info.tags.join(", "),
+                        info.metadata,
+                        match info.expires_at {
+                            0 => String::new(),
+                            at => format!(", expires at {}", at),
+                        },
+                        match info.retain_until {
+                            0 => String::new(),
+                            at => format!(", retained until {}", at),
+                        },
+                        if info.legal_hold {
+                            ", on legal hold"
+                        } else {
+                            ""
+                        },
+                        if file.is_error { " (broken)" } else { "" }
+                    ),
+                    None => println!("{} missing", file.file_hash),
+                }
+            }
+        }
+        "update-meta" => {
+            let file_hash = env::args().nth(2).expect("No file hash provided");
+            let generation = env::args()
+                .nth(3)
+                .expect("No generation provided")
+                .parse()?;
+            let args: Vec<String> = env::args().skip(4).collect();
+            let info = client
+                .update_metadata(UpdateMetadataRequest {
+                    file_hash,
+                    generation,
+                    metadata: metadata_args(&args),
+                    tags: flag_values(&args, "--tag"),
+                })
+                .await?
+                .into_inner();
+            println!(
+                "{} now at generation {}, tags [{}], metadata {:?}",
+                info.file_hash,
+                info.generation,
+                info.tags.join(", "),
+                info.metadata
+            );
+        }
+        "extend-ttl" => {
+            let file_hash = env::args().nth(2).expect("No file hash provided");
+            let expiration = env::args().nth(3).map(|ttl| Expiration {
+                at: Some(expiration::At::TtlSeconds(
+                    ttl.parse().expect("TTL should be a number of seconds"),
+                )),
+            });
+            let info = client
+                .extend_ttl(ExtendTtlRequest {
+                    file_hash,
+                    expiration,
+                })
+                .await?
+                .into_inner();
+            match info.expires_at {
+                0 => println!("{} no longer expires", info.file_hash),
+                at => println!("{} expires at {}", info.file_hash, at),
+            }
+        }
+        "set-retention" => {
+            let file_hash = env::args().nth(2).expect("No file hash provided");
+            let retain_until = env::args()
+                .nth(3)
+                .expect("No retention time provided")
+                .parse()?;
+            let info = client
+                .set_retention(SetRetentionRequest {
+                    file_hash,
+                    retain_until,
+                })
+                .await?
+                .into_inner();
+            println!("{} retained until {}", info.file_hash, info.retain_until);
+        }
+        "list" => {
+            let args: Vec<String> = env::args().skip(2).collect();
+            let (metadata_key, metadata_value) = match flag_value(&args, "--meta") {
+                Some(meta) => match meta.split_once('=') {
+                    Some((key, value)) => (key.to_owned(), Some(value.to_owned())),
+                    None => (meta.clone(), None),
+                },
+                None => (String::new(), None),
+            };
+            let mut request = ListFilesRequest {
+                tags: flag_values(&args, "--tag"),
+                metadata_key,
+                metadata_value,
+                limit: 0,
+                page_token: String::new(),
+            };
+            loop {
+                let page = client.list_files(request.clone()).await?.into_inner();
+                for info in page.files {
+                    println!(
+                        "{} \"{}\" {} bytes, tags [{}]",
+                        info.file_hash,
+                        info.file_name,
+                        info.size,
+                        info.tags.join(", ")
+                    );
+                }
+                if page.next_page_token.is_empty() {
+                    break;
+                }
+                request.page_token = page.next_page_token;
+            }
+        }
+        "fetch-many" => {
+            let output_dir = env::args().nth(2).expect("No output folder provided");
+            let args: Vec<String> = env::args().skip(3).collect();
+            let request = FetchManyRequest {
+                file_hashes: args
+                    .iter()
+                    .filter(|arg| !arg.starts_with("--"))
+                    .cloned()
+                    .collect(),
+                verify: args.iter().any(|arg| arg == "--verify"),
+            };
+            fetch_many(&mut client, request, output_dir).await?;
+        }
+        "archive" => {
+            let output = env::args().nth(2).expect("No output file provided");
+            let args: Vec<String> = env::args().skip(3).collect();
+            let format = match args.iter().position(|arg| arg == "--format") {
+                Some(pos) => match args.get(pos + 1).map(String::as_str) {
+                    Some("tar") => ArchiveFormat::Tar,
+                    Some("tar.zst") => ArchiveFormat::TarZstd,
+                    Some("zip") => ArchiveFormat::Zip,
+                    _ => panic!("'--format' should be one of: tar, tar.zst, zip"),
+                },
+                None => ArchiveFormat::Tar,
+            };
+            let file_hashes = args
+                .iter()
+                .enumerate()
+                .filter(|(i, arg)| {
+                    !arg.starts_with("--") && args[i.saturating_sub(1)] != "--format"
+                })
+                .map(|(_, arg)| arg.clone())
+                .collect();
+
+            let mut stream = client
+                .fetch_archive(FetchArchiveRequest {
+                    file_hashes,
+                    format: format as i32,
+                })
+                .await?
+                .into_inner();
+
+            let mut file = File::create(&output)?;
+            let mut received: u64 = 0;
+            while let Some(message) = stream.message().await? {
+                file.write_all(&message.chunk)?;
+                received += message.chunk.len() as u64;
+                print!("\r{} bytes", received);
+            }
+            println!("\nArchive saved to {}", output);
+        }
+        "delete-many" => {
+            let file_hashes: Vec<String> = env::args().skip(2).collect();
+            let response = client
+                .batch_delete(BatchDeleteRequest { file_hashes })
+                .await?
+                .into_inner();
+            for result in response.results {
+                println!("{} {}", result.file_hash, result.message);
+            }
         }
         "delete" => {
             let file_hash = env::args().nth(2).expect("No file hash provided");
             delete_file(&mut client, file_hash).await?;
         }
+        "status" => {
+            let response = AdminClient::new(channel)
+                .get_status(admin_request(GetStatusRequest {})?)
+                .await?;
+            println!("{:#?}", response.into_inner());
+        }
+        "mode" => {
+            let mode = env::args().nth(2).expect("No mode provided");
+            let mode = ServiceMode::from_str_name(&format!(
+                "SERVICE_MODE_{}",
+                mode.to_uppercase().replace('-', "_")
+            ))
+            .expect("Mode should be one of: read-write, read-only, maintenance");
+            let reason = env::args().nth(3).unwrap_or_default();
+
+            let response = AdminClient::new(channel)
+                .set_mode(admin_request(SetModeRequest {
+                    mode: mode as i32,
+                    reason,
+                })?)
+                .await?;
+            println!("{:#?}", response.into_inner());
+        }
+        "legal-hold" => {
+            let file_hash = env::args().nth(2).expect("No file hash provided");
+            let hold = match env::args().nth(3).as_deref() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => panic!("Legal hold should be 'on' or 'off'"),
+            };
+            let reason = env::args().nth(4).unwrap_or_default();
+
+            let response = AdminClient::new(channel)
+                .set_legal_hold(admin_request(SetLegalHoldRequest {
+                    file_hash,
+                    hold,
+                    reason,
+                })?)
+                .await?;
+            println!("{:#?}", response.into_inner());
+        }
+        "override-retention" => {
+            let file_hash = env::args().nth(2).expect("No file hash provided");
+            let retain_until = env::args()
+                .nth(3)
+                .expect("No retention time provided")
+                .parse()?;
+            let reason = env::args().nth(4).unwrap_or_default();
+
+            let response = AdminClient::new(channel)
+                .override_retention(admin_request(OverrideRetentionRequest {
+                    file_hash,
+                    retain_until,
+                    reason,
+                })?)
+                .await?;
+            println!("{:#?}", response.into_inner());
+        }
+        "namespace-retention" => {
+            let namespace = env::args().nth(2).expect("No namespace provided");
+            let retention_seconds = env::args().nth(3).expect("No retention provided").parse()?;
+            let reason = env::args().nth(4).unwrap_or_default();
+
+            let response = AdminClient::new(channel)
+                .set_namespace_retention(admin_request(SetNamespaceRetentionRequest {
+                    namespace,
+                    retention_seconds,
+                    reason,
+                })?)
+                .await?;
+            println!("{:#?}", response.into_inner());
+        }
+        "watch" => {
+            let args: Vec<String> = env::args().skip(2).collect();
+            let request = WatchEventsRequest {
+                namespace: flag_value(&args, "--namespace").cloned(),
+                name_prefix: flag_value(&args, "--prefix").cloned().unwrap_or_default(),
+                cursor: flag_value(&args, "--cursor").cloned().unwrap_or_default(),
+            };
+
+            let mut stream = client.watch_events(request).await?.into_inner();
+            while let Some(event) = stream.message().await? {
+                let key = match &event.key {
+                    Some(key) => format!(" {}/{} v{}", key.namespace, key.key, event.version),
+                    None => String::new(),
+                };
+                println!(
+                    "{} {} {} \"{}\" {} bytes{}",
+                    event.cursor,
+                    event.r#type().as_str_name(),
+                    event.file_hash,
+                    event.file_name,
+                    event.size,
+                    key
+                );
+            }
+        }
+        "audit" => {
+            let args: Vec<String> = env::args().skip(2).collect();
+            let mut request = audit_args(&args)?;
+
+            loop {
+                let response = AdminClient::new(channel.clone())
+                    .query_audit_log(admin_request(request.clone())?)
+                    .await?
+                    .into_inner();
+                for record in response.records {
+                    println!(
+                        "{} {} {} {} {} {} {} {}",
+                        record.id,
+                        record.created_at,
+                        record.operation,
+                        record.caller,
+                        record.file_hash,
+                        record.bytes,
+                        record.outcome,
+                        record.message
+                    );
+                }
+                if response.next_page_token.is_empty() {
+                    break;
+                }
+                request.page_token = response.next_page_token;
+            }
+        }
+        "audit-export" => {
+            let output = env::args().nth(2).expect("No output file provided");
+            let args: Vec<String> = env::args().skip(3).collect();
+
+            let mut stream = AdminClient::new(channel)
+                .export_audit_log(admin_request(audit_args(&args)?)?)
+                .await?
+                .into_inner();
+            let mut file = File::create(&output)?;
+            while let Some(chunk) = stream.message().await? {
+                file.write_all(&chunk.data)?;
+            }
+            println!("Audit log exported to {}", output);
+        }
+        "webhook-add" => {
+            let url = env::args().nth(2).expect("No webhook URL provided");
+            let args: Vec<String> = env::args().skip(3).collect();
+            let events = flag_values(&args, "--event")
+                .iter()
+                .map(|event| {
+                    EventType::from_str_name(&format!("EVENT_TYPE_FILE_{}", event.to_uppercase()))
+                        .map(|event| event as i32)
+                        .expect("Event should be 'uploaded', 'deleted' or 'expired'")
+                })
+                .collect();
+
+            let response = AdminClient::new(channel)
+                .register_webhook(admin_request(RegisterWebhookRequest {
+                    url,
+                    secret: flag_value(&args, "--secret").cloned().unwrap_or_default(),
+                    events,
+                })?)
+                .await?;
+            println!("{:#?}", response.into_inner());
+        }
+        "webhooks" => {
+            let response = AdminClient::new(channel)
+                .list_webhooks(admin_request(ListWebhooksRequest {})?)
+                .await?;
+            for webhook in response.into_inner().webhooks {
+                let events: Vec<&str> = webhook.events().map(|event| event.as_str_name()).collect();
+                println!("{} {} [{}]", webhook.id, webhook.url, events.join(", "));
+            }
+        }
+        "webhook-delete" => {
+            let id = env::args()
+                .nth(2)
+                .expect("No webhook id provided")
+                .parse()?;
+
+            AdminClient::new(channel)
+                .delete_webhook(admin_request(DeleteWebhookRequest { id })?)
+                .await?;
+            println!("Webhook {} deleted", id);
+        }
+        "deliveries" => {
+            let args: Vec<String> = env::args().skip(2).collect();
+            let mut request = ListDeliveriesRequest {
+                webhook_id: flag_value(&args, "--webhook")
+                    .map(|id| id.parse())
+                    .transpose()?
+                    .unwrap_or_default(),
+                state: flag_value(&args, "--state").map(|state| {
+                    DeliveryState::from_str_name(&format!(
+                        "DELIVERY_STATE_{}",
+                        state.to_uppercase()
+                    ))
+                    .map(|state| state as i32)
+                    .expect("State should be 'pending', 'delivered' or 'dead'")
+                }),
+                ..Default::default()
+            };
+
+            loop {
+                let response = AdminClient::new(channel.clone())
+                    .list_deliveries(admin_request(request.clone())?)
+                    .await?
+                    .into_inner();
+                for delivery in response.deliveries {
+                    println!(
+                        "{} webhook:{} event:{} {} {} {} attempts:{} status:{} {}",
+                        delivery.id,
+                        delivery.webhook_id,
+                        delivery.event_id,
+                        delivery.event_type().as_str_name(),
+                        delivery.file_hash,
+                        delivery.state().as_str_name(),
+                        delivery.attempts,
+                        delivery.last_status,
+                        delivery.last_error
+                    );
+                }
+                if response.next_page_token.is_empty() {
+                    break;
+                }
+                request.page_token = response.next_page_token;
+            }
+        }
+        "redeliver" => {
+            let id = env::args()
+                .nth(2)
+                .expect("No delivery id provided")
+                .parse()?;
+
+            let response = AdminClient::new(channel)
+                .retry_delivery(admin_request(RetryDeliveryRequest { id })?)
+                .await?;
+            println!("{:#?}", response.into_inner());
+        }
         "-h" | "--help" => print_help(),
         _ => {
-            println!("Unknown command. Use 'upload', 'fetch', or 'delete'.");
+            println!(
+                "Unknown command. Use 'upload', 'upload-multipart', 'fetch', 'has' or 'delete'."
+            );
             print_help();
         }
     }
@@ -42,14 +712,163 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 fn print_help() {
     println!("Usage:");
-    println!("  upload <file_path>    - Upload a file");
-    println!("  fetch  <file_hash>    - Fetch a file by its hash");
+    println!("  upload <file_path> [content_type] [--skip-existing]");
+    println!("         [--key <key> [--namespace <namespace>] [--keep <versions>]]");
+    println!("         [--tag <tag>]... [--meta <key>=<value>]...");
+    println!("         [--ttl <seconds> | --expires-at <unix_time>] [--retain-until <unix_time>]");
+    println!("                        - Upload a file (unless it's already stored),");
+    println!("                          optionally as the next version of a key");
+    println!("  upload-archive <archive_path>");
+    println!("                        - Store each file of a .tar, .tar.zst or .zip archive");
+    println!("  upload-multipart <file_path> [part_size_mb]");
+    println!("                        - Upload a file in parts sent in parallel");
+    println!(
+        "  fetch  <file_hash> [output_file] [--verify] [--proofs] [--range <offset>:[length]]"
+    );
+    println!("                        - Fetch a file (or a range of it) by its hash");
+    println!("  fetch-key <key> [output_file] [--namespace <namespace>] [--version <n>]");
+    println!("                        - Fetch a version of a key (the latest by default)");
+    println!("  versions <key> [--namespace <namespace>]");
+    println!("                        - List the stored versions of a key");
+    println!("  fetch-ref <ref> [output_file]");
+    println!("                        - Fetch the file a ref points at");
+    println!("  set-ref <ref> <file_hash> [--expect <file_hash> | --create]");
+    println!("                        - Point a ref at a file, if it still points at");
+    println!("                          the expected hash (or doesn't exist yet)");
+    println!("  get-ref <ref>         - Show the file a ref points at");
+    println!("  delete-ref <ref> [--expect <file_hash>]");
+    println!("                        - Delete a ref");
+    println!("  refs [prefix]         - List refs");
+    println!("  fetch-many <output_folder> <file_hash>... [--verify]");
+    println!("                        - Fetch several files in one stream");
+    println!("  archive <output_file> <file_hash>... [--format tar|tar.zst|zip]");
+    println!("                        - Fetch several files as one archive");
+    println!("  has <file_hash>...    - Check which files are stored");
+    println!("  stat <file_hash>...   - Show stored files");
+    println!("  update-meta <file_hash> <generation> [--tag <tag>]... [--meta <key>=<value>]...");
+    println!("                        - Replace the tags and metadata of a file");
+    println!("  extend-ttl <file_hash> [ttl_seconds]");
+    println!("                        - Set when a file expires (never without a TTL)");
+    println!("  set-retention <file_hash> <unix_time>");
+    println!("                        - Extend the retention of a file");
+    println!("  list [--tag <tag>]... [--meta <key>[=<value>]]");
+    println!("                        - List files by tags and metadata");
+    println!("  watch [--namespace <namespace>] [--prefix <name_prefix>] [--cursor <cursor>]");
+    println!("                        - Print storage events as they happen, after the");
+    println!("                          event of the cursor first");
     println!("  delete <file_hash>    - Delete a file by its hash");
+    println!("  delete-many <file_hash>...");
+    println!("                        - Delete several files");
+    println!("  status                - Show service mode and volumes (admin)");
+    println!("  mode <read-write|read-only|maintenance> [reason]");
+    println!("                        - Switch service mode (admin)");
+    println!("  legal-hold <file_hash> <on|off> <reason>");
+    println!("                        - Place or release a legal hold (admin)");
+    println!("  override-retention <file_hash> <unix_time|0> <reason>");
+    println!("                        - Change or remove a retention (admin, governance mode)");
+    println!("  namespace-retention <namespace> <seconds> <reason>");
+    println!("                        - Set the default retention of a namespace (admin)");
+    println!(
+        "  audit [--from <unix_time>] [--to <unix_time>] [--caller <id>] [--hash <file_hash>]"
+    );
+    println!("        [--op <operation>]");
+    println!("                        - Show the audit log (admin)");
+    println!("  audit-export <output_file> [same filters as audit]");
+    println!("                        - Export the audit log as JSON lines (admin)");
+    println!("  webhook-add <url> [--secret <secret>] [--event uploaded|deleted|expired]...");
+    println!("                        - Register a webhook, for every event by default (admin)");
+    println!("  webhooks              - List webhooks (admin)");
+    println!("  webhook-delete <id>   - Delete a webhook (admin)");
+    println!("  deliveries [--webhook <id>] [--state pending|delivered|dead]");
+    println!("                        - Show webhook deliveries, newest first (admin)");
+    println!("  redeliver <delivery_id>");
+    println!("                        - Attempt a delivery again, e.g. a dead one (admin)");
+    println!();
+    println!("Calls name the client with CLIENT_ID in the audit log, if it's set.");
+}
+
+/// Reads the filters of the audit log.
+fn audit_args(args: &[String]) -> Result<QueryAuditLogRequest, Box<dyn std::error::Error>> {
+    let time = |flag| {
+        flag_value(args, flag)
+            .map(|at| at.parse::<i64>())
+            .transpose()
+            .map(Option::unwrap_or_default)
+    };
+
+    Ok(QueryAuditLogRequest {
+        from: time("--from")?,
+        to: time("--to")?,
+        caller: flag_value(args, "--caller").cloned().unwrap_or_default(),
+        file_hash: flag_value(args, "--hash").cloned().unwrap_or_default(),
+        operation: flag_value(args, "--op").cloned().unwrap_or_default(),
+        ..Default::default()
+    })
+}
+
+/// Returns the arguments following each `flag`, for repeatable flags.
+fn flag_values(args: &[String], flag: &str) -> Vec<String> {
+    args.windows(2)
+        .filter(|pair| pair[0] == flag)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+/// Collects `--meta <key>=<value>` arguments.
+fn metadata_args(args: &[String]) -> HashMap<String, String> {
+    flag_values(args, "--meta")
+        .into_iter()
+        .map(|meta| match meta.split_once('=') {
+            Some((key, value)) => (key.to_owned(), value.to_owned()),
+            None => (meta, String::new()),
+        })
+        .collect()
+}
+
+/// Reads `--ttl <seconds>` or `--expires-at <unix_time>`.
+fn expiration_args(args: &[String]) -> Option<Expiration> {
+    let at = match (flag_value(args, "--ttl"), flag_value(args, "--expires-at")) {
+        (Some(ttl), _) => {
+            expiration::At::TtlSeconds(ttl.parse().expect("'--ttl' requires a number of seconds"))
+        }
+        (None, Some(at)) => expiration::At::ExpiresAt(
+            at.parse()
+                .expect("'--expires-at' requires a unix timestamp"),
+        ),
+        (None, None) => return None,
+    };
+
+    Some(Expiration { at: Some(at) })
+}
+
+/// Returns the argument following `flag`.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|pos| args.get(pos + 1))
+}
+
+/// Attaches `ADMIN_TOKEN` and `CLIENT_ID` (if set) to an admin call.
+fn admin_request<T>(message: T) -> Result<Request<T>, Box<dyn std::error::Error>> {
+    let mut request = Request::new(message);
+    if let Ok(token) = env::var("ADMIN_TOKEN") {
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {}", token).parse()?);
+    }
+    if let Ok(id) = env::var("CLIENT_ID") {
+        request.metadata_mut().insert("x-client-id", id.parse()?);
+    }
+
+    Ok(request)
 }
 
+/// Uploads a file with the options of `header`, its name, size and hash are
+/// filled in from the file.
 async fn upload_file(
-    client: &mut StorageClient<Channel>,
+    client: &mut Client,
     file_path: String,
+    header: UploadHeader,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut file = File::open(&file_path)?;
     let mut buffer = Vec::new();
@@ -57,18 +876,27 @@ async fn upload_file(
 
     let file_name = file_path.split("/").last().unwrap().to_string();
 
-    let stream = tokio_stream::iter(vec![
-        UploadFileRequest {
-            data: Some(grpc_storage::storage::upload_file_request::Data::FileName(
+    let mut messages = vec![UploadFileRequest {
+        data: Some(grpc_storage::storage::upload_file_request::Data::Header(
+            Box::new(UploadHeader {
                 file_name,
-            )),
-        },
-        UploadFileRequest {
-            data: Some(grpc_storage::storage::upload_file_request::Data::Chunk(
-                buffer,
-            )),
-        },
-    ]);
+                size: Some(buffer.len() as u64),
+                expected_hash: format!("{:x}", Sha256::digest(&buffer)),
+                ..header
+            }),
+        )),
+    }];
+    messages.extend(
+        buffer
+            .chunks(UPLOAD_CHUNK_SIZE)
+            .map(|chunk| UploadFileRequest {
+                data: Some(grpc_storage::storage::upload_file_request::Data::Chunk(
+                    chunk.to_vec(),
+                )),
+            }),
+    );
+
+    let stream = tokio_stream::iter(messages);
 
     let response = client.upload_file(tonic::Request::new(stream)).await?;
     println!("File uploaded: {:?}", response.into_inner());
@@ -76,26 +904,215 @@ async fn upload_file(
     Ok(())
 }
 
-async fn fetch_file(
-    client: &mut StorageClient<Channel>,
-    file_hash: String,
-    file_name: Option<String>,
+async fn upload_archive(
+    client: &mut Client,
+    file_path: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let format = if file_path.ends_with(".zip") {
+        ArchiveFormat::Zip
+    } else if file_path.ends_with(".tar.zst") || file_path.ends_with(".tzst") {
+        ArchiveFormat::TarZstd
+    } else if file_path.ends_with(".tar") {
+        ArchiveFormat::Tar
+    } else {
+        return Err("Archive should be a .tar, .tar.zst or .zip file".into());
+    };
+
+    let mut file = File::open(&file_path)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+
+    let mut messages = vec![UploadArchiveRequest {
+        data: Some(upload_archive_request::Data::Header(UploadArchiveHeader {
+            format: format as i32,
+        })),
+    }];
+    messages.extend(
+        buffer
+            .chunks(UPLOAD_CHUNK_SIZE)
+            .map(|chunk| UploadArchiveRequest {
+                data: Some(upload_archive_request::Data::Chunk(chunk.to_vec())),
+            }),
+    );
+
     let response = client
-        .fetch_file(FetchFileRequest { file_hash })
+        .upload_archive(tokio_stream::iter(messages))
         .await?
         .into_inner();
 
-    let mut file = if file_name.is_some() {
-        File::create(file_name.unwrap())?
+    for entry in response.entries {
+        println!("{} {} ({} bytes)", entry.file_hash, entry.path, entry.size);
+    }
+    for path in response.skipped {
+        println!("skipped: {}", path);
+    }
+
+    Ok(())
+}
+
+async fn upload_multipart(
+    client: &mut Client,
+    file_path: String,
+    part_size: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = File::open(&file_path)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+
+    let file_name = file_path.split("/").last().unwrap().to_string();
+
+    let upload_id = client
+        .create_multipart_upload(CreateMultipartUploadRequest {
+            header: Some(UploadHeader {
+                file_name,
+                size: Some(buffer.len() as u64),
+                content_type: String::new(),
+                metadata: HashMap::new(),
+                expected_hash: format!("{:x}", Sha256::digest(&buffer)),
+                skip_if_exists: false,
+                key: None,
+                keep_versions: None,
+                tags: Vec::new(),
+                expiration: None,
+                retain_until: 0,
+            }),
+        })
+        .await?
+        .into_inner()
+        .upload_id;
+
+    let mut workers = Vec::new();
+    for (index, part) in buffer.chunks(part_size.max(1)).enumerate() {
+        let mut client = client.clone();
+        let mut messages = vec![UploadPartRequest {
+            data: Some(upload_part_request::Data::Header(UploadPartHeader {
+                upload_id: upload_id.clone(),
+                part_number: index as u32 + 1,
+                size: Some(part.len() as u64),
+                expected_hash: format!("{:x}", Sha256::digest(part)),
+            })),
+        }];
+        messages.extend(
+            part.chunks(UPLOAD_CHUNK_SIZE)
+                .map(|chunk| UploadPartRequest {
+                    data: Some(upload_part_request::Data::Chunk(chunk.to_vec())),
+                }),
+        );
+
+        workers.push(tokio::spawn(async move {
+            client.upload_part(tokio_stream::iter(messages)).await
+        }));
+    }
+
+    let mut parts = Vec::with_capacity(workers.len());
+    for worker in workers {
+        match worker.await? {
+            Ok(response) => {
+                let response = response.into_inner();
+                println!(
+                    "Part {} uploaded ({} bytes)",
+                    response.part_number, response.size
+                );
+                parts.push(CompletedPart {
+                    part_number: response.part_number,
+                    part_hash: response.part_hash,
+                });
+            }
+            Err(status) => {
+                client
+                    .abort_multipart_upload(AbortMultipartUploadRequest { upload_id })
+                    .await?;
+                return Err(status.into());
+            }
+        }
+    }
+
+    let response = client
+        .complete_multipart_upload(CompleteMultipartUploadRequest { upload_id, parts })
+        .await?;
+    println!("File uploaded: {:?}", response.into_inner());
+
+    Ok(())
+}
+
+async fn fetch_file(
+    client: &mut Client,
+    request: FetchFileRequest,
+    file_name: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ranged = request.offset.is_some() || request.length.is_some();
+    let offset = request.offset.unwrap_or(0);
+    let length = request.length;
+
+    let response = client.fetch_file(request).await?.into_inner();
+
+    let mut file = if let Some(name) = file_name {
+        File::create(name)?
     } else {
         File::create("downloaded_file.txt")?
     };
 
     let mut stream = response;
+    let mut hasher = Sha256::new();
+    let mut expected_hash = String::new();
+    let mut root_hash = None;
+    let mut total: u64 = 0;
+    let mut end: u64 = 0;
+    let mut received: u64 = 0;
+
+    while let Some(message) = stream.message().await? {
+        match message.data {
+            Some(FetchData::Info(info)) => {
+                println!(
+                    "Fetching \"{}\" ({} bytes, {})",
+                    info.file_name, info.size, info.content_type
+                );
+                expected_hash = info.file_hash;
+                root_hash = blake3::Hash::from_hex(&info.blake3_hash).ok();
+                total = info.size;
+                end = length.map_or(total, |len| offset.saturating_add(len).min(total));
+            }
+            Some(FetchData::Chunk(chunk)) => {
+                hasher.update(&chunk);
+                file.write_all(&chunk)?;
+                received += chunk.len() as u64;
+                print!("\r{}/{} bytes", received, total);
+            }
+            Some(FetchData::Verified(slice)) => {
+                let proof = slice
+                    .proof
+                    .iter()
+                    .map(|node| Ok((node.hash.as_slice().try_into()?, node.is_left)))
+                    .collect::<Result<Vec<_>, std::array::TryFromSliceError>>()?;
 
-    while let Some(chunk) = stream.message().await? {
-        file.write_all(&chunk.chunk)?;
+                let actual = bao::slice_root(total, slice.offset, &slice.data, &proof);
+                if Some(actual) != root_hash {
+                    return Err(format!("Slice at {} failed verification!", slice.offset).into());
+                }
+
+                hasher.update(&slice.data);
+                received += slice.data.len() as u64;
+
+                // Slices are aligned, only the requested range is kept
+                let slice_end = slice.offset + slice.data.len() as u64;
+                let from = offset.max(slice.offset).min(slice_end) - slice.offset;
+                let to = end.min(slice_end).max(slice.offset) - slice.offset;
+                file.write_all(&slice.data[from as usize..to as usize])?;
+                print!("\r{}/{} bytes verified", slice_end.min(end), end);
+            }
+            Some(FetchData::Trailer(trailer)) => {
+                println!();
+                let actual_hash = format!("{:x}", hasher.clone().finalize());
+                if trailer.file_hash != actual_hash || trailer.size != received {
+                    return Err("Download was corrupted in transit!".into());
+                }
+                if !ranged && trailer.file_hash != expected_hash {
+                    return Err("Stored file doesn't match its hash!".into());
+                }
+            }
+            Some(FetchData::Error(error)) => return Err(error.message.into()),
+            None => {}
+        }
     }
 
     println!("Complete!");
@@ -103,8 +1120,55 @@ async fn fetch_file(
     Ok(())
 }
 
+async fn fetch_many(
+    client: &mut Client,
+    request: FetchManyRequest,
+    output_dir: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = client.fetch_many(request).await?.into_inner();
+
+    let mut current: Option<(File, FileInfo)> = None;
+    let mut hasher = Sha256::new();
+
+    while let Some(message) = stream.message().await? {
+        match message.data {
+            Some(FetchData::Info(info)) => {
+                let path = Path::new(&output_dir).join(&info.file_name);
+                current = Some((File::create(path)?, info));
+                hasher = Sha256::new();
+            }
+            Some(FetchData::Chunk(chunk)) => {
+                if let Some((file, _)) = current.as_mut() {
+                    hasher.update(&chunk);
+                    file.write_all(&chunk)?;
+                }
+            }
+            Some(FetchData::Trailer(trailer)) => {
+                if let Some((_, info)) = current.take() {
+                    let actual_hash = format!("{:x}", hasher.clone().finalize());
+                    if trailer.file_hash != actual_hash || actual_hash != info.file_hash {
+                        println!("{} \"{}\" corrupted!", info.file_hash, info.file_name);
+                    } else {
+                        println!(
+                            "{} \"{}\" ({} bytes)",
+                            info.file_hash, info.file_name, info.size
+                        );
+                    }
+                }
+            }
+            Some(FetchData::Error(error)) => {
+                current = None;
+                println!("{} failed: {}", error.file_hash, error.message);
+            }
+            Some(FetchData::Verified(_)) | None => {}
+        }
+    }
+
+    Ok(())
+}
+
 async fn delete_file(
-    client: &mut StorageClient<Channel>,
+    client: &mut Client,
     file_hash: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let response = client